@@ -0,0 +1,11 @@
+CREATE TABLE api_tokens (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    scope VARCHAR NOT NULL,
+    volume_path VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ,
+    PRIMARY KEY (id)
+);
@@ -0,0 +1,2 @@
+DROP TABLE email_changes;
+ALTER TABLE users DROP COLUMN session_version;
@@ -0,0 +1,20 @@
+-- addresses differing only in case or surrounding spaces would violate the index, they have
+-- to be merged or renamed by hand first
+DO $$
+DECLARE
+    duplicates TEXT;
+BEGIN
+    SELECT string_agg(email, ', ') INTO duplicates
+    FROM (
+        SELECT lower(trim(email)) AS email
+        FROM users
+        GROUP BY lower(trim(email))
+        HAVING count(*) > 1
+    ) AS duplicate_emails;
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Several users have the email addresses %, rename all but one of each before migrating', duplicates;
+    END IF;
+END $$;
+
+UPDATE users SET email = lower(trim(email));
+CREATE UNIQUE INDEX users_email_lower_idx ON users (lower(email));
@@ -0,0 +1,13 @@
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE TABLE audit_events (
+    id BIGSERIAL,
+    user_id uuid,
+    actor_id uuid,
+    action VARCHAR NOT NULL,
+    detail JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX audit_events_user_id_idx ON audit_events (user_id);
@@ -0,0 +1,17 @@
+CREATE TABLE shares (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token VARCHAR NOT NULL UNIQUE,
+    pass_hash VARCHAR,
+    path VARCHAR NOT NULL,
+    device BIGINT NOT NULL,
+    inode BIGINT NOT NULL,
+    mode VARCHAR NOT NULL,
+    expires_at TIMESTAMPTZ,
+    max_downloads INTEGER,
+    downloads INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX shares_user_id ON shares (user_id);
@@ -0,0 +1,14 @@
+CREATE TABLE folder_grants (
+    id uuid DEFAULT uuid_generate_v4(),
+    owner_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    recipient_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    path VARCHAR NOT NULL,
+    device BIGINT NOT NULL,
+    inode BIGINT NOT NULL,
+    access VARCHAR NOT NULL DEFAULT 'read',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (id),
+    UNIQUE (recipient_id, path)
+);
+
+CREATE INDEX folder_grants_owner_id ON folder_grants (owner_id);
@@ -0,0 +1,14 @@
+CREATE TABLE trash_entries (
+    id uuid DEFAULT uuid_generate_v4(),
+    volume_path VARCHAR NOT NULL,
+    original_path VARCHAR NOT NULL,
+    deleted_by uuid REFERENCES users (id) ON DELETE SET NULL,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    size BIGINT NOT NULL,
+    is_dir BOOLEAN NOT NULL,
+    os_user VARCHAR,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX trash_entries_volume_path ON trash_entries (volume_path);
+CREATE INDEX trash_entries_deleted_at ON trash_entries (deleted_at);
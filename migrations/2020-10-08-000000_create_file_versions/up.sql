@@ -0,0 +1,15 @@
+CREATE TABLE file_versions (
+    id uuid DEFAULT uuid_generate_v4(),
+    store_path VARCHAR NOT NULL,
+    path VARCHAR NOT NULL,
+    size BIGINT NOT NULL,
+    mtime TIMESTAMPTZ NOT NULL,
+    author uuid REFERENCES users (id) ON DELETE SET NULL,
+    replaced_by uuid REFERENCES users (id) ON DELETE SET NULL,
+    hash VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX file_versions_path ON file_versions (path);
+CREATE INDEX file_versions_store_path_hash ON file_versions (store_path, hash);
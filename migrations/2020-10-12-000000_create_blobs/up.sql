@@ -0,0 +1,11 @@
+CREATE TABLE blobs (
+    hash VARCHAR NOT NULL,
+    size BIGINT NOT NULL,
+    device BIGINT NOT NULL,
+    inode BIGINT NOT NULL,
+    refs BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (hash)
+);
+
+CREATE UNIQUE INDEX blobs_device_inode ON blobs (device, inode);
@@ -0,0 +1,15 @@
+CREATE TABLE netmounts (
+    id uuid DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    host VARCHAR NOT NULL,
+    port INTEGER NOT NULL,
+    username VARCHAR NOT NULL,
+    password BYTEA,
+    private_key BYTEA,
+    host_key VARCHAR NOT NULL,
+    root VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX netmounts_user_id ON netmounts (user_id);
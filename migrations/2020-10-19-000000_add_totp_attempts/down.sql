@@ -0,0 +1,3 @@
+ALTER TABLE user_totp
+    DROP COLUMN failed_attempts,
+    DROP COLUMN locked_until;
@@ -0,0 +1,6 @@
+CREATE TABLE password_resets (
+    token_hash VARCHAR NOT NULL,
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (token_hash)
+);
@@ -0,0 +1 @@
+ALTER TABLE file_versions ADD COLUMN os_user VARCHAR;
@@ -0,0 +1 @@
+ALTER TABLE blobs ADD COLUMN last_used TIMESTAMPTZ NOT NULL DEFAULT now();
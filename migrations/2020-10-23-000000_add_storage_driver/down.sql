@@ -0,0 +1,2 @@
+ALTER TABLE file_versions DROP COLUMN driver;
+ALTER TABLE trash_entries DROP COLUMN driver;
@@ -0,0 +1,2 @@
+ALTER TABLE trash_entries ADD COLUMN driver VARCHAR NOT NULL DEFAULT 'local';
+ALTER TABLE file_versions ADD COLUMN driver VARCHAR NOT NULL DEFAULT 'local';
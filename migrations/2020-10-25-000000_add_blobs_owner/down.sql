@@ -0,0 +1,4 @@
+DELETE FROM blobs WHERE owner <> '';
+ALTER TABLE blobs DROP CONSTRAINT blobs_pkey;
+ALTER TABLE blobs ADD PRIMARY KEY (hash);
+ALTER TABLE blobs DROP COLUMN owner;
@@ -0,0 +1,3 @@
+ALTER TABLE blobs ADD COLUMN owner VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE blobs DROP CONSTRAINT blobs_pkey;
+ALTER TABLE blobs ADD PRIMARY KEY (owner, hash);
@@ -0,0 +1 @@
+DROP INDEX export_jobs_active;
@@ -0,0 +1 @@
+CREATE UNIQUE INDEX export_jobs_active ON export_jobs (user_id) WHERE status IN ('pending', 'running');
@@ -0,0 +1,3 @@
+ALTER TABLE shares
+    DROP COLUMN failed_attempts,
+    DROP COLUMN locked_until;
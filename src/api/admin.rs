@@ -0,0 +1,349 @@
+use crate::api::user::current_user;
+use crate::audit::AuditEvent;
+use crate::env::Environment;
+use crate::group::Group;
+use crate::privsep;
+use crate::user::error::{Error, Result};
+use crate::user::reset::PasswordReset;
+use crate::user::User;
+use crate::volume::Volume;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Responder, Scope};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde_derive::Deserialize;
+use std::collections::HashSet;
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 200;
+
+#[derive(Deserialize)]
+struct ListParams {
+    page: Option<i64>,
+    per_page: Option<i64>,
+    q: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QuotaData {
+    quota_bytes: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct VolumesData {
+    volumes: Vec<Volume>,
+}
+
+#[derive(Deserialize)]
+struct OsUserData {
+    os_user: Option<String>,
+}
+
+/// The session's user, who must be an administrator
+fn admin_user(session: &Session, conn: &PgConnection) -> Result<User> {
+    let user = current_user(session, conn)?;
+    if !user.is_admin() {
+        return Err(Error::NotAuthorized);
+    }
+    Ok(user)
+}
+
+fn audit(conn: &PgConnection, admin: &User, user_id: uuid::Uuid, action: &str, detail: serde_json::Value) -> Result<()> {
+    AuditEvent::record(conn, Some(user_id), Some(admin.id), action, detail).map_err(Into::into)
+}
+
+async fn list_users(
+    params: web::Query<ListParams>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    admin_user(&session, &conn)?;
+
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(DEFAULT_PER_PAGE).max(1).min(MAX_PER_PAGE);
+    let (users, total) = User::list(&conn, params.q.as_deref(), page, per_page)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "users": users,
+        "page": page,
+        "per_page": per_page,
+        "total": total,
+    })))
+}
+
+async fn set_disabled(
+    uid: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+    disabled: bool,
+) -> Result<HttpResponse> {
+    use crate::schema::users::dsl;
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let admin = admin_user(&session, &conn)?;
+    if admin.id == uid.0 {
+        return Err(Error::InvalidParams);
+    }
+
+    let user = conn.transaction::<_, Error, _>(|| {
+        let user = diesel::update(dsl::users.find(uid.0))
+            .set((
+                dsl::disabled.eq(disabled),
+                dsl::session_version.eq(dsl::session_version + 1),
+            ))
+            .get_result::<User>(&conn)?;
+        let action = if disabled { "admin.user.disabled" } else { "admin.user.enabled" };
+        audit(&conn, &admin, user.id, action, serde_json::json!({}))?;
+        Ok(user)
+    })?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+async fn disable_user(
+    uid: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    set_disabled(uid, env, session, true).await
+}
+
+async fn enable_user(
+    uid: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    set_disabled(uid, env, session, false).await
+}
+
+/// Ends the user's sessions, disables the current password and mails them a one-time link to
+/// choose a new one. Nothing changes unless the mail is sent, so a failure cannot lock the user
+/// out.
+async fn reset_password(
+    uid: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    // directory passwords are reset in the directory
+    if !env.auth.local_passwords() {
+        return Err(Error::InvalidParams);
+    }
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let admin = admin_user(&session, &conn)?;
+
+    let mut user = User::find_by_id(&conn, uid.0)?;
+    conn.transaction::<_, Error, _>(|| {
+        let token = PasswordReset::create(&conn, user.id)?;
+        user.clear_password(&conn)?;
+        user.bump_session_version(&conn)?;
+        audit(&conn, &admin, user.id, "admin.user.password_reset", serde_json::json!({}))?;
+        env.mailer
+            .send(
+                &user.email,
+                "Your password has been reset",
+                &format!(
+                    "An administrator has reset your password. Open the following link within a day to choose a new one:\r\n{}/app/reset-password?token={}",
+                    env.mailer.base_url, token
+                ),
+            )
+            .map_err(|_| Error::MailError)
+    })?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+async fn set_quota(
+    uid: web::Path<(uuid::Uuid,)>,
+    data: web::Json<QuotaData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    use crate::schema::users::dsl;
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let admin = admin_user(&session, &conn)?;
+    if data.quota_bytes.map_or(false, |quota| quota < 0) {
+        return Err(Error::InvalidParams);
+    }
+
+    let user = conn.transaction::<_, Error, _>(|| {
+        let user = diesel::update(dsl::users.find(uid.0))
+            .set(dsl::quota_bytes.eq(data.quota_bytes))
+            .get_result::<User>(&conn)?;
+        audit(
+            &conn,
+            &admin,
+            user.id,
+            "admin.user.quota",
+            serde_json::json!({ "quota_bytes": data.quota_bytes }),
+        )?;
+        Ok(user)
+    })?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+async fn set_group_quota(
+    gid: web::Path<(uuid::Uuid,)>,
+    data: web::Json<QuotaData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    use crate::schema::groups::dsl;
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let admin = admin_user(&session, &conn)?;
+    if data.quota_bytes.map_or(false, |quota| quota < 0) {
+        return Err(Error::InvalidParams);
+    }
+
+    let group = conn.transaction::<_, Error, _>(|| {
+        let group = diesel::update(dsl::groups.find(gid.0))
+            .set(dsl::quota_bytes.eq(data.quota_bytes))
+            .get_result::<Group>(&conn)?;
+        AuditEvent::record(
+            &conn,
+            None,
+            Some(admin.id),
+            "admin.group.quota",
+            serde_json::json!({ "group_id": group.id, "quota_bytes": data.quota_bytes }),
+        )?;
+        Ok(group)
+    })?;
+
+    Ok(HttpResponse::Ok().json(group))
+}
+
+async fn set_volumes(
+    uid: web::Path<(uuid::Uuid,)>,
+    data: web::Json<VolumesData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    use crate::schema::users::dsl;
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let admin = admin_user(&session, &conn)?;
+
+    // every assigned volume has to be an existing directory inside an allowed base directory
+    let mut volumes = Vec::new();
+    for vol in data.into_inner().volumes {
+        volumes.push(vol.validate(&env.volume_bases).await.map_err(|_| Error::InvalidParams)?);
+    }
+
+    let mut aliases = HashSet::new();
+    if !volumes.iter().filter_map(|vol| vol.alias.as_ref()).all(|alias| aliases.insert(alias)) {
+        return Err(Error::InvalidParams);
+    }
+
+    let user = conn.transaction::<_, Error, _>(|| {
+        let user = diesel::update(dsl::users.find(uid.0))
+            .set(dsl::volumes.eq(Some(&volumes)))
+            .get_result::<User>(&conn)?;
+        audit(
+            &conn,
+            &admin,
+            user.id,
+            "admin.user.volumes",
+            serde_json::json!({ "volumes": &volumes }),
+        )?;
+        Ok(user)
+    })?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Maps the user to a Unix account whose permissions their file operations run with
+async fn set_os_user(
+    uid: web::Path<(uuid::Uuid,)>,
+    data: web::Json<OsUserData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    use crate::schema::users::dsl;
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let admin = admin_user(&session, &conn)?;
+    if let Some(name) = &data.os_user {
+        privsep::os_user(name).map_err(|_| Error::InvalidParams)?;
+    }
+
+    let user = conn.transaction::<_, Error, _>(|| {
+        let user = diesel::update(dsl::users.find(uid.0))
+            .set(dsl::os_user.eq(&data.os_user))
+            .get_result::<User>(&conn)?;
+        audit(
+            &conn,
+            &admin,
+            user.id,
+            "admin.user.os_user",
+            serde_json::json!({ "os_user": data.os_user }),
+        )?;
+        Ok(user)
+    })?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Switches the session to another user, remembering the administrator
+async fn impersonate(
+    uid: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let admin = admin_user(&session, &conn)?;
+
+    let user = User::find_by_id(&conn, uid.0)?;
+    audit(&conn, &admin, user.id, "admin.impersonate.start", serde_json::json!({}))?;
+
+    session
+        .set("impersonator", &admin)
+        .map_err(|_| Error::SessionError)?;
+    session
+        .set("user", &user)
+        .map_err(|_| Error::SessionError)?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Returns the session to the administrator who started impersonating
+async fn stop_impersonating(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+
+    let impersonator = session
+        .get::<User>("impersonator")
+        .map_err(|_| Error::SessionError)?
+        .ok_or(Error::NotAuthorized)?;
+    let user = current_user(&session, &conn)?;
+
+    let admin = User::find_by_id(&conn, impersonator.id)?;
+    if !admin.is_admin() || admin.session_version != impersonator.session_version {
+        session.purge();
+        return Err(Error::NotAuthenticated);
+    }
+    audit(&conn, &admin, user.id, "admin.impersonate.stop", serde_json::json!({}))?;
+
+    session.remove("impersonator");
+    session
+        .set("user", &admin)
+        .map_err(|_| Error::SessionError)?;
+
+    Ok(HttpResponse::Ok().json(admin))
+}
+
+pub fn service() -> Scope {
+    web::scope("/admin")
+        .route("/users", web::get().to(list_users))
+        .route("/users/{id}/disable", web::post().to(disable_user))
+        .route("/users/{id}/enable", web::post().to(enable_user))
+        .route("/users/{id}/reset-password", web::post().to(reset_password))
+        .route("/users/{id}/quota", web::put().to(set_quota))
+        .route("/users/{id}/volumes", web::put().to(set_volumes))
+        .route("/groups/{id}/quota", web::put().to(set_group_quota))
+        .route("/users/{id}/os-user", web::put().to(set_os_user))
+        .route("/users/{id}/impersonate", web::post().to(impersonate))
+        .route("/impersonate/stop", web::post().to(stop_impersonating))
+}
@@ -0,0 +1,724 @@
+//! WebDAV (class 1 and 2) access to the user's volume under `/dav/`, e.g. to map it as a
+//! network drive. Clients log in with Basic auth using an API token as the password, so
+//! read-only and path restricted tokens work as they do for the finder. Locks are kept in
+//! memory and do not survive a restart. PUT bodies are streamed to storage, other bodies
+//! are small XML documents read in full.
+
+use crate::api::finder::{self, ops};
+use crate::change::Kind;
+use crate::env::Environment;
+use crate::error::Error;
+use crate::file::{File, HIDDEN_DIRS};
+use crate::storage::{ByteStream, Stat};
+use crate::trash::TrashEntry;
+use crate::user::error::Error as UserError;
+use crate::user::token::ApiToken;
+use crate::user::User;
+use crate::version::Version;
+use crate::volume::Volume;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse, ResponseError, Scope};
+use chrono::{TimeZone, Utc};
+use futures::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const PREFIX: &str = "/dav";
+const METHODS: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+const XML: &str = "application/xml; charset=utf-8";
+
+/// Largest body accepted by methods other than PUT
+const MAX_XML_SIZE: usize = 1024 * 1024;
+
+/// Longest a lock lasts without being refreshed, also used when clients ask for no timeout
+const MAX_LOCK_SECS: u64 = 60 * 60;
+
+/// Bytes of path segments left as they are in hrefs
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+static LOCKS: Lazy<Mutex<Vec<Lock>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// A write lock on a resource, and with `infinite` on everything below it
+struct Lock {
+    token: String,
+    user_id: uuid::Uuid,
+    /// Absolute path of the locked resource
+    path: PathBuf,
+    href: String,
+    shared: bool,
+    infinite: bool,
+    owner: String,
+    expires: Instant,
+}
+
+impl Lock {
+    fn covers(&self, path: &Path) -> bool {
+        self.path == path || (self.infinite && path.starts_with(&self.path))
+    }
+
+    fn xml(&self) -> String {
+        let remaining = self.expires.saturating_duration_since(Instant::now()).as_secs();
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope>{}</D:lockscope>\
+             <D:depth>{}</D:depth><D:owner>{}</D:owner><D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot>\
+             </D:activelock>",
+            if self.shared { "<D:shared/>" } else { "<D:exclusive/>" },
+            if self.infinite { "infinity" } else { "0" },
+            escape(&self.owner),
+            remaining,
+            self.token,
+            escape(&self.href)
+        )
+    }
+}
+
+/// The locks which have not expired yet
+fn active_locks() -> MutexGuard<'static, Vec<Lock>> {
+    let mut locks = LOCKS.lock().expect("DAV lock table poisoned");
+    let now = Instant::now();
+    locks.retain(|lock| lock.expires > now);
+    locks
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn status(code: StatusCode) -> HttpResponse {
+    HttpResponse::build(code).finish()
+}
+
+/// Status codes WebDAV clients expect for the errors shared with the finder
+fn error_status(e: &Error) -> StatusCode {
+    match e {
+        Error::IoError(e) => match e.kind() {
+            io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            io::ErrorKind::AlreadyExists => StatusCode::METHOD_NOT_ALLOWED,
+            io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        Error::PathError => StatusCode::NOT_FOUND,
+        e => e.status_code(),
+    }
+}
+
+fn header_value<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|value| value.to_str().ok())
+}
+
+/// The caller, from Basic auth credentials whose password is an API token. The user name
+/// is up to the client, the token alone identifies the user.
+fn authenticate(req: &HttpRequest, env: &Environment) -> Result<(User, ApiToken), Error> {
+    let credentials = header_value(req, "authorization")
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or(UserError::NotAuthenticated)?;
+    let token = credentials.splitn(2, ':').nth(1).ok_or(UserError::NotAuthenticated)?;
+
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let (api_token, user) = ApiToken::authenticate(&conn, token)?;
+    Ok((user, api_token))
+}
+
+/// Path relative to the volume root of a URL path below `/dav`
+fn volume_path(url_path: &str) -> Result<PathBuf, Error> {
+    let rest = url_path.strip_prefix(PREFIX).ok_or(Error::PathError)?;
+    let decoded = percent_decode_str(rest)
+        .decode_utf8()
+        .map_err(|_| Error::InvalidParams)?;
+    Ok(Path::new("/").join(decoded.trim_start_matches('/')))
+}
+
+/// URL path of a path relative to the volume root, collections end with a `/`
+fn href(path: &Path, is_dir: bool) -> String {
+    let mut href = PREFIX.to_owned();
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            href.push('/');
+            href.extend(utf8_percent_encode(&part.to_string_lossy(), SEGMENT));
+        }
+    }
+    if is_dir || href == PREFIX {
+        href.push('/');
+    }
+    href
+}
+
+/// Path relative to the volume root named by the `Destination` header, which has to be
+/// on this server
+fn destination(req: &HttpRequest) -> Result<PathBuf, Error> {
+    let dest = header_value(req, "destination").ok_or(Error::InvalidParams)?;
+    let path = match dest.find("://") {
+        Some(i) => {
+            let rest = &dest[i + 3..];
+            &rest[rest.find('/').unwrap_or(rest.len())..]
+        }
+        None => dest,
+    };
+    volume_path(path)
+}
+
+/// The `Depth` header, `None` for infinity
+fn depth(req: &HttpRequest) -> Option<u32> {
+    match header_value(req, "depth").map(str::trim) {
+        Some("0") => Some(0),
+        Some("1") => Some(1),
+        _ => None,
+    }
+}
+
+/// Lock tokens submitted in the `If` header
+fn submitted_tokens(req: &HttpRequest) -> Vec<String> {
+    header_value(req, "if")
+        .unwrap_or_default()
+        .split('<')
+        .filter_map(|part| part.split('>').next())
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Whether locks keep the request from modifying the absolute `path`, and with `tree`
+/// anything below it. Holding any of the locks in question is enough.
+fn is_locked(req: &HttpRequest, user: &User, path: &Path, tree: bool) -> bool {
+    let tokens = submitted_tokens(req);
+    let locks = active_locks();
+    let applying = locks
+        .iter()
+        .filter(|lock| lock.covers(path) || (tree && lock.path.starts_with(path)))
+        .collect::<Vec<_>>();
+    !applying.is_empty()
+        && !applying
+            .iter()
+            .any(|lock| lock.user_id == user.id && tokens.contains(&lock.token))
+}
+
+/// Drops the locks on the absolute `path` and below, once it is gone
+fn release_locks(path: &Path) {
+    active_locks().retain(|lock| !lock.path.starts_with(path));
+}
+
+fn lock_timeout(req: &HttpRequest) -> Duration {
+    let requested = header_value(req, "timeout")
+        .and_then(|value| value.split(',').find_map(|part| part.trim().strip_prefix("Second-")))
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(MAX_LOCK_SECS);
+    Duration::from_secs(requested.min(MAX_LOCK_SECS))
+}
+
+/// Whether a `lockinfo` body asks for a shared lock, and the lock's owner
+fn parse_lockinfo(body: &[u8]) -> Result<(bool, String), Error> {
+    let mut reader = Reader::from_reader(body);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let (mut shared, mut owner, mut in_owner) = (false, String::new(), false);
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.local_name() {
+                b"shared" => shared = true,
+                b"owner" => in_owner = true,
+                _ => {}
+            },
+            Ok(Event::End(e)) if e.local_name() == b"owner" => in_owner = false,
+            Ok(Event::Text(text)) if in_owner => {
+                owner.push_str(&text.unescape_and_decode(&reader).map_err(|_| Error::InvalidParams)?)
+            }
+            Ok(Event::Eof) => break,
+            Err(_) => return Err(Error::InvalidParams),
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok((shared, owner))
+}
+
+fn etag(stat: &Stat) -> String {
+    format!("\"{:x}-{:x}\"", stat.len, stat.modified)
+}
+
+fn http_date(secs: u64) -> String {
+    Utc.timestamp(secs as i64, 0).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn multistatus(responses: &[String]) -> HttpResponse {
+    HttpResponse::build(StatusCode::MULTI_STATUS).content_type(XML).body(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        responses.concat()
+    ))
+}
+
+/// The live properties of a resource, `path` being relative to the volume root
+fn properties(vol: &Volume, path: &Path, full: &Path, stat: &Stat) -> String {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => vol.name.clone(),
+    };
+    let modified = Utc.timestamp(stat.modified as i64, 0);
+    let (resourcetype, content) = if stat.is_dir {
+        ("<D:resourcetype><D:collection/></D:resourcetype>".to_owned(), String::new())
+    } else {
+        (
+            "<D:resourcetype/>".to_owned(),
+            format!(
+                "<D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>application/octet-stream</D:getcontenttype>",
+                stat.len
+            ),
+        )
+    };
+    let locks = active_locks()
+        .iter()
+        .filter(|lock| lock.covers(full))
+        .map(Lock::xml)
+        .collect::<String>();
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>{}{}\
+         <D:creationdate>{}</D:creationdate><D:getlastmodified>{}</D:getlastmodified><D:getetag>{}</D:getetag>\
+         <D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+         <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry></D:supportedlock>\
+         <D:lockdiscovery>{}</D:lockdiscovery></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(&href(path, stat.is_dir)),
+        escape(&name),
+        resourcetype,
+        content,
+        modified.to_rfc3339(),
+        http_date(stat.modified),
+        escape(&etag(stat)),
+        locks
+    )
+}
+
+/// Whether the parent of the absolute `full` is an existing collection
+async fn parent_exists(vol: &Volume, full: &Path) -> bool {
+    match full.parent() {
+        Some(parent) => matches!(vol.storage().stat(parent).await, Ok(stat) if stat.is_dir),
+        None => false,
+    }
+}
+
+fn options() -> HttpResponse {
+    HttpResponse::Ok()
+        .header("DAV", "1, 2")
+        .header(header::ALLOW, METHODS)
+        .header("MS-Author-Via", "DAV")
+        .finish()
+}
+
+async fn get(req: &HttpRequest, vol: &Volume, path: &Path) -> Result<HttpResponse, Error> {
+    let full = File::check_path(vol, path)?;
+    let storage = vol.storage();
+    let stat = storage.stat(&full).await?;
+    if stat.is_dir {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("application/octet-stream")
+        .header(header::ETAG, etag(&stat))
+        .header(header::LAST_MODIFIED, http_date(stat.modified))
+        .header(header::CONTENT_LENGTH, stat.len)
+        .no_chunking();
+    if req.method() == Method::HEAD {
+        return Ok(response.streaming(stream::empty::<Result<Bytes, Error>>()));
+    }
+    Ok(response.streaming(storage.read(&full).await?))
+}
+
+async fn propfind(req: &HttpRequest, vol: &Volume, path: &Path) -> Result<HttpResponse, Error> {
+    let full = File::check_path(vol, path)?;
+    let storage = vol.storage();
+    let stat = storage.stat(&full).await?;
+
+    let mut responses = vec![properties(vol, path, &full, &stat)];
+    match depth(req) {
+        Some(0) => {}
+        Some(_) if stat.is_dir => {
+            for (name, child) in storage.list(&full).await? {
+                if !HIDDEN_DIRS.contains(&name.as_str()) {
+                    responses.push(properties(vol, &path.join(&name), &full.join(&name), &child));
+                }
+            }
+        }
+        Some(_) => {}
+        None => {
+            return Ok(HttpResponse::Forbidden().content_type(XML).body(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>",
+            ))
+        }
+    }
+    Ok(multistatus(&responses))
+}
+
+/// Creates or replaces a file, what it replaces is kept as a version
+async fn put(
+    req: &HttpRequest,
+    env: &Environment,
+    user: &User,
+    vol: &Volume,
+    path: &Path,
+    body: ByteStream,
+) -> Result<HttpResponse, Error> {
+    vol.check_write()?;
+    let full = File::check_path(vol, path)?;
+    if is_locked(req, user, &full, false) {
+        return Ok(status(StatusCode::LOCKED));
+    }
+    let existed = match vol.storage().stat(&full).await {
+        Ok(stat) if stat.is_dir => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    if !parent_exists(vol, &full).await {
+        return Ok(status(StatusCode::CONFLICT));
+    }
+
+    ops::write_stream(env, user, vol, path, body).await?;
+    Ok(status(if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED }))
+}
+
+async fn mkcol(
+    req: &HttpRequest,
+    env: &Environment,
+    user: &User,
+    vol: &Volume,
+    path: &Path,
+    body: &[u8],
+) -> Result<HttpResponse, Error> {
+    vol.check_write()?;
+    if !body.is_empty() {
+        return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    let full = File::check_path(vol, path)?;
+    if is_locked(req, user, &full, false) {
+        return Ok(status(StatusCode::LOCKED));
+    }
+    let storage = vol.storage();
+    if storage.stat(&full).await.is_ok() {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    if !parent_exists(vol, &full).await {
+        return Ok(status(StatusCode::CONFLICT));
+    }
+
+    storage.mkdir(&full, false).await?;
+    env.changes.publish(vol, &full, Kind::Added);
+    Ok(status(StatusCode::CREATED))
+}
+
+/// Moves the resource to the trash, like `rm` in the finder
+async fn delete(
+    req: &HttpRequest,
+    env: &Environment,
+    user: &User,
+    vol: &Volume,
+    path: &Path,
+) -> Result<HttpResponse, Error> {
+    vol.check_write()?;
+    let full = File::check_path(vol, path)?;
+    if full == vol.path {
+        return Err(Error::Forbidden);
+    }
+    if is_locked(req, user, &full, true) {
+        return Ok(status(StatusCode::LOCKED));
+    }
+    vol.storage().stat(&full).await?;
+
+    {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        TrashEntry::trash(&conn, vol, path, user.id).await?;
+    }
+    env.changes.publish(vol, &full, Kind::Removed);
+    release_locks(&full);
+    Ok(status(StatusCode::NO_CONTENT))
+}
+
+/// `COPY`, or `MOVE` with `cut`. A replaced destination is moved to the trash.
+async fn transfer(
+    req: &HttpRequest,
+    env: &Environment,
+    user: &User,
+    vol: &Volume,
+    path: &Path,
+    cut: bool,
+) -> Result<HttpResponse, Error> {
+    vol.check_write()?;
+    let to_path = destination(req)?;
+    let from = File::check_path(vol, path)?;
+    let to = File::check_path(vol, &to_path)?;
+    if (cut && from == vol.path) || to == vol.path || to.starts_with(&from) {
+        return Err(Error::Forbidden);
+    }
+    let storage = vol.storage();
+    storage.stat(&from).await?;
+    if (cut && is_locked(req, user, &from, true)) || is_locked(req, user, &to, true) {
+        return Ok(status(StatusCode::LOCKED));
+    }
+    if !parent_exists(vol, &to).await {
+        return Ok(status(StatusCode::CONFLICT));
+    }
+
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let existed = storage.stat(&to).await.is_ok();
+    if existed {
+        if header_value(req, "overwrite").map(str::trim) == Some("F") {
+            return Ok(status(StatusCode::PRECONDITION_FAILED));
+        }
+        TrashEntry::trash(&conn, vol, &to_path, user.id).await?;
+    }
+
+    if cut {
+        storage.rename(&from, &to).await?;
+        Version::moved(&conn, vol.driver, &from, &to)?;
+        env.changes.publish(vol, &from, Kind::Removed);
+        env.changes.publish(vol, &to, Kind::Added);
+        release_locks(&from);
+    } else {
+        ops::copy_tree(env, &conn, (vol, path), (vol, &to_path)).await?;
+    }
+    Ok(status(if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED }))
+}
+
+/// Locks a resource, or refreshes a lock when there is no body. Locking an unmapped URL
+/// creates an empty file.
+async fn lock(
+    req: &HttpRequest,
+    env: &Environment,
+    user: &User,
+    vol: &Volume,
+    path: &Path,
+    body: &[u8],
+) -> Result<HttpResponse, Error> {
+    vol.check_write()?;
+    let full = File::check_path(vol, path)?;
+    let timeout = lock_timeout(req);
+
+    if body.is_empty() {
+        let tokens = submitted_tokens(req);
+        let mut locks = active_locks();
+        let held = locks
+            .iter_mut()
+            .find(|lock| lock.user_id == user.id && tokens.contains(&lock.token) && lock.covers(&full));
+        return Ok(match held {
+            Some(lock) => {
+                lock.expires = Instant::now() + timeout;
+                lock_response(StatusCode::OK, lock)
+            }
+            None => status(StatusCode::PRECONDITION_FAILED),
+        });
+    }
+
+    let (shared, owner) = parse_lockinfo(body)?;
+    let infinite = depth(req) != Some(0);
+    let stat = vol.storage().stat(&full).await.ok();
+    if stat.is_none() && !parent_exists(vol, &full).await {
+        return Ok(status(StatusCode::CONFLICT));
+    }
+
+    let token = format!(
+        "opaquelocktoken:{}",
+        uuid::Builder::from_bytes(rand::random())
+            .set_variant(uuid::Variant::RFC4122)
+            .set_version(uuid::Version::Random)
+            .build()
+    );
+    let response = {
+        let mut locks = active_locks();
+        let conflict = locks.iter().any(|lock| {
+            (lock.covers(&full) || (infinite && lock.path.starts_with(&full))) && !(shared && lock.shared)
+        });
+        if conflict {
+            return Ok(status(StatusCode::LOCKED));
+        }
+        let lock = Lock {
+            token: token.clone(),
+            user_id: user.id,
+            path: full.clone(),
+            href: href(path, stat.as_ref().map_or(false, |stat| stat.is_dir)),
+            shared,
+            infinite,
+            owner,
+            expires: Instant::now() + timeout,
+        };
+        let created = if stat.is_some() { StatusCode::OK } else { StatusCode::CREATED };
+        let response = lock_response(created, &lock);
+        locks.push(lock);
+        response
+    };
+
+    if stat.is_none() {
+        if let Err(e) = ops::write_file(env, user, vol, path, &[]).await {
+            active_locks().retain(|lock| lock.token != token);
+            return Err(e);
+        }
+    }
+    Ok(response)
+}
+
+fn lock_response(code: StatusCode, lock: &Lock) -> HttpResponse {
+    HttpResponse::build(code)
+        .content_type(XML)
+        .header("Lock-Token", format!("<{}>", lock.token))
+        .body(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+            lock.xml()
+        ))
+}
+
+fn unlock(req: &HttpRequest, user: &User, vol: &Volume, path: &Path) -> Result<HttpResponse, Error> {
+    let full = File::check_path(vol, path)?;
+    let token = header_value(req, "lock-token")
+        .map(|token| token.trim().trim_start_matches('<').trim_end_matches('>'))
+        .ok_or(Error::InvalidParams)?;
+
+    let mut locks = active_locks();
+    let before = locks.len();
+    locks.retain(|lock| !(lock.token == token && lock.user_id == user.id && lock.covers(&full)));
+    if locks.len() == before {
+        return Ok(status(StatusCode::CONFLICT));
+    }
+    Ok(status(StatusCode::NO_CONTENT))
+}
+
+async fn serve(req: &HttpRequest, env: &Environment, mut payload: web::Payload) -> Result<HttpResponse, Error> {
+    let (user, api_token) = authenticate(req, env)?;
+    // the user's own volume, or the part of it a restricted token is limited to
+    let vol = finder::mounts(env, &user, Some(&api_token))
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(Error::PathError)?;
+    let path = volume_path(req.path())?;
+    if req.method().as_str() == "PUT" {
+        let body = payload.map(|chunk| chunk.map_err(|e| Error::Other(e.to_string())));
+        return put(req, env, &user, &vol, &path, body.boxed_local()).await;
+    }
+
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk.map_err(|e| Error::Other(e.to_string()))?);
+        if body.len() > MAX_XML_SIZE {
+            return Ok(status(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+    }
+    let body = &body[..];
+
+    match req.method().as_str() {
+        "GET" | "HEAD" => get(req, &vol, &path).await,
+        "PROPFIND" => propfind(req, &vol, &path).await,
+        "MKCOL" => mkcol(req, env, &user, &vol, &path, body).await,
+        "DELETE" => delete(req, env, &user, &vol, &path).await,
+        "COPY" => transfer(req, env, &user, &vol, &path, false).await,
+        "MOVE" => transfer(req, env, &user, &vol, &path, true).await,
+        "LOCK" => lock(req, env, &user, &vol, &path, body).await,
+        "UNLOCK" => unlock(req, &user, &vol, &path),
+        _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    }
+}
+
+async fn handle(req: HttpRequest, env: web::Data<Environment>, payload: web::Payload) -> HttpResponse {
+    // clients probe for WebDAV support before they authenticate
+    if req.method() == Method::OPTIONS {
+        return options();
+    }
+    match serve(&req, &env, payload).await {
+        Ok(response) => response,
+        Err(e) => {
+            let code = error_status(&e);
+            let mut response = HttpResponse::build(code);
+            if code == StatusCode::UNAUTHORIZED {
+                response.header(header::WWW_AUTHENTICATE, "Basic realm=\"arca\"");
+            }
+            response.finish()
+        }
+    }
+}
+
+pub fn service() -> Scope {
+    web::scope(PREFIX)
+        .default_service(web::route().to(handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn maps_urls_to_volume_paths() {
+        assert_eq!(volume_path("/dav").unwrap(), Path::new("/"));
+        assert_eq!(volume_path("/dav/").unwrap(), Path::new("/"));
+        assert_eq!(volume_path("/dav/docs/a%20b.txt").unwrap(), Path::new("/docs/a b.txt"));
+        assert!(volume_path("/other/a.txt").is_err());
+        assert!(volume_path("/dav/%ff").is_err());
+    }
+
+    #[test]
+    fn encodes_hrefs() {
+        assert_eq!(href(Path::new("/"), true), "/dav/");
+        assert_eq!(href(Path::new("/"), false), "/dav/");
+        assert_eq!(href(Path::new("/docs"), true), "/dav/docs/");
+        assert_eq!(href(Path::new("/docs/a b&c.txt"), false), "/dav/docs/a%20b%26c.txt");
+        assert_eq!(volume_path(&href(Path::new("/ä/b?.txt"), false)).unwrap(), Path::new("/ä/b?.txt"));
+    }
+
+    #[test]
+    fn reads_destinations() {
+        let req = TestRequest::default()
+            .header("Destination", "https://example.com/dav/new%20name.txt")
+            .to_http_request();
+        assert_eq!(destination(&req).unwrap(), Path::new("/new name.txt"));
+
+        let req = TestRequest::default().header("Destination", "/dav/docs/").to_http_request();
+        assert_eq!(destination(&req).unwrap(), Path::new("/docs"));
+
+        let req = TestRequest::default()
+            .header("Destination", "https://example.com/elsewhere/a.txt")
+            .to_http_request();
+        assert!(destination(&req).is_err());
+        assert!(destination(&TestRequest::default().to_http_request()).is_err());
+    }
+
+    #[test]
+    fn collects_submitted_tokens() {
+        let req = TestRequest::default()
+            .header(
+                "If",
+                "</dav/a.txt> (<opaquelocktoken:1>) (Not <DAV:no-lock>) (<opaquelocktoken:2> [\"etag\"])",
+            )
+            .to_http_request();
+        assert_eq!(submitted_tokens(&req), vec!["opaquelocktoken:1", "opaquelocktoken:2"]);
+        assert!(submitted_tokens(&TestRequest::default().to_http_request()).is_empty());
+    }
+
+    #[test]
+    fn parses_lockinfo() {
+        let exclusive = br#"<?xml version="1.0" encoding="utf-8"?>
+            <D:lockinfo xmlns:D="DAV:">
+                <D:lockscope><D:exclusive/></D:lockscope>
+                <D:locktype><D:write/></D:locktype>
+                <D:owner><D:href>mailto:alice@example.com</D:href></D:owner>
+            </D:lockinfo>"#;
+        assert_eq!(
+            parse_lockinfo(exclusive).unwrap(),
+            (false, "mailto:alice@example.com".to_owned())
+        );
+
+        let shared = br#"<lockinfo xmlns="DAV:"><lockscope><shared/></lockscope><owner>Bob &amp; co</owner></lockinfo>"#;
+        assert_eq!(parse_lockinfo(shared).unwrap(), (true, "Bob & co".to_owned()));
+
+        assert!(parse_lockinfo(b"<lockinfo><owner></lockinfo>").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Server-sent events telling finder sessions about files added, removed or changed in
+//! their volumes, e.g. by a teammate uploading to a shared folder. Each event is named after
+//! the kind of change and carries the finder hashes of the file and its parent directory.
+//!
+//! Streams stay open for long, so the caller is authenticated again and their mounts are
+//! resolved anew every little while. The stream ends once the caller was logged out, disabled
+//! or deleted, and folders no longer granted to them stop reporting changes.
+
+use crate::api::finder;
+use crate::change::Change;
+use crate::env::Environment;
+use crate::error::Error;
+use crate::file::File;
+use crate::user::error::Error as UserError;
+use crate::user::token::ApiToken;
+use crate::user::User;
+use crate::volume::Volume;
+use actix_session::Session;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse, Resource};
+use futures::stream::{self, StreamExt};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::RecvError;
+
+/// Comments sent in between events so proxies keep idle connections open
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// How long the caller's authentication and mounts are relied on before they are checked again
+const RECHECK: Duration = Duration::from_secs(60);
+
+enum Input {
+    Change(Change),
+    Keepalive,
+}
+
+/// What a stream knows about its caller, checked again every `RECHECK`
+struct Subscriber {
+    user_id: uuid::Uuid,
+    session_version: i32,
+    api_token: Option<uuid::Uuid>,
+    netmounts: Vec<uuid::Uuid>,
+    mounts: Vec<Volume>,
+    checked_at: Instant,
+}
+
+impl Subscriber {
+    /// Authenticates the caller again like `finder::authenticate` did when the stream was
+    /// opened, then resolves their mounts anew
+    async fn recheck(&mut self, env: &Environment) -> Result<(), Error> {
+        let (user, api_token) = {
+            let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+            let user = User::find_by_id(&conn, self.user_id)?;
+            user.ensure_active()?;
+            let api_token = match self.api_token {
+                Some(id) => Some(ApiToken::find(&conn, user.id, id)?),
+                None if user.session_version != self.session_version => {
+                    return Err(UserError::NotAuthenticated.into())
+                }
+                None => None,
+            };
+            (user, api_token)
+        };
+
+        let mut mounts = finder::mounts(env, &user, api_token.as_ref()).await?;
+        if api_token.is_none() {
+            mounts.extend(finder::netmount_volumes(env, &user, &self.netmounts)?);
+        }
+        self.mounts = mounts;
+        self.checked_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// The change as one event per mount it happened in, nothing if it is not visible in any
+fn events(mounts: &[Volume], change: &Change) -> String {
+    mounts
+        .iter()
+        .filter(|vol| change.concerns(vol))
+        .filter_map(|vol| {
+            let relative = change.path.strip_prefix(&vol.path).ok()?;
+            // trash and versions are not part of the volume contents
+            File::check_path(vol, relative).ok()?;
+            let data = serde_json::json!({
+                "hash": File::hash(vol, relative),
+                "phash": relative.parent().map(|parent| File::hash(vol, parent)),
+            });
+            Some(format!("event: {}\ndata: {}\n\n", change.kind.as_str(), data))
+        })
+        .collect()
+}
+
+/// Streams the changes in the caller's volumes, including the netmounts of the session, as
+/// they happen
+async fn subscribe(req: HttpRequest, env: web::Data<Environment>, session: Session) -> Result<HttpResponse, Error> {
+    let (user, api_token) = finder::authenticate(&req, &env, &session)?;
+    let subscriber = Subscriber {
+        user_id: user.id,
+        session_version: user.session_version,
+        api_token: api_token.as_ref().map(|api_token| api_token.id),
+        netmounts: finder::session_netmounts(&session),
+        mounts: finder::session_mounts(&env, &session, &user, api_token.as_ref()).await?,
+        checked_at: Instant::now(),
+    };
+
+    let changes = stream::unfold(env.changes.subscribe(), move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(change) => return Some((Input::Change(change), receiver)),
+                // changes the session fell behind on are lost, it refreshes on the next one
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed_local();
+    let keepalive = stream::unfold(tokio::time::interval(KEEPALIVE), |mut interval| async move {
+        interval.tick().await;
+        Some((Input::Keepalive, interval))
+    })
+    .boxed_local();
+
+    let inputs = stream::select(changes, keepalive);
+    let body = stream::unfold((inputs, subscriber), move |(mut inputs, mut subscriber)| {
+        let env = env.clone();
+        async move {
+            loop {
+                let input = inputs.next().await?;
+                if subscriber.checked_at.elapsed() >= RECHECK {
+                    match subscriber.recheck(&env).await {
+                        Ok(()) => {}
+                        // the mounts resolved last are used until the database is back
+                        Err(Error::UserError(UserError::DbError)) => {}
+                        Err(_) => return None,
+                    }
+                }
+                let events = match input {
+                    Input::Change(change) => events(&subscriber.mounts, &change),
+                    Input::Keepalive => ": keepalive\n\n".to_owned(),
+                };
+                if !events.is_empty() {
+                    return Some((events, (inputs, subscriber)));
+                }
+            }
+        }
+    })
+    .map(|events| Ok::<_, Error>(Bytes::from(events)));
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .streaming(body))
+}
+
+pub fn service() -> Resource {
+    web::resource("/events").route(web::get().to(subscribe))
+}
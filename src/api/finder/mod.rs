@@ -1,38 +1,373 @@
 pub mod ops;
 
+use crate::api::user::current_user;
+use crate::file::File;
+use crate::grant::Grant;
+use crate::group::Group;
+use crate::netmount::Netmount;
+use crate::privsep;
+use crate::trash::TRASH_ID;
 use crate::user::User;
 use crate::user::error::Error as UserError;
+use crate::user::token::{ApiToken, Scope};
 use crate::env::Environment;
+use crate::volume::{Access, Volume};
+use actix_http::http::header;
+use actix_multipart::Multipart;
 use actix_session::Session;
-use actix_web::{web, HttpResponse, Resource};
+use actix_web::{guard, web, HttpResponse, Resource};
+use futures::StreamExt;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::env;
 use std::path::{Path, PathBuf};
 use crate::error::Error;
 
+/// Commands which only read, any other command is treated as one which modifies a volume
+const READ_COMMANDS: &[&str] = &[
+    "dim", "file", "get", "info", "ls", "open", "parents", "search", "size", "tmb", "tree",
+];
+
+/// Parameters of write commands naming the files or directories being modified
+const WRITE_TARGETS: &[&str] = &["target", "targets[]", "dst"];
+
+/// Session key holding the ids of the netmounts mounted during the session
+const NETMOUNTS_KEY: &str = "netmounts";
+
+/// Largest request body accepted by commands, e.g. the content of `put` or an upload
+pub(crate) const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Parameters of a command, taken from the query string and the request body
+pub struct Params {
+    values: Vec<(String, String)>,
+    /// Files sent with `upload` as their names and contents
+    pub(crate) uploads: Vec<(String, web::Bytes)>,
+}
+
+impl Params {
+    fn parse(query: &str) -> Result<Self, Error> {
+        Ok(Self {
+            values: serde_urlencoded::from_str(query).map_err(|_| Error::InvalidParams)?,
+            uploads: Vec::new(),
+        })
+    }
+
+    /// First value of a parameter
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of a parameter, e.g. of `targets[]`
+    pub fn all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.values
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn bearer_token(req: &web::HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Resolves the caller either from an `Authorization: Bearer` token or the session cookie
+pub(crate) fn authenticate(
+    req: &web::HttpRequest,
+    env: &Environment,
+    session: &Session,
+) -> Result<(User, Option<ApiToken>), Error> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    if let Some(token) = bearer_token(req) {
+        let (api_token, user) = ApiToken::authenticate(&conn, token)?;
+        return Ok((user, Some(api_token)));
+    }
+
+    let user = current_user(session, &conn)?;
+    Ok((user, None))
+}
+
+/// The volumes a user reaches by themselves: their own volume, the extra volumes assigned
+/// to them and those of their groups
+pub(crate) async fn owned_mounts(env: &Environment, user: &User) -> Result<Vec<Volume>, Error> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let mut mounts = Volume::for_user(env, user).await?;
+    mounts.extend(Group::volumes(&conn, &env.finder_root, user.id)?);
+    Ok(mounts)
+}
+
+/// The innermost mounted local volume holding the absolute `path`
+pub(crate) fn containing<'a>(mounts: &'a [Volume], path: &Path) -> Option<&'a Volume> {
+    mounts
+        .iter()
+        .filter(|vol| vol.run_as.is_none() && vol.driver.is_local() && path.starts_with(&vol.path))
+        .max_by_key(|vol| vol.path.components().count())
+}
+
+/// Folders other users granted the user access to. A grant
+/// never allows more than its owner may currently do in the folder and shares the quota of
+/// the owner's volume, grants of owners who lost access or whose folder was moved are left out.
+async fn granted_mounts(env: &Environment, user: &User) -> Result<Vec<Volume>, Error> {
+    let grants = {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        Grant::received(&conn, user.id)?
+    };
+
+    let mut owners: HashMap<uuid::Uuid, Vec<Volume>> = HashMap::new();
+    let mut mounts = Vec::new();
+    for grant in grants {
+        if let Entry::Vacant(entry) = owners.entry(grant.owner_id) {
+            let owner = {
+                let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+                User::find_by_id(&conn, grant.owner_id)
+            };
+            let owner_mounts = match owner {
+                Ok(owner) if owner.ensure_active().is_ok() => {
+                    owned_mounts(env, &owner).await.unwrap_or_default()
+                }
+                _ => Vec::new(),
+            };
+            entry.insert(owner_mounts);
+        }
+
+        let path = PathBuf::from(&grant.path);
+        let (owner_access, quota) = match containing(&owners[&grant.owner_id], &path) {
+            Some(vol) => (vol.access, vol.quota.clone()),
+            None => continue,
+        };
+        match privsep::stat(None, &path).await {
+            Ok(stat) if stat.is_dir && grant.is_target(&stat) => {}
+            _ => continue,
+        }
+
+        let access = grant.access().min(owner_access).min(Access::Write);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let id = Volume::grant_id(grant.id);
+        // writes count against the owner's volume
+        mounts.push(Volume {
+            quota,
+            ..Volume::new(path, &id, &name, access)
+        });
+    }
+    Ok(mounts)
+}
+
+/// Every volume the caller can access, including folders granted by other users
+pub(crate) async fn mounts(env: &Environment, user: &User, api_token: Option<&ApiToken>) -> Result<Vec<Volume>, Error> {
+    let mut mounts = match api_token.and_then(|api_token| api_token.volume_path.as_ref()) {
+        // path restricted tokens only see that part of the user's volume
+        Some(path) => vec![Volume::create_or_find(env, user).await?.restrict(path).await?],
+        None => {
+            let mut mounts = owned_mounts(env, user).await?;
+            mounts.extend(granted_mounts(env, user).await?);
+            mounts
+        }
+    };
+
+    if api_token.map_or(false, |api_token| api_token.scope() == Scope::Read) {
+        for vol in &mut mounts {
+            vol.access = Access::Read;
+        }
+    }
+    Ok(mounts)
+}
+
+/// Ids of the netmounts mounted during the session
+pub(crate) fn session_netmounts(session: &Session) -> Vec<uuid::Uuid> {
+    session
+        .get::<Vec<uuid::Uuid>>(NETMOUNTS_KEY)
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+pub(crate) fn set_session_netmounts(session: &Session, ids: Vec<uuid::Uuid>) -> Result<(), Error> {
+    session
+        .set(NETMOUNTS_KEY, ids)
+        .map_err(|_| UserError::SessionError.into())
+}
+
+/// The volumes of the user's netmounts among `ids`, those mounted during a session
+pub(crate) fn netmount_volumes(env: &Environment, user: &User, ids: &[uuid::Uuid]) -> Result<Vec<Volume>, Error> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    Netmount::find_all(&conn, user.id, ids)?
+        .iter()
+        .map(|mount| mount.volume(&env.secret_key))
+        .collect()
+}
+
+/// The caller's mounts along with the netmounts of the session. Netmounts belong to the
+/// browser session, API tokens never see them.
+pub(crate) async fn session_mounts(
+    env: &Environment,
+    session: &Session,
+    user: &User,
+    api_token: Option<&ApiToken>,
+) -> Result<Vec<Volume>, Error> {
+    let mut mounts = mounts(env, user, api_token).await?;
+    if api_token.is_none() {
+        mounts.extend(netmount_volumes(env, user, &session_netmounts(session))?);
+    }
+    Ok(mounts)
+}
+
+/// Finds the mounted volume a hash belongs to and the path it encodes
+pub fn resolve<'a>(mounts: &'a [Volume], hash: &str) -> Result<(&'a Volume, PathBuf), Error> {
+    let (id, path) = File::decode_hash(hash)?;
+    let vol = mounts
+        .iter()
+        .find(|vol| vol.id == id)
+        .ok_or(Error::PathError)?;
+    Ok((vol, path))
+}
+
+/// Fails unless the token scope allows the command
+fn check_scope(scope: Scope, cmd: &str) -> Result<(), Error> {
+    if scope == Scope::Read && !READ_COMMANDS.contains(&cmd) {
+        return Err(Error::UserError(UserError::NotAuthorized));
+    }
+    Ok(())
+}
+
+/// Checks that write commands only touch volumes the caller may modify, and only inside
+/// them. Entries of the trash are checked against the volume they were deleted from when
+/// they are resolved.
+fn authorize(params: &Params, cmd: &str, mounts: &[Volume]) -> Result<(), Error> {
+    if READ_COMMANDS.contains(&cmd) {
+        return Ok(());
+    }
+
+    // copying only reads the pasted files
+    let copy_only = cmd == "paste" && params.get("cut") != Some("1");
+    let targets = WRITE_TARGETS
+        .iter()
+        .filter(|key| !(copy_only && **key == "targets[]"))
+        .flat_map(|key| params.all(key))
+        .filter(|hash| !hash.starts_with(TRASH_ID));
+    for hash in targets {
+        let (vol, path) = resolve(mounts, hash)?;
+        vol.check_write()?;
+        File::check_path(vol, path)?;
+    }
+    Ok(())
+}
+
+/// Commands sent as a query string or a form encoded body
 async fn command(
     req: web::HttpRequest,
     env: web::Data<Environment>,
-    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
     session: Session,
 ) -> Result<HttpResponse, Error> {
-    if let Some(user) = session
-        .get::<User>("user")
-        .map_err(|_| Error::UserError(UserError::SessionError))?
-    {
-        let cmd = query.get("cmd").ok_or(Error::InvalidParams)?;
+    let mut params = Params::parse(req.query_string())?;
+    let form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/x-www-form-urlencoded"));
+    if form {
+        let body = std::str::from_utf8(&body).map_err(|_| Error::InvalidParams)?;
+        params.values.extend(Params::parse(body)?.values);
+    }
+    dispatch(&req, &env, &session, &params).await
+}
 
-        match &cmd as &str {
-            "open" => ops::open(&req, &env, &user).await,
-            _ => Ok(HttpResponse::Ok().finish()),
+/// Commands sent as `multipart/form-data`, i.e. uploads
+async fn multipart_command(
+    req: web::HttpRequest,
+    env: web::Data<Environment>,
+    mut payload: Multipart,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let mut params = Params::parse(req.query_string())?;
+    let mut total = 0;
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| Error::Other(e.to_string()))?;
+        let disposition = field.content_disposition().ok_or(Error::InvalidParams)?;
+        let name = disposition.get_name().ok_or(Error::InvalidParams)?.to_owned();
+        let filename = disposition.get_filename().map(str::to_owned);
+
+        let mut data = web::BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| Error::Other(e.to_string()))?;
+            total += chunk.len();
+            if total > MAX_BODY_SIZE {
+                return Err(Error::InvalidParams);
+            }
+            data.extend_from_slice(&chunk);
         }
-    } else {
-        Err(Error::UserError(UserError::NotAuthenticated))
+
+        match filename {
+            Some(filename) => params.uploads.push((filename, data.freeze())),
+            None => {
+                let value = String::from_utf8(data.to_vec()).map_err(|_| Error::InvalidParams)?;
+                params.values.push((name, value));
+            }
+        }
+    }
+    dispatch(&req, &env, &session, &params).await
+}
+
+async fn dispatch(
+    req: &web::HttpRequest,
+    env: &Environment,
+    session: &Session,
+    params: &Params,
+) -> Result<HttpResponse, Error> {
+    let (user, api_token) = authenticate(req, env, session)?;
+    let cmd = params.get("cmd").ok_or(Error::InvalidParams)?;
+
+    if let Some(api_token) = &api_token {
+        check_scope(api_token.scope(), cmd)?;
+    }
+
+    let mounts = session_mounts(env, session, &user, api_token.as_ref()).await?;
+    authorize(params, cmd, &mounts)?;
+
+    match cmd {
+        "open" => ops::open(params, env, &mounts).await,
+        "rm" => ops::rm(params, env, &user, &mounts).await,
+        "restore" => ops::restore(params, env, &mounts).await,
+        "empty" => ops::empty(params, env, &mounts).await,
+        "put" => ops::put(params, env, &user, &mounts).await,
+        "upload" => ops::upload(params, env, &user, &mounts).await,
+        "paste" => ops::paste(params, env, &mounts).await,
+        "duplicate" => ops::duplicate(params, env, &mounts).await,
+        "size" => ops::size(params, &mounts).await,
+        "file" => ops::file(params, &mounts).await,
+        "netmount" if api_token.is_none() => ops::netmount(params, env, session, &user, &mounts).await,
+        "netmount" => Err(Error::Forbidden),
+        _ => Ok(HttpResponse::Ok().finish()),
     }
 }
 
 pub fn service() -> Resource {
-    web::resource("/finder").route(web::to(command))
+    web::resource("/finder")
+        .data(web::PayloadConfig::new(MAX_BODY_SIZE))
+        .route(
+            web::post()
+                .guard(guard::fn_guard(|head| {
+                    head.headers()
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|value| value.to_str().ok())
+                        .map_or(false, |value| value.starts_with("multipart/form-data"))
+                }))
+                .to(multipart_command),
+        )
+        .route(web::to(command))
 }
 
 pub fn init() -> PathBuf {
@@ -44,3 +379,59 @@ pub fn init() -> PathBuf {
     }
     PathBuf::from(root_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRITE_COMMANDS: &[&str] = &[
+        "archive", "chmod", "duplicate", "empty", "extract", "mkdir", "mkfile", "paste", "put",
+        "rename", "resize", "restore", "rm", "upload",
+    ];
+
+    fn params(cmd: &str, key: &str, hash: &str) -> Params {
+        Params::parse(&format!("cmd={}&{}={}", cmd, key, hash)).unwrap()
+    }
+
+    #[test]
+    fn read_tokens_only_run_read_commands() {
+        for cmd in WRITE_COMMANDS.iter().chain(&["netmount", "unknown"]) {
+            assert!(check_scope(Scope::Read, cmd).is_err(), "{} was allowed", cmd);
+            assert!(check_scope(Scope::ReadWrite, cmd).is_ok());
+        }
+        for cmd in &["open", "file", "size"] {
+            assert!(check_scope(Scope::Read, cmd).is_ok());
+        }
+    }
+
+    #[test]
+    fn write_commands_need_writable_targets() {
+        // what read tokens see of the user's volume
+        let vol = Volume::new("/finder-tests/read", "l1_", "Home", Access::Read);
+        let hash = File::hash(&vol, "/a.txt");
+        let mounts = [vol];
+        for cmd in WRITE_COMMANDS {
+            assert!(authorize(&params(cmd, "target", &hash), cmd, &mounts).is_err());
+        }
+        assert!(authorize(&params("open", "target", &hash), "open", &mounts).is_ok());
+        // pasted copies are only read
+        assert!(authorize(&params("paste", "targets[]", &hash), "paste", &mounts).is_ok());
+    }
+
+    #[test]
+    fn restricted_tokens_stay_inside_their_folder() {
+        let home = Volume::new("/finder-tests/home", "l1_", "Home", Access::Write);
+        let other = File::hash(&Volume::new("/finder-tests/other", "l2_", "Other", Access::Write), "/a.txt");
+        let mounts = [Volume {
+            path: home.path.join("docs"),
+            ..home
+        }];
+        let inside = File::hash(&mounts[0], "/a.txt");
+        let outside = format!("l1_{}", base64::encode_config("../secret.txt", base64::URL_SAFE_NO_PAD));
+
+        assert!(authorize(&params("rm", "targets[]", &inside), "rm", &mounts).is_ok());
+        assert!(authorize(&params("rm", "targets[]", &outside), "rm", &mounts).is_err());
+        assert!(authorize(&params("mkdir", "target", &outside), "mkdir", &mounts).is_err());
+        assert!(authorize(&params("paste", "dst", &other), "paste", &mounts).is_err());
+    }
+}
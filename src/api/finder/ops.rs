@@ -1,24 +1,144 @@
+use super::{resolve, session_netmounts, set_session_netmounts, Params};
+use crate::blob::BlobStore;
+use crate::change::Kind;
 use crate::env::Environment;
 use crate::error::Error;
 use crate::file;
+use crate::netmount::Netmount;
+use crate::storage::sftp::{Auth, Config};
+use crate::storage::{temp_path, ByteStream};
+use crate::trash::{TrashEntry, TRASH_ID};
+use crate::user::error::Error as UserError;
 use crate::user::User;
-use crate::volume::Volume;
-use actix_web::{web, HttpResponse};
-use serde_derive::{Deserialize, Serialize};
+use crate::version::Version;
+use crate::volume::{Access, Volume};
+use actix_session::Session;
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use diesel::pg::PgConnection;
+use futures::{stream, StreamExt};
+use sha2::{Digest, Sha256};
+use serde_derive::Serialize;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 
-pub async fn open(
-    req: &web::HttpRequest,
-    env: &web::Data<Environment>,
-    user: &User,
-) -> Result<HttpResponse, Error> {
-    #[derive(Deserialize)]
-    struct Params {
-        init: Option<bool>,
-        target: Option<String>,
-        tree: Option<bool>,
+/// Values of all `targets[]` (and `target`) parameters
+fn targets(params: &Params) -> Vec<String> {
+    params
+        .all("targets[]")
+        .chain(params.all("target"))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Whether a flag such as `init=1` is set
+fn flag(params: &Params, key: &str) -> bool {
+    params.get(key).map_or(false, |value| value == "1" || value == "true")
+}
+
+/// The mounted volume a trashed entry was deleted from, the trash is only visible to
+/// those who may modify the volume
+fn trash_mount<'a>(mounts: &'a [Volume], entry: &TrashEntry) -> Option<&'a Volume> {
+    let volume_path = Path::new(&entry.volume_path);
+    mounts
+        .iter()
+        .filter(|vol| vol.access.can_write() && vol.driver.as_str() == entry.driver)
+        .filter(|vol| volume_path.starts_with(&vol.path))
+        .max_by_key(|vol| vol.path.components().count())
+}
+
+/// The trash directory of a volume, mounted under the trash root's id
+fn trash_volume(mount: &Volume, entry: &TrashEntry) -> Volume {
+    Volume {
+        run_as: mount.run_as.clone(),
+        driver: mount.driver,
+        ..Volume::new(
+            TrashEntry::dir(Path::new(&entry.volume_path)),
+            TRASH_ID,
+            "Trash",
+            Access::Write,
+        )
     }
-    
+}
+
+fn visible_entries(conn: &PgConnection, mounts: &[Volume]) -> Result<Vec<TrashEntry>, Error> {
+    let roots = mounts
+        .iter()
+        .filter(|vol| vol.access.can_write() && !vol.is_netmount())
+        .collect::<Vec<_>>();
+    TrashEntry::below(conn, &roots)
+}
 
+/// Resolves a hash of the trash root to a trashed entry and the path inside of it,
+/// `None` for the trash root itself
+fn resolve_trash<'a>(
+    conn: &PgConnection,
+    mounts: &'a [Volume],
+    hash: &str,
+) -> Result<Option<(TrashEntry, &'a Volume, PathBuf)>, Error> {
+    let (id, path) = file::File::decode_hash(hash)?;
+    if id != TRASH_ID {
+        return Err(Error::PathError);
+    }
+    let mut components = path.components().filter_map(|c| match c {
+        Component::Normal(part) => Some(part),
+        _ => None,
+    });
+    let entry_id = match components.next() {
+        Some(entry_id) => entry_id
+            .to_str()
+            .and_then(|entry_id| uuid::Uuid::parse_str(entry_id).ok())
+            .ok_or(Error::PathError)?,
+        None => return Ok(None),
+    };
+    let inner = components.collect::<PathBuf>();
+
+    let entry = TrashEntry::find(conn, entry_id)?;
+    let mount = trash_mount(mounts, &entry).ok_or(Error::PathError)?;
+    Ok(Some((entry, mount, inner)))
+}
+
+/// Describes a trashed entry, or a file inside of it, as part of the trash root
+async fn trash_file(mount: &Volume, entry: &TrashEntry, inner: &Path) -> Result<file::File, Error> {
+    let vol = trash_volume(mount, entry);
+    let path = Path::new("/").join(entry.id.to_simple().to_string()).join(inner);
+    let file = file::File::info(&vol, &path).await?;
+    if inner.as_os_str().is_empty() {
+        Ok(file.with_name(entry.name()))
+    } else {
+        Ok(file)
+    }
+}
+
+async fn open_trash(
+    conn: &PgConnection,
+    mounts: &[Volume],
+    target: &str,
+) -> Result<(file::File, Vec<file::File>), Error> {
+    match resolve_trash(conn, mounts, target)? {
+        None => {
+            let mut files = Vec::new();
+            for entry in visible_entries(conn, mounts)? {
+                if let Some(mount) = trash_mount(mounts, &entry) {
+                    files.push(trash_file(mount, &entry, Path::new("")).await?);
+                }
+            }
+            let cwd = file::File::virtual_root(TRASH_ID, "Trash", files.iter().any(|f| f.is_dir()));
+            Ok((cwd, files))
+        }
+        Some((entry, mount, inner)) => {
+            let cwd = trash_file(mount, &entry, &inner).await?;
+            let path = Path::new("/").join(entry.id.to_simple().to_string()).join(&inner);
+            let files = file::File::open_dir(&trash_volume(mount, &entry), &path).await?;
+            Ok((cwd, files))
+        }
+    }
+}
+
+pub async fn open(params: &Params, env: &Environment, mounts: &[Volume]) -> Result<HttpResponse, Error> {
     #[derive(Serialize)]
     struct Response {
         api: f32,
@@ -26,29 +146,498 @@ pub async fn open(
         files: Vec<file::File>,
     }
 
-    let params: web::Query<Params> =
-        web::Query::from_query(req.query_string()).map_err(|_| Error::InvalidParams)?;
-
-    let _ = params.tree; // TODO: handle tree parameter
-    if let Some(true) = &params.init {
-        let target = params.target.as_deref().unwrap_or("/");
-        let vol = Volume::create_or_find(env, user).await?;
-        let files = vol.ls_path(target).await?;
-        let cwd = vol.root().await?;
-        Ok(HttpResponse::Ok().json(Response {
-            api: 2.1, 
-            cwd,
-            files,
-        }))
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+
+    let init = flag(params, "init");
+    let target = params.get("target").filter(|target| !target.is_empty());
+    let (cwd, mut files) = match target {
+        Some(target) if target.starts_with(TRASH_ID) => open_trash(&conn, mounts, target).await?,
+        _ => {
+            let (vol, path) = match target.map(|target| resolve(mounts, target)) {
+                Some(Ok(resolved)) => resolved,
+                // on init an unknown target falls back to the default root
+                Some(Err(_)) | None if init => (&mounts[0], PathBuf::from("/")),
+                Some(Err(e)) => return Err(e),
+                None => return Err(Error::InvalidParams),
+            };
+            (file::File::info(vol, &path).await?, file::File::open_dir(vol, &path).await?)
+        }
+    };
+
+    if flag(params, "tree") {
+        for root in mounts {
+            files.push(root.root().await?);
+        }
+        if mounts.iter().any(|vol| vol.access.can_write()) {
+            let trashed = !visible_entries(&conn, mounts)?.is_empty();
+            files.push(file::File::virtual_root(TRASH_ID, "Trash", trashed));
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(Response {
+        api: 2.1,
+        cwd,
+        files,
+    }))
+}
+
+/// Moves files to the trash, files already in the trash or on netmounts are deleted for good
+pub async fn rm(
+    params: &Params,
+    env: &Environment,
+    user: &User,
+    mounts: &[Volume],
+) -> Result<HttpResponse, Error> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+
+    let mut removed = Vec::new();
+    for target in targets(params) {
+        if target.starts_with(TRASH_ID) {
+            match resolve_trash(&conn, mounts, &target)? {
+                None => return Err(Error::InvalidParams),
+                Some((entry, mount, inner)) if inner.as_os_str().is_empty() => {
+                    entry.remove(&conn, &*mount.storage()).await?
+                }
+                Some((entry, mount, inner)) => {
+                    let vol = trash_volume(mount, &entry);
+                    let path = Path::new("/").join(entry.id.to_simple().to_string()).join(&inner);
+                    let full = file::File::check_path(&vol, &path)?;
+                    vol.storage().remove(&full).await?;
+                }
+            }
+        } else {
+            let (vol, path) = resolve(mounts, &target)?;
+            let full = file::File::check_path(vol, &path)?;
+            if vol.is_netmount() {
+                vol.storage().remove(&full).await?;
+            } else {
+                TrashEntry::trash(&conn, vol, &path, user.id).await?;
+            }
+            env.changes.publish(vol, &full, Kind::Removed);
+        }
+        removed.push(target);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "removed": removed })))
+}
+
+/// Moves trashed files back to where they were deleted from
+pub async fn restore(
+    params: &Params,
+    env: &Environment,
+    mounts: &[Volume],
+) -> Result<HttpResponse, Error> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for target in targets(params) {
+        let (entry, mount, inner) = resolve_trash(&conn, mounts, &target)?.ok_or(Error::InvalidParams)?;
+        // only whole entries can be restored
+        if !inner.as_os_str().is_empty() {
+            return Err(Error::InvalidParams);
+        }
+
+        let volume_path = Path::new(&entry.volume_path)
+            .strip_prefix(&mount.path)
+            .map_err(|_| Error::PathError)?
+            .to_owned();
+        let path = Path::new("/")
+            .join(volume_path)
+            .join(entry.original_path.trim_start_matches('/'));
+        let restored = entry.restore(&conn, mount, &path).await?;
+        env.changes
+            .publish(mount, &file::File::check_path(mount, &restored)?, Kind::Added);
+
+        added.push(file::File::info(mount, &restored).await?);
+        removed.push(target);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "added": added,
+        "removed": removed,
+    })))
+}
+
+/// Empties the trash, the only folder which can be emptied
+pub async fn empty(
+    params: &Params,
+    env: &Environment,
+    mounts: &[Volume],
+) -> Result<HttpResponse, Error> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+
+    let mut removed = Vec::new();
+    for target in targets(params) {
+        if resolve_trash(&conn, mounts, &target)?.is_some() {
+            return Err(Error::InvalidParams);
+        }
+        for entry in visible_entries(&conn, mounts)? {
+            let mount = match trash_mount(mounts, &entry) {
+                Some(mount) => mount,
+                None => continue,
+            };
+            let path = Path::new("/").join(entry.id.to_simple().to_string());
+            let hash = file::File::hash(&trash_volume(mount, &entry), path);
+            let storage = mount.storage();
+            entry.remove(&conn, &*storage).await?;
+            removed.push(hash);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "removed": removed })))
+}
+
+/// Writes `data` to the file at `path`, relative to the volume root, keeping what it
+/// replaces as a version
+pub(crate) async fn write_file(
+    env: &Environment,
+    user: &User,
+    vol: &Volume,
+    path: &Path,
+    data: &[u8],
+) -> Result<file::File, Error> {
+    let data = Bytes::copy_from_slice(data);
+    write_stream(env, user, vol, path, stream::once(async move { Ok(data) }).boxed_local()).await
+}
+
+/// Writes the file at `path`, relative to the volume root, from `data` as it arrives. The
+/// content goes to a temporary file next to it first, the quota is checked as it is
+/// written and the hash it is versioned and stored by is known once it is complete.
+pub(crate) async fn write_stream(
+    env: &Environment,
+    user: &User,
+    vol: &Volume,
+    path: &Path,
+    data: ByteStream,
+) -> Result<file::File, Error> {
+    vol.check_write()?;
+    let full = file::File::check_path(vol, path)?;
+    let storage = vol.storage();
+    let mut remaining = vol.quota_remaining().await?;
+
+    let kind = match storage.stat(&full).await {
+        Ok(_) => Kind::Changed,
+        Err(_) => Kind::Added,
+    };
+    let hasher = Rc::new(RefCell::new(Sha256::new()));
+    let data = {
+        let hasher = hasher.clone();
+        data.map(move |chunk| {
+            let chunk = chunk?;
+            if let Some(remaining) = remaining.as_mut() {
+                *remaining = remaining.checked_sub(chunk.len() as u64).ok_or(Error::QuotaExceeded)?;
+            }
+            hasher.borrow_mut().update(&chunk);
+            Ok(chunk)
+        })
+        .boxed_local()
+    };
+    let tmp = temp_path(&full);
+    if let Err(e) = storage.write(&tmp, data).await {
+        let _ = storage.remove(&tmp).await;
+        return Err(e);
+    }
+    let hash = hex::encode(hasher.borrow_mut().finalize_reset());
+
+    let moved = async {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        if !vol.is_netmount() {
+            Version::keep(&conn, vol, path, &hash, user.id, env.versions).await?;
+        }
+        BlobStore::move_file(env.blobs.as_ref(), &conn, vol, &tmp, &full, &hash).await
+    };
+    if let Err(e) = moved.await {
+        let _ = storage.remove(&tmp).await;
+        return Err(e);
+    }
+    env.changes.publish(vol, &full, kind);
+    file::File::info(vol, path).await
+}
+
+/// Replaces the content of a text file
+pub async fn put(
+    params: &Params,
+    env: &Environment,
+    user: &User,
+    mounts: &[Volume],
+) -> Result<HttpResponse, Error> {
+    let target = params.get("target").ok_or(Error::InvalidParams)?;
+    let content = params.get("content").unwrap_or_default();
+    let (vol, path) = resolve(mounts, target)?;
+    if file::File::info(vol, &path).await?.is_dir() {
+        return Err(Error::InvalidParams);
+    }
+
+    let changed = write_file(env, user, vol, &path, content.as_bytes()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "changed": [changed] })))
+}
+
+/// Stores uploaded files in the target folder, files they replace are kept as versions
+pub async fn upload(
+    params: &Params,
+    env: &Environment,
+    user: &User,
+    mounts: &[Volume],
+) -> Result<HttpResponse, Error> {
+    let target = params.get("target").ok_or(Error::InvalidParams)?;
+    let (vol, dir) = resolve(mounts, target)?;
+    if !file::File::info(vol, &dir).await?.is_dir() {
+        return Err(Error::InvalidParams);
+    }
+
+    let mut added = Vec::new();
+    for (name, data) in &params.uploads {
+        let mut components = Path::new(name).components();
+        let name = match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => name,
+            _ => return Err(Error::InvalidParams),
+        };
+        added.push(write_file(env, user, vol, &dir.join(name), data).await?);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "added": added })))
+}
+
+/// Copies a file or a directory with everything in it from `from` in `from_vol` to `to` in
+/// `to_vol`, both relative to their volume roots. Files referring to stored blobs are
+/// copied by adding another reference.
+pub(crate) async fn copy_tree(
+    env: &Environment,
+    conn: &PgConnection,
+    (from_vol, from): (&Volume, &Path),
+    (to_vol, to): (&Volume, &Path),
+) -> Result<(), Error> {
+    let from = file::File::check_path(from_vol, from)?;
+    let to = file::File::check_path(to_vol, to)?;
+    if to.starts_with(&from) {
+        return Err(Error::InvalidParams);
+    }
+    let (from_storage, to_storage) = (from_vol.storage(), to_vol.storage());
+    if let Some(remaining) = to_vol.quota_remaining().await? {
+        if from_storage.size(&from).await? > remaining {
+            return Err(Error::QuotaExceeded);
+        }
+    }
+
+    let mut pending = vec![(from, to.clone())];
+    while let Some((from, to)) = pending.pop() {
+        if from_storage.stat(&from).await?.is_dir {
+            to_storage.mkdir(&to, false).await?;
+            for (name, _) in from_storage.list(&from).await? {
+                if !file::HIDDEN_DIRS.contains(&name.as_str()) {
+                    pending.push((from.join(&name), to.join(&name)));
+                }
+            }
+        } else {
+            BlobStore::copy_file(env.blobs.as_ref(), conn, (from_vol, &from), (to_vol, &to)).await?;
+        }
+    }
+    env.changes.publish(to_vol, &to, Kind::Added);
+    Ok(())
+}
+
+/// Copies or, with `cut`, moves files into the `dst` folder
+pub async fn paste(params: &Params, env: &Environment, mounts: &[Volume]) -> Result<HttpResponse, Error> {
+    let dst = params.get("dst").ok_or(Error::InvalidParams)?;
+    let (dst_vol, dst_dir) = resolve(mounts, dst)?;
+    dst_vol.check_write()?;
+    if !file::File::info(dst_vol, &dst_dir).await?.is_dir() {
+        return Err(Error::InvalidParams);
+    }
+    let cut = flag(params, "cut");
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for target in targets(params) {
+        let (vol, path) = resolve(mounts, &target)?;
+        let name = path.file_name().ok_or(Error::InvalidParams)?;
+        let to = file::File::copy_name(dst_vol, dst_dir.join(name)).await?;
+
+        if cut {
+            vol.check_write()?;
+            let from = file::File::check_path(vol, &path)?;
+            let full_to = file::File::check_path(dst_vol, &to)?;
+            let renamed = vol.same_storage(dst_vol) && vol.storage().rename(&from, &full_to).await.is_ok();
+            if renamed {
+                Version::moved(&conn, vol.driver, &from, &full_to)?;
+                env.changes.publish(dst_vol, &full_to, Kind::Added);
+            } else {
+                copy_tree(env, &conn, (vol, &path), (dst_vol, &to)).await?;
+                vol.storage().remove(&from).await?;
+            }
+            env.changes.publish(vol, &from, Kind::Removed);
+            removed.push(target);
+        } else {
+            copy_tree(env, &conn, (vol, &path), (dst_vol, &to)).await?;
+        }
+        added.push(file::File::info(dst_vol, &to).await?);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "added": added,
+        "removed": removed,
+    })))
+}
+
+/// Copies files next to themselves as "name copy N"
+pub async fn duplicate(params: &Params, env: &Environment, mounts: &[Volume]) -> Result<HttpResponse, Error> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+
+    let mut added = Vec::new();
+    for target in targets(params) {
+        let (vol, path) = resolve(mounts, &target)?;
+        vol.check_write()?;
+        let to = file::File::copy_name(vol, &path).await?;
+        copy_tree(env, &conn, (vol, &path), (vol, &to)).await?;
+        added.push(file::File::info(vol, &to).await?);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "added": added })))
+}
+
+/// Logical size of the targets, and their physical size counting files which refer to the
+/// same content only once
+pub async fn size(params: &Params, mounts: &[Volume]) -> Result<HttpResponse, Error> {
+    let mut logical = 0;
+    let mut physical = 0;
+    let mut file_count = 0;
+    let mut dir_count = 0;
+    let mut seen = HashSet::new();
+    for target in targets(params) {
+        let (vol, path) = resolve(mounts, &target)?;
+        let storage = vol.storage();
+        let full = file::File::check_path(vol, &path)?;
+
+        let mut pending = vec![(full.clone(), storage.stat(&full).await?)];
+        while let Some((path, stat)) = pending.pop() {
+            if stat.is_dir {
+                if path != full {
+                    dir_count += 1;
+                }
+                for (name, stat) in storage.list(&path).await? {
+                    if !file::HIDDEN_DIRS.contains(&name.as_str()) {
+                        pending.push((path.join(name), stat));
+                    }
+                }
+            } else {
+                file_count += 1;
+                logical += stat.len;
+                // storage without inodes reports 0 for every file
+                if stat.ino == 0 || seen.insert((stat.dev, stat.ino)) {
+                    physical += stat.len;
+                }
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "size": logical,
+        "physicalSize": physical,
+        "fileCnt": file_count,
+        "dirCnt": dir_count,
+    })))
+}
+
+/// Content of a file, inline unless `download=1`. Files on storage which can serve them
+/// directly, like S3, are redirected to instead of being passed through.
+pub async fn file(params: &Params, mounts: &[Volume]) -> Result<HttpResponse, Error> {
+    let target = params.get("target").ok_or(Error::InvalidParams)?;
+    let (vol, path) = resolve(mounts, target)?;
+    let full = file::File::check_path(vol, &path)?;
+    let storage = vol.storage();
+    if storage.stat(&full).await?.is_dir {
+        return Err(Error::InvalidParams);
+    }
+
+    let download = flag(params, "download");
+    if let Some(url) = storage.download_url(&full, download) {
+        return Ok(HttpResponse::Found().header(header::LOCATION, url).finish());
+    }
+
+    let name = full
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let disposition = if download {
+        DispositionType::Attachment
     } else {
-        let target = params.target.as_ref().ok_or(Error::InvalidParams)?;
-        let vol = Volume::create_or_find(env, user).await?;
-        let files = vol.ls_path(target).await?;
-        let cwd = vol.root().await?;
-        Ok(HttpResponse::Ok().json(Response {
-            api: 2.1, 
-            cwd,
-            files,
-        }))
+        DispositionType::Inline
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .set(ContentDisposition {
+            disposition,
+            parameters: vec![DispositionParam::Filename(name)],
+        })
+        .streaming(storage.read(&full).await?))
+}
+
+/// Mounts an SFTP server for the rest of the session, authenticating with a password or a
+/// private key unlocked by it. With `protocol=netunmount` the mount whose netkey is `host`
+/// is removed instead.
+pub async fn netmount(
+    params: &Params,
+    env: &Environment,
+    session: &Session,
+    user: &User,
+    mounts: &[Volume],
+) -> Result<HttpResponse, Error> {
+    let non_empty = |key: &str| params.get(key).filter(|value| !value.is_empty());
+    let host = non_empty("host").ok_or(Error::InvalidParams)?;
+    let mut ids = session_netmounts(session);
+
+    match params.get("protocol") {
+        Some("netunmount") => {
+            let id = host.parse::<uuid::Uuid>().map_err(|_| Error::InvalidParams)?;
+            let vol = mounts
+                .iter()
+                .find(|vol| vol.netmount == Some(id))
+                .ok_or(Error::PathError)?;
+            {
+                let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+                Netmount::find(&conn, user.id, id)?.delete(&conn)?;
+            }
+            ids.retain(|mounted| *mounted != id);
+            set_session_netmounts(session, ids)?;
+            Ok(HttpResponse::Ok().json(serde_json::json!({ "removed": [file::File::hash(vol, "/")] })))
+        }
+        Some("sftp") => {
+            let port = match non_empty("port") {
+                Some(port) => port.parse().map_err(|_| Error::InvalidParams)?,
+                None => 22,
+            };
+            let password = non_empty("pass").map(str::to_owned);
+            let auth = match non_empty("key") {
+                Some(private_key) => Auth::Key {
+                    private_key: private_key.to_owned(),
+                    passphrase: password,
+                },
+                None => Auth::Password(password.ok_or(Error::InvalidParams)?),
+            };
+            let config = Config {
+                host: host.to_owned(),
+                port,
+                user: non_empty("user").ok_or(Error::InvalidParams)?.to_owned(),
+                auth,
+                host_key: None,
+            };
+            let root = non_empty("path").map(PathBuf::from);
+
+            let mount = {
+                let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+                Netmount::create(&conn, &env.secret_key, user.id, config, root).await?
+            };
+            ids.push(mount.id);
+            set_session_netmounts(session, ids)?;
+            let vol = mount.volume(&env.secret_key)?;
+            // the fingerprint the server has to present from now on, for the user to verify
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "added": [vol.root().await?],
+                "host_key": mount.host_key,
+            })))
+        }
+        _ => Err(Error::InvalidParams),
     }
 }
@@ -0,0 +1,117 @@
+use crate::api::finder;
+use crate::api::user::current_user;
+use crate::env::Environment;
+use crate::error::{Error, Result};
+use crate::file::File;
+use crate::grant::Grant;
+use crate::privsep;
+use crate::user::error::Error as UserError;
+use crate::user::User;
+use crate::volume::Access;
+use actix_session::Session;
+use actix_web::{guard, web, HttpResponse, Responder, Scope};
+use serde_derive::Deserialize;
+
+#[derive(Deserialize)]
+struct GrantFormData {
+    target: String,
+    email: String,
+    access: Access,
+}
+
+fn grant_json(grant: &Grant, email: &str) -> serde_json::Value {
+    let mut value = serde_json::to_value(grant).unwrap_or_default();
+    value["access"] = grant.access().as_str().into();
+    value["email"] = email.into();
+    value
+}
+
+/// Grants the user gave, along with the recipients' emails
+async fn list_given(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let grants = Grant::given(&conn, user.id)?
+        .iter()
+        .filter_map(|grant| {
+            let recipient = User::find_by_id(&conn, grant.recipient_id).ok()?;
+            Some(grant_json(grant, &recipient.email))
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(grants))
+}
+
+/// Grants the user received, along with the owners' emails
+async fn list_received(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let grants = Grant::received(&conn, user.id)?
+        .iter()
+        .filter_map(|grant| {
+            let owner = User::find_by_id(&conn, grant.owner_id).ok()?;
+            Some(grant_json(grant, &owner.email))
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(grants))
+}
+
+async fn create_grant(
+    form: web::Form<GrantFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let (user, recipient) = {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        let user = current_user(&session, &conn)?;
+        let recipient = User::find(&conn, &form.email)?;
+        (user, recipient)
+    };
+
+    // folders granted by other users cannot be granted on
+    let mounts = finder::owned_mounts(&env, &user).await?;
+    let (vol, path) = finder::resolve(&mounts, &form.target)?;
+    if vol.run_as.is_some() || !vol.driver.is_local() {
+        return Err(Error::Forbidden);
+    }
+    // nobody can pass on more access than they have
+    if form.access > vol.access {
+        return Err(Error::Forbidden);
+    }
+    let path = tokio::fs::canonicalize(File::check_path(vol, &path)?).await?;
+    let stat = privsep::stat(None, &path).await?;
+
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let grant = Grant::create(&conn, user.id, recipient.id, &path, &stat, form.access)?;
+    Ok(HttpResponse::Ok().json(grant_json(&grant, &recipient.email)))
+}
+
+async fn revoke_grant(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    Grant::revoke(&conn, user.id, id.0)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn service() -> Scope {
+    web::scope("/grants")
+        .service(
+            web::resource("")
+                .route(web::get().to(list_given))
+                .route(
+                    web::post()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(create_grant),
+                ),
+        )
+        .route("/received", web::get().to(list_received))
+        .route("/{id}", web::delete().to(revoke_grant))
+}
@@ -0,0 +1,144 @@
+use crate::api::user::current_user;
+use crate::env::Environment;
+use crate::error::{Error, Result};
+use crate::group::Group;
+use crate::user::error::Error as UserError;
+use crate::user::User;
+use crate::volume::Access;
+use actix_session::Session;
+use actix_web::{guard, web, HttpResponse, Responder, Scope};
+use serde_derive::Deserialize;
+
+#[derive(Deserialize)]
+struct GroupFormData {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct MemberFormData {
+    email: String,
+    role: Access,
+}
+
+async fn list_groups(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let groups = Group::for_user(&conn, user.id)?
+        .into_iter()
+        .map(|(group, member)| {
+            serde_json::json!({
+                "id": group.id,
+                "name": group.name,
+                "created_at": group.created_at,
+                "role": member.role,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(groups))
+}
+
+async fn create_group(
+    form: web::Form<GroupFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let group = Group::create(&conn, &env.finder_root, &form.name, &user).await?;
+
+    Ok(HttpResponse::Ok().json(group))
+}
+
+async fn delete_group(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+    Group::check_access(&conn, id.0, user.id, Access::Admin)?;
+
+    Group::delete(&conn, &env.finder_root, id.0).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn list_members(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+    Group::check_access(&conn, id.0, user.id, Access::Read)?;
+
+    Ok(HttpResponse::Ok().json(Group::members(&conn, id.0)?))
+}
+
+async fn set_member(
+    id: web::Path<(uuid::Uuid,)>,
+    form: web::Form<MemberFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+    Group::check_access(&conn, id.0, user.id, Access::Admin)?;
+
+    let member = User::find(&conn, &form.email)?;
+    let member = Group::set_member(&conn, id.0, member.id, form.role)?;
+
+    Ok(HttpResponse::Ok().json(member))
+}
+
+/// Admins can remove anyone, other members only themselves
+async fn remove_member(
+    path: web::Path<(uuid::Uuid, uuid::Uuid)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let (group_id, member_id) = path.into_inner();
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+    if user.id != member_id {
+        Group::check_access(&conn, group_id, user.id, Access::Admin)?;
+    }
+
+    Group::membership(&conn, group_id, member_id)?.ok_or(Error::UserError(UserError::NotFound))?;
+    Group::remove_member(&conn, group_id, member_id)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn service() -> Scope {
+    web::scope("/groups")
+        .service(
+            web::resource("")
+                .route(web::get().to(list_groups))
+                .route(
+                    web::post()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(create_group),
+                ),
+        )
+        .route("/{id}", web::delete().to(delete_group))
+        .service(
+            web::resource("/{id}/members")
+                .route(web::get().to(list_members))
+                .route(
+                    web::put()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(set_member),
+                ),
+        )
+        .route("/{id}/members/{user_id}", web::delete().to(remove_member))
+}
@@ -1,9 +1,24 @@
+pub mod admin;
+pub mod dav;
+pub mod events;
 pub mod finder;
+pub mod grant;
+pub mod group;
+pub mod oidc;
+pub mod share;
 pub mod user;
+pub mod version;
 use actix_web::{web, Scope};
 
 pub fn service() -> Scope {
   web::scope("/api")
     .service(user::service())
+    .service(admin::service())
+    .service(group::service())
+    .service(share::service())
+    .service(grant::service())
+    .service(version::service())
+    .service(oidc::service())
     .service(finder::service())
+    .service(events::service())
 }
@@ -0,0 +1,333 @@
+use crate::api::finder::{self, ops};
+use crate::api::user::current_user;
+use crate::env::Environment;
+use crate::error::{Error, Result};
+use crate::file::File;
+use crate::privsep;
+use crate::share::{Mode, Share};
+use crate::user::error::Error as UserError;
+use crate::user::User;
+use crate::volume::{Access, Volume};
+use actix_files::NamedFile;
+use actix_session::Session;
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::Method;
+use actix_web::{guard, web, HttpRequest, HttpResponse, Responder, Scope};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+/// Session key holding the ids of password protected shares the visitor unlocked
+const UNLOCKED_SHARES: &str = "shares";
+
+#[derive(Deserialize)]
+struct ShareFormData {
+    target: String,
+    mode: Mode,
+    password: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_downloads: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct UnlockFormData {
+    password: String,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    dir: bool,
+    size: u64,
+    ts: u64,
+}
+
+fn share_json(env: &Environment, share: &Share) -> serde_json::Value {
+    let mut value = serde_json::to_value(share).unwrap_or_default();
+    value["url"] = format!("{}/s/{}", env.mailer.base_url, share.token).into();
+    value["mode"] = share.mode().as_str().into();
+    value["has_password"] = share.has_password().into();
+    value
+}
+
+async fn list_shares(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let shares = Share::list(&conn, user.id)?
+        .iter()
+        .map(|share| share_json(&env, share))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(shares))
+}
+
+async fn create_share(
+    form: web::Form<ShareFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let user = {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        current_user(&session, &conn)?
+    };
+
+    // folders granted by other users cannot be shared on
+    let mounts = finder::owned_mounts(&env, &user).await?;
+    let (vol, path) = finder::resolve(&mounts, &form.target)?;
+    // visitors are served by the server process itself
+    if vol.run_as.is_some() || !vol.driver.is_local() {
+        return Err(Error::Forbidden);
+    }
+    if form.mode == Mode::Upload {
+        vol.check_write()?;
+    }
+    let path = tokio::fs::canonicalize(File::check_path(vol, &path)?).await?;
+    let stat = privsep::stat(None, &path).await?;
+
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let share = Share::create(
+        &conn,
+        user.id,
+        &path,
+        &stat,
+        form.mode,
+        form.password.as_deref(),
+        form.expires_at,
+        form.max_downloads,
+    )?;
+    Ok(HttpResponse::Ok().json(share_json(&env, &share)))
+}
+
+async fn revoke_share(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    Share::revoke(&conn, user.id, id.0)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// The share and a volume rooted at its target. Shares whose owner is gone, who lost
+/// access to the target, or whose target was moved or deleted are gone as well.
+async fn open_share(env: &Environment, session: &Session, token: &str) -> Result<(Share, Volume)> {
+    let (share, owner) = {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        let share = Share::find_by_token(&conn, token)?;
+        let owner = User::find_by_id(&conn, share.user_id).map_err(|_| Error::Gone)?;
+        owner.ensure_active().map_err(|_| Error::Gone)?;
+        (share, owner)
+    };
+
+    if share.has_password() {
+        let unlocked = session
+            .get::<Vec<uuid::Uuid>>(UNLOCKED_SHARES)
+            .map_err(|_| UserError::SessionError)?
+            .unwrap_or_default();
+        if !unlocked.contains(&share.id) {
+            return Err(UserError::NotAuthenticated.into());
+        }
+    }
+
+    let path = PathBuf::from(&share.path);
+    let mounts = finder::owned_mounts(env, &owner).await?;
+    let vol = finder::containing(&mounts, &path).ok_or(Error::Gone)?;
+    let stat = privsep::stat(None, &path).await.map_err(|_| Error::Gone)?;
+    if !share.is_target(&stat) {
+        return Err(Error::Gone);
+    }
+
+    let access = if share.mode() == Mode::Upload && vol.access.can_write() {
+        Access::Write
+    } else {
+        Access::Read
+    };
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok((
+        share,
+        Volume {
+            quota: vol.quota.clone(),
+            ..Volume::new(path, "", &name, access)
+        },
+    ))
+}
+
+/// Serves a file of a read-only share or lists one of its folders
+async fn serve(
+    req: &HttpRequest,
+    env: &Environment,
+    share: &Share,
+    vol: &Volume,
+    path: &Path,
+) -> Result<HttpResponse> {
+    if share.mode() != Mode::Read {
+        return Err(Error::Forbidden);
+    }
+    let full = File::check_path(vol, path)?;
+    let stat = privsep::stat(None, &full).await?;
+
+    if !stat.is_dir {
+        // only complete downloads count, resuming or seeking within a file is free as long
+        // as the limit is not reached
+        if req.method() == Method::GET && !req.headers().contains_key(header::RANGE) {
+            let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+            share.count_download(&conn)?;
+        } else if !share.can_download() {
+            return Err(Error::Gone);
+        }
+        let name = full.file_name().unwrap_or_default().to_string_lossy().to_string();
+        return NamedFile::open(&full)?
+            .set_content_disposition(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(name)],
+            })
+            .into_response(req)
+            .map_err(|e| Error::Other(e.to_string()));
+    }
+
+    let entries = privsep::read_dir(None, &full)
+        .await?
+        .into_iter()
+        .map(|(name, stat)| Entry {
+            name,
+            dir: stat.is_dir,
+            size: stat.len,
+            ts: stat.modified,
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "name": vol.name,
+        "mode": share.mode().as_str(),
+        "entries": entries,
+    })))
+}
+
+async fn visit_share(
+    req: HttpRequest,
+    token: web::Path<(String,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let (share, vol) = open_share(&env, &session, &token.0).await?;
+    if share.mode() == Mode::Upload {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "name": vol.name,
+            "mode": share.mode().as_str(),
+        })));
+    }
+    serve(&req, &env, &share, &vol, Path::new("/")).await
+}
+
+async fn visit_share_path(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let (share, vol) = open_share(&env, &session, &path.0).await?;
+    serve(&req, &env, &share, &vol, Path::new(&path.1)).await
+}
+
+async fn unlock_share(
+    token: web::Path<(String,)>,
+    form: web::Form<UnlockFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    let share = Share::find_by_token(&conn, &token.0)?;
+    share.check_password(&conn, &form.password)?;
+
+    let mut unlocked = session
+        .get::<Vec<uuid::Uuid>>(UNLOCKED_SHARES)
+        .map_err(|_| UserError::SessionError)?
+        .unwrap_or_default();
+    if !unlocked.contains(&share.id) {
+        unlocked.push(share.id);
+    }
+    session
+        .set(UNLOCKED_SHARES, unlocked)
+        .map_err(|_| UserError::SessionError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Adds a file to a file drop, never overwriting existing files
+async fn upload_to_share(
+    path: web::Path<(String, String)>,
+    payload: web::Payload,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let (token, name) = path.into_inner();
+    let (share, vol) = open_share(&env, &session, &token).await?;
+    if share.mode() != Mode::Upload {
+        return Err(Error::Forbidden);
+    }
+    vol.check_write()?;
+
+    let mut components = Path::new(&name).components();
+    let name = match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => name.to_owned(),
+        _ => return Err(Error::InvalidParams),
+    };
+    let full = File::check_path(&vol, &name)?;
+    if vol.storage().stat(&full).await.is_ok() {
+        return Err(Error::InvalidParams);
+    }
+    let owner = {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        User::find_by_id(&conn, share.user_id)?
+    };
+
+    // the quota is checked as the upload goes since its size is not known up front. A
+    // file added under the same name in the meantime is kept as a version.
+    let data = payload.map(|chunk| chunk.map_err(|e| Error::Other(e.to_string())));
+    ops::write_stream(&env, &owner, &vol, Path::new(&name), data.boxed_local()).await?;
+
+    Ok(HttpResponse::Created().finish())
+}
+
+pub fn service() -> Scope {
+    web::scope("/shares")
+        .service(
+            web::resource("")
+                .route(web::get().to(list_shares))
+                .route(
+                    web::post()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(create_share),
+                ),
+        )
+        .route("/{id}", web::delete().to(revoke_share))
+}
+
+/// Unauthenticated routes for visitors of share links
+pub fn public() -> Scope {
+    web::scope("/s")
+        .service(
+            web::resource("/{token}")
+                .route(web::get().to(visit_share))
+                .route(
+                    web::post()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(unlock_share),
+                ),
+        )
+        .service(
+            web::resource("/{token}/{path:.*}")
+                .route(web::get().to(visit_share_path))
+                .route(web::put().to(upload_to_share)),
+        )
+}
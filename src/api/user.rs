@@ -1,9 +1,21 @@
+use crate::api::finder;
 use crate::env::Environment;
+use crate::netmount::Netmount;
 use crate::user::error::{Error, Result};
-use crate::user::User;
+use crate::user::token::{self, ApiToken};
+use crate::user::totp::Totp;
+use crate::user::{normalize_email, User};
+use crate::models::ClientUser;
+use crate::user::deletion;
+use crate::user::email::EmailChange;
+use crate::user::reset::PasswordReset;
+use crate::user::export::ExportJob;
+use actix_files::NamedFile;
 use actix_session::Session;
+use diesel::pg::PgConnection;
+use diesel::Connection;
 use actix_web::{guard, web, HttpResponse, Responder, Scope};
-use publicsuffix::List;
+use crate::suffix::EmailValidator;
 use serde_derive::Deserialize;
 
 #[derive(Deserialize)]
@@ -12,24 +24,75 @@ struct UserFormData {
     password: String,
 }
 
+#[derive(Deserialize)]
+struct CodeFormData {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordFormData {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct TokenFormData {
+    name: String,
+    scope: token::Scope,
+    volume_path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordFormData {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordFormData {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyEmailParams {
+    token: String,
+}
+
+/// Loads the session's user from the database, rejecting sessions that were
+/// issued before the user's sessions were invalidated
+pub(crate) fn current_user(session: &Session, conn: &PgConnection) -> Result<User> {
+    let session_user = session
+        .get::<User>("user")
+        .map_err(|_| Error::SessionError)?
+        .ok_or(Error::NotAuthenticated)?;
+
+    let user = User::find_by_id(conn, session_user.id)?;
+    if user.session_version != session_user.session_version {
+        session.purge();
+        return Err(Error::NotAuthenticated);
+    }
+    user.ensure_active()?;
+    Ok(user)
+}
+
 async fn create_user(
     form: web::Form<UserFormData>,
-    list: web::Data<publicsuffix::List>,
+    validator: web::Data<EmailValidator>,
     env: web::Data<Environment>,
     session: Session,
 ) -> Result<impl Responder> {
     // validate email
-    list.parse_email(&form.email)
-        .map_err(|_| Error::InvalidEmail)?;
+    if !validator.is_valid(&form.email) {
+        return Err(Error::InvalidEmail);
+    }
 
-    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    // validate password
+    env.password_policy.check(&form.email, &form.password)?;
 
-    // check if user exists
-    User::find(&conn, &form.email)
-        .err()
-        .ok_or(Error::AlreadyExists)?;
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
 
-    let user = User::create(&conn, &form.email, &form.password, None)?;
+    // a duplicate email is rejected by the unique index
+    let user = User::create(&conn, &env.hasher, &form.email, &form.password, None)?;
 
     session
         .set("user", &user)
@@ -46,11 +109,19 @@ async fn login(
     // make db connection
     let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
 
-    // find the user from the id
-    let user = User::find(&conn, &form.email)?;
+    // check the credentials with the configured provider
+    let email = normalize_email(&form.email);
+    let user = env.auth.authenticate(&conn, &email, &form.password)?;
+    user.ensure_active()?;
 
-    // verify password
-    bcrypt::verify(&form.password, &user.pass_hash).map_err(|_| Error::NotAuthenticated)?;
+    // defer the session until the second factor is checked
+    if Totp::find_enabled(&conn, user.id)?.is_some() {
+        session.remove("user");
+        session
+            .set("pending_user", user.id)
+            .map_err(|_| Error::SessionError)?;
+        return Ok(HttpResponse::Ok().json(serde_json::json!({ "totp_required": true })));
+    }
 
     // set cookie
     session
@@ -60,7 +131,273 @@ async fn login(
     Ok(HttpResponse::Ok().json(user))
 }
 
-async fn logout(session: Session) -> impl Responder {
+async fn login_totp(
+    form: web::Form<CodeFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let user_id = session
+        .get::<uuid::Uuid>("pending_user")
+        .map_err(|_| Error::SessionError)?
+        .ok_or(Error::NotAuthenticated)?;
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+
+    let user = User::find_by_id(&conn, user_id)?;
+    user.ensure_active()?;
+    let mut totp = Totp::find_enabled(&conn, user.id)?.ok_or(Error::TotpNotEnabled)?;
+
+    // accept either a one time password or a recovery code
+    totp.check_login(&conn, &env.secret_key, &form.code)?;
+
+    session.remove("pending_user");
+    session
+        .set("user", &user)
+        .map_err(|_| Error::SessionError)?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+async fn totp_enroll(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let (_, secret) = Totp::enroll(&conn, &env.secret_key, user.id)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "uri": Totp::uri(&user.email, &secret),
+        "secret": secret,
+    })))
+}
+
+async fn totp_confirm(
+    form: web::Form<CodeFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let mut totp = Totp::find(&conn, user.id)?.ok_or(Error::TotpNotEnabled)?;
+    let recovery_codes = totp.confirm(&conn, &env.secret_key, &form.code)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "recovery_codes": recovery_codes })))
+}
+
+async fn totp_disable(
+    form: web::Form<PasswordFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let session_user = current_user(&session, &conn)?;
+
+    // require the password again
+    let user = env.auth.authenticate(&conn, &session_user.email, &form.password)?;
+    if user.id != session_user.id {
+        return Err(Error::NotAuthorized);
+    }
+
+    Totp::find_enabled(&conn, user.id)?.ok_or(Error::TotpNotEnabled)?;
+    Totp::disable(&conn, user.id)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn list_tokens(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    Ok(HttpResponse::Ok().json(ApiToken::list(&conn, user.id)?))
+}
+
+async fn create_token(
+    form: web::Form<TokenFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let (api_token, token) = ApiToken::create(
+        &conn,
+        user.id,
+        &form.name,
+        form.scope,
+        form.volume_path.as_deref(),
+    )?;
+
+    // the plaintext token is only ever returned here
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "token": token,
+        "info": api_token,
+    })))
+}
+
+async fn revoke_token(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    Ok(HttpResponse::Ok().json(ApiToken::revoke(&conn, user.id, id.0)?))
+}
+
+async fn change_password(
+    form: web::Form<ChangePasswordFormData>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    // directory passwords are changed in the directory
+    if !env.auth.local_passwords() {
+        return Err(Error::InvalidParams);
+    }
+
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let session_user = current_user(&session, &conn)?;
+
+    // require the current password
+    let mut user = env.auth.authenticate(&conn, &session_user.email, &form.current_password)?;
+    if user.id != session_user.id {
+        return Err(Error::NotAuthorized);
+    }
+    env.password_policy.check(&user.email, &form.new_password)?;
+
+    user.set_password(&conn, &env.hasher, &form.new_password)?;
+
+    // log out every other session, keep this one, and revoke the API tokens, which could
+    // have leaked along with the old password
+    user.bump_session_version(&conn)?;
+    ApiToken::revoke_all(&conn, user.id)?;
+    session
+        .set("user", &user)
+        .map_err(|_| Error::SessionError)?;
+
+    Ok(HttpResponse::Ok().json(ClientUser::from(&user)))
+}
+
+/// Sets a new password with the link of a reset by an administrator
+async fn reset_password(
+    form: web::Form<ResetPasswordFormData>,
+    env: web::Data<Environment>,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+
+    // a rejected password leaves the link usable
+    let user = conn.transaction::<_, Error, _>(|| {
+        let mut user = User::find_by_id(&conn, PasswordReset::redeem(&conn, &form.token)?)?;
+        user.ensure_active()?;
+        env.password_policy.check(&user.email, &form.new_password)?;
+
+        user.set_password(&conn, &env.hasher, &form.new_password)?;
+        user.bump_session_version(&conn)?;
+        ApiToken::revoke_all(&conn, user.id)?;
+        Ok(user)
+    })?;
+
+    Ok(HttpResponse::Ok().json(ClientUser::from(&user)))
+}
+
+async fn change_email(
+    form: web::Form<UserFormData>,
+    validator: web::Data<EmailValidator>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let session_user = current_user(&session, &conn)?;
+
+    // require the current password
+    let user = env.auth.authenticate(&conn, &session_user.email, &form.password)?;
+    if user.id != session_user.id {
+        return Err(Error::NotAuthorized);
+    }
+
+    // validate email
+    if !validator.is_valid(&form.email) {
+        return Err(Error::InvalidEmail);
+    }
+
+    // check if the address is taken
+    User::find(&conn, &form.email)
+        .err()
+        .ok_or(Error::AlreadyExists)?;
+
+    let token = EmailChange::create(&conn, user.id, &form.email)?;
+    env.mailer
+        .send(
+            &form.email,
+            "Confirm your new email address",
+            &format!(
+                "Open the following link to confirm your new email address:\r\n{}/api/user/email/verify?token={}",
+                env.mailer.base_url, token
+            ),
+        )
+        .map_err(|_| Error::MailError)?;
+
+    Ok(HttpResponse::Accepted().json(ClientUser::from(&user)))
+}
+
+async fn verify_email(
+    params: web::Query<VerifyEmailParams>,
+    env: web::Data<Environment>,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+
+    let user = EmailChange::confirm(&conn, &params.token)?;
+
+    Ok(HttpResponse::Ok().json(ClientUser::from(&user)))
+}
+
+async fn create_export(env: web::Data<Environment>, session: Session) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let job = ExportJob::create(&conn, user.id)?;
+    ExportJob::spawn(env.clone(), job.id, user.id);
+
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+async fn export_status(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    Ok(HttpResponse::Ok().json(ExportJob::find(&conn, user.id, id.0)?))
+}
+
+async fn export_download(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> crate::error::Result<NamedFile> {
+    let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
+    let user = current_user(&session, &conn)?;
+
+    let job = ExportJob::find(&conn, user.id, id.0).map_err(Error::from)?;
+    if !job.is_available() {
+        return Err(Error::NotFound.into());
+    }
+
+    Ok(NamedFile::open(ExportJob::archive_path(&env.finder_root, job.id))?)
+}
+
+/// Ends the session, forgetting the servers mounted during it
+async fn logout(env: web::Data<Environment>, session: Session) -> impl Responder {
+    if let Ok(conn) = env.db_pool.get() {
+        if let Ok(user) = current_user(&session, &conn) {
+            let ids = finder::session_netmounts(&session);
+            for mount in Netmount::find_all(&conn, user.id, &ids).unwrap_or_default() {
+                let _ = mount.delete(&conn);
+            }
+        }
+    }
     session.purge();
     HttpResponse::Ok()
 }
@@ -82,7 +419,7 @@ async fn delete_user(
     uid: web::Path<(uuid::Uuid,)>,
     env: web::Data<Environment>,
     session: Session,
-) -> Result<impl Responder> {
+) -> crate::error::Result<impl Responder> {
     // make connection
     let conn = env.db_pool.get().map_err(|_| Error::DbError)?;
 
@@ -90,26 +427,21 @@ async fn delete_user(
     let user = User::find_by_id(&conn, uid.0)?;
     
     // validate user id
-    if let Some(session_user) = session
-        .get::<User>("user")
-        .map_err(|_| Error::SessionError)?
-    {
-        if session_user.id != user.id {
-            return Err(Error::NotAuthorized);
-        }
-    } else {
-        return Err(Error::NotAuthenticated);
+    if current_user(&session, &conn)?.id != user.id {
+        return Err(Error::NotAuthorized.into());
     }
 
-    // delete the user
-    let user = user.delete(&conn)?; 
+    // delete the account along with its volume, sessions and tokens
+    let client_user = ClientUser::from(&user);
+    let actor_id = Some(user.id);
+    deletion::delete_account(&env.db_pool, &env.finder_root, env.deletion_grace, user, actor_id).await?;
+    session.purge();
 
-    Ok(HttpResponse::Ok().json(user))
+    Ok(HttpResponse::Ok().json(client_user))
 }
 
 pub fn service() -> Scope {
     web::scope("/user")
-        .data(List::fetch().expect("Could not fetch public suffix list"))
         .route(
             "/create",
             web::post()
@@ -128,7 +460,82 @@ pub fn service() -> Scope {
                 ))
                 .to(login),
         )
+        .route(
+            "/login/totp",
+            web::post()
+                .guard(guard::Header(
+                    "Content-Type",
+                    "application/x-www-form-urlencoded",
+                ))
+                .to(login_totp),
+        )
         .route("/logout", web::get().to(logout))
+        .route(
+            "/password",
+            web::post()
+                .guard(guard::Header(
+                    "Content-Type",
+                    "application/x-www-form-urlencoded",
+                ))
+                .to(change_password),
+        )
+        .route(
+            "/password/reset",
+            web::post()
+                .guard(guard::Header(
+                    "Content-Type",
+                    "application/x-www-form-urlencoded",
+                ))
+                .to(reset_password),
+        )
+        .route(
+            "/email",
+            web::post()
+                .guard(guard::Header(
+                    "Content-Type",
+                    "application/x-www-form-urlencoded",
+                ))
+                .to(change_email),
+        )
+        .route("/email/verify", web::get().to(verify_email))
+        .service(
+            web::scope("/totp")
+                .route("/enroll", web::post().to(totp_enroll))
+                .route(
+                    "/confirm",
+                    web::post()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(totp_confirm),
+                )
+                .route(
+                    "/disable",
+                    web::post()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(totp_disable),
+                ),
+        )
+        .service(
+            web::resource("/tokens")
+                .route(web::get().to(list_tokens))
+                .route(
+                    web::post()
+                        .guard(guard::Header(
+                            "Content-Type",
+                            "application/x-www-form-urlencoded",
+                        ))
+                        .to(create_token),
+                ),
+        )
+        .route("/tokens/{id}", web::delete().to(revoke_token))
+        .route("/export", web::post().to(create_export))
+        .route("/export/{id}", web::get().to(export_status))
+        .route("/export/{id}/download", web::get().to(export_download))
         .service(
             web::resource("/{id}")
                 .route(web::get().to(user_info))
@@ -0,0 +1,106 @@
+use crate::api::finder;
+use crate::api::user::current_user;
+use crate::env::Environment;
+use crate::error::{Error, Result};
+use crate::file::File;
+use crate::user::error::Error as UserError;
+use crate::user::User;
+use crate::version::Version;
+use crate::volume::Volume;
+use actix_session::Session;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse, Responder, Scope};
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+struct ListQuery {
+    target: String,
+}
+
+/// The innermost mount of the same driver holding the versioned file and the file's path
+/// relative to it
+fn locate<'a>(mounts: &'a [Volume], version: &Version) -> Result<(&'a Volume, PathBuf)> {
+    let path = Path::new(&version.path);
+    let vol = mounts
+        .iter()
+        .filter(|vol| vol.driver.as_str() == version.driver && path.starts_with(&vol.path))
+        .max_by_key(|vol| vol.path.components().count())
+        .ok_or(Error::Forbidden)?;
+    let relative = path.strip_prefix(&vol.path).map_err(|_| Error::PathError)?;
+    Ok((vol, Path::new("/").join(relative)))
+}
+
+/// The version and the caller, who has to be able to reach the versioned file
+async fn open_version(
+    env: &Environment,
+    session: &Session,
+    id: uuid::Uuid,
+) -> Result<(User, Version, Vec<Volume>)> {
+    let (user, version) = {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        (current_user(session, &conn)?, Version::find(&conn, id)?)
+    };
+    let mounts = finder::mounts(env, &user, None).await?;
+    locate(&mounts, &version)?;
+    Ok((user, version, mounts))
+}
+
+/// Versions of the file a finder hash points to, newest first
+async fn list_versions(
+    query: web::Query<ListQuery>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let user = {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        current_user(&session, &conn)?
+    };
+    let mounts = finder::mounts(&env, &user, None).await?;
+    let (vol, path) = finder::resolve(&mounts, &query.target)?;
+    let full = File::check_path(vol, &path)?;
+
+    let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+    Ok(HttpResponse::Ok().json(Version::list(&conn, vol.driver, &full)?))
+}
+
+async fn download_version(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let (_, version, mounts) = open_version(&env, &session, id.0).await?;
+    let (vol, _) = locate(&mounts, &version)?;
+    let data = version.read(&*vol.storage()).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .set(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(version.name())],
+        })
+        .body(data))
+}
+
+async fn restore_version(
+    id: web::Path<(uuid::Uuid,)>,
+    env: web::Data<Environment>,
+    session: Session,
+) -> Result<impl Responder> {
+    let (user, version, mounts) = open_version(&env, &session, id.0).await?;
+    let (vol, path) = locate(&mounts, &version)?;
+    vol.check_write()?;
+
+    {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        version.restore(&conn, env.blobs.as_ref(), vol, &path, user.id, env.versions).await?;
+    }
+    Ok(HttpResponse::Ok().json(File::info(vol, &path).await?))
+}
+
+pub fn service() -> Scope {
+    web::scope("/versions")
+        .route("", web::get().to(list_versions))
+        .route("/{id}/download", web::get().to(download_version))
+        .route("/{id}/restore", web::post().to(restore_version))
+}
@@ -0,0 +1,66 @@
+use crate::schema::audit_events;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde_derive::Serialize;
+
+/// Record of a security relevant action. Events outlive the users they refer to.
+#[derive(Queryable, Serialize)]
+pub struct AuditEvent {
+    pub(crate) id: i64,
+    pub(crate) user_id: Option<uuid::Uuid>,
+    pub(crate) actor_id: Option<uuid::Uuid>,
+    pub(crate) action: String,
+    pub(crate) detail: serde_json::Value,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_events"]
+struct NewAuditEvent<'a> {
+    user_id: Option<uuid::Uuid>,
+    actor_id: Option<uuid::Uuid>,
+    action: &'a str,
+    detail: serde_json::Value,
+}
+
+impl AuditEvent {
+    /// `user_id` is the account affected, `actor_id` who performed the action
+    pub fn record(
+        conn: &PgConnection,
+        user_id: Option<uuid::Uuid>,
+        actor_id: Option<uuid::Uuid>,
+        action: &str,
+        detail: serde_json::Value,
+    ) -> QueryResult<()> {
+        diesel::insert_into(audit_events::table)
+            .values(&NewAuditEvent {
+                user_id,
+                actor_id,
+                action,
+                detail,
+            })
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    /// Drops the details of the events about a purged account, which hold its email address
+    /// and paths, keeping only what happened when
+    pub fn scrub(conn: &PgConnection, user_id: uuid::Uuid) -> QueryResult<()> {
+        use crate::schema::audit_events::dsl;
+
+        diesel::update(dsl::audit_events.filter(dsl::user_id.eq(user_id)))
+            .set(dsl::detail.eq(serde_json::json!({})))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    pub fn for_user(conn: &PgConnection, user_id: uuid::Uuid) -> QueryResult<Vec<Self>> {
+        use crate::schema::audit_events::dsl;
+
+        dsl::audit_events
+            .filter(dsl::user_id.eq(user_id).or(dsl::actor_id.eq(user_id)))
+            .order(dsl::created_at.asc())
+            .load::<Self>(conn)
+    }
+}
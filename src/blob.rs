@@ -0,0 +1,403 @@
+//! Content addressed storage.
+//!
+//! With `CONTENT_STORE=on` the contents of files written through the finder are stored once
+//! per owner under `FINDER_ROOT/.blobs`, named by their SHA-256 hash, and every file in the
+//! owner's volume tree holding that content is a hard link to the blob. The `blobs` table
+//! knows each blob by its inode, so copying such a file only adds another link. Since files can also be moved,
+//! trashed or removed outside of arca, the link count of a blob is what counts: the garbage
+//! collector refreshes `refs` from it and removes blobs nothing refers to anymore.
+//!
+//! Blobs are never shared between owners, i.e. users, groups and volumes outside of
+//! `FINDER_ROOT`. Otherwise whether an upload was deduplicated, or the link count of a
+//! file, would tell a user that someone else stores the same content. Blobs of older
+//! versions have no owner and are only collected.
+//!
+//! Only local volumes accessed by the server itself take part; files of mapped Unix accounts
+//! and volumes on other filesystems or storage are written as plain files.
+
+use super::env::DbPool;
+use super::error::{Error, Result};
+use super::group::GROUP_DIR;
+use super::privsep::{self, Stat};
+use super::schema::blobs;
+use super::share::Share;
+use super::storage::{temp_path, Local, Storage};
+use super::user::error::Error as UserError;
+use super::volume::Volume;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use sha2::{Digest, Sha256};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Directory inside `FINDER_ROOT` holding the blobs
+pub const BLOB_DIR: &str = ".blobs";
+
+const GC_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Blobs stored or looked up more recently than this are not collected, they may be about
+/// to be linked
+const GC_GRACE_MINUTES: i64 = 60;
+
+#[derive(Queryable)]
+pub struct Blob {
+    hash: String,
+    size: i64,
+    device: i64,
+    inode: i64,
+    refs: i64,
+    created_at: DateTime<Utc>,
+    /// When `store` last handed out the blob
+    last_used: DateTime<Utc>,
+    owner: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "blobs"]
+struct NewBlob<'a> {
+    owner: &'a str,
+    hash: &'a str,
+    size: i64,
+    device: i64,
+    inode: i64,
+}
+
+#[derive(Clone)]
+pub struct BlobStore {
+    finder_root: PathBuf,
+    root: PathBuf,
+}
+
+impl BlobStore {
+    /// Reads `CONTENT_STORE`, content addressed storage is used when it is `on`
+    pub fn from_env(finder_root: &Path) -> Option<Self> {
+        match std::env::var("CONTENT_STORE").as_deref() {
+            Ok("on") => {
+                let root = finder_root.join(BLOB_DIR);
+                std::fs::create_dir_all(&root).expect("Could not create the blob store");
+                Some(Self {
+                    finder_root: finder_root.to_owned(),
+                    root,
+                })
+            }
+            Ok("off") | Err(_) => None,
+            Ok(_) => panic!("CONTENT_STORE must be on or off"),
+        }
+    }
+
+    /// Where the owner's blob with the given hash is kept, spread over directories by its
+    /// first byte
+    fn path(&self, owner: &str, hash: &str) -> PathBuf {
+        self.root.join(owner).join(&hash[..2]).join(hash)
+    }
+
+    /// Who the absolute `path` belongs to: the user or group whose directory under
+    /// `FINDER_ROOT` it is in, otherwise the volume. Hashed so it can name a directory.
+    fn owner(&self, vol: &Volume, path: &Path) -> String {
+        let root = match path.strip_prefix(&self.finder_root) {
+            Ok(rel) => {
+                let mut components = rel.components();
+                let mut root = self.finder_root.clone();
+                if let Some(first) = components.next() {
+                    root.push(first);
+                    if first.as_os_str() == GROUP_DIR {
+                        root.extend(components.next());
+                    }
+                }
+                root
+            }
+            Err(_) => vol.path.clone(),
+        };
+        hex::encode(Sha256::digest(root.as_os_str().as_bytes()))
+    }
+
+    /// Whether files of the volume can refer to blobs
+    pub fn serves(&self, vol: &Volume) -> bool {
+        vol.run_as.is_none() && vol.driver.is_local()
+    }
+
+    /// Stores `data` for `owner` unless they have a blob with the same content already
+    pub async fn store(&self, conn: &PgConnection, owner: &str, data: &[u8]) -> Result<Blob> {
+        use crate::schema::blobs::dsl;
+
+        let hash = hex::encode(Sha256::digest(data));
+        let path = self.path(owner, &hash);
+        if let Some(blob) = dsl::blobs.find((owner, &hash)).first::<Blob>(conn).optional()? {
+            // a blob file replaced behind our back, e.g. by restoring a backup, is recorded anew
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.dev() as i64 == blob.device && metadata.ino() as i64 == blob.inode => {
+                    // keeps the collector away until the caller linked to it
+                    return diesel::update(dsl::blobs.find((owner, &hash)))
+                        .set(dsl::last_used.eq(Utc::now()))
+                        .get_result::<Blob>(conn)
+                        .map_err(Into::into);
+                }
+                _ => {}
+            }
+        }
+
+        tokio::fs::create_dir_all(path.parent().unwrap_or(&self.root)).await?;
+        Local::new(None).write_all(&path, data).await?;
+        // blobs are shared by every file linking to them and must never change
+        tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).await?;
+        let metadata = tokio::fs::metadata(&path).await?;
+
+        diesel::insert_into(dsl::blobs)
+            .values(&NewBlob {
+                owner,
+                hash: &hash,
+                size: data.len() as i64,
+                device: metadata.dev() as i64,
+                inode: metadata.ino() as i64,
+            })
+            .on_conflict((dsl::owner, dsl::hash))
+            .do_update()
+            .set((
+                dsl::device.eq(metadata.dev() as i64),
+                dsl::inode.eq(metadata.ino() as i64),
+                dsl::last_used.eq(Utc::now()),
+            ))
+            .get_result::<Blob>(conn)
+            .map_err(Into::into)
+    }
+
+    /// Makes the file at the absolute `tmp`, whose content hashes to `hash`, the owner's blob
+    /// of that content unless they have one already, in which case the file is removed
+    async fn adopt(&self, conn: &PgConnection, owner: &str, hash: &str, tmp: &Path) -> Result<Blob> {
+        use crate::schema::blobs::dsl;
+
+        let path = self.path(owner, hash);
+        if let Some(blob) = dsl::blobs.find((owner, hash)).first::<Blob>(conn).optional()? {
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.dev() as i64 == blob.device && metadata.ino() as i64 == blob.inode => {
+                    tokio::fs::remove_file(tmp).await?;
+                    return diesel::update(dsl::blobs.find((owner, hash)))
+                        .set(dsl::last_used.eq(Utc::now()))
+                        .get_result::<Blob>(conn)
+                        .map_err(Into::into);
+                }
+                _ => {}
+            }
+        }
+
+        tokio::fs::create_dir_all(path.parent().unwrap_or(&self.root)).await?;
+        tokio::fs::rename(tmp, &path).await?;
+        tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).await?;
+        let metadata = tokio::fs::metadata(&path).await?;
+
+        diesel::insert_into(dsl::blobs)
+            .values(&NewBlob {
+                owner,
+                hash,
+                size: metadata.len() as i64,
+                device: metadata.dev() as i64,
+                inode: metadata.ino() as i64,
+            })
+            .on_conflict((dsl::owner, dsl::hash))
+            .do_update()
+            .set((
+                dsl::device.eq(metadata.dev() as i64),
+                dsl::inode.eq(metadata.ino() as i64),
+                dsl::last_used.eq(Utc::now()),
+            ))
+            .get_result::<Blob>(conn)
+            .map_err(Into::into)
+    }
+
+    /// The blob a file refers to, `None` for plain files
+    pub fn find_by_stat(conn: &PgConnection, stat: &Stat) -> Result<Option<Blob>> {
+        use crate::schema::blobs::dsl;
+
+        dsl::blobs
+            .filter(dsl::device.eq(stat.dev as i64))
+            .filter(dsl::inode.eq(stat.ino as i64))
+            .first::<Blob>(conn)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Makes the file at the absolute `path` refer to the blob, replacing what was there
+    pub async fn link(&self, conn: &PgConnection, blob: &Blob, path: &Path) -> Result<()> {
+        use crate::schema::blobs::dsl;
+
+        if path.file_name().is_none() {
+            return Err(Error::InvalidParams);
+        }
+        let tmp = temp_path(path);
+        tokio::fs::hard_link(self.path(&blob.owner, &blob.hash), &tmp).await?;
+        if let Err(e) = tokio::fs::rename(&tmp, path).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e.into());
+        }
+
+        diesel::update(dsl::blobs.find((&blob.owner, &blob.hash)))
+            .set(dsl::refs.eq(dsl::refs + 1))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Writes `data` to the absolute `path` inside `vol`. Without a store, or when the
+    /// volume does not take part in it, the file is written as is. Either way the file is
+    /// replaced by a new one, which shares of the old one move on to.
+    pub async fn write(
+        store: Option<&Self>,
+        conn: &PgConnection,
+        vol: &Volume,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<()> {
+        let storage = vol.storage();
+        let replaced = storage.stat(path).await.ok().filter(|stat| !stat.is_dir);
+
+        let mut linked = false;
+        if let Some(store) = store.filter(|store| store.serves(vol)) {
+            let blob = store.store(conn, &store.owner(vol, path), data).await?;
+            match store.link(conn, &blob, path).await {
+                // e.g. volumes on another filesystem, which cannot link to the store
+                Err(Error::IoError(e)) if e.raw_os_error() == Some(nix::libc::EXDEV) => {}
+                Err(e) => return Err(e),
+                Ok(()) => linked = true,
+            }
+        }
+        if !linked {
+            storage.write_all(path, data).await?;
+        }
+
+        if let Some(replaced) = replaced {
+            Share::follow_replacement(conn, path, &replaced, &storage.stat(path).await?)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the file at the absolute `tmp` in `vol`, whose content hashes to `hash`, to the
+    /// absolute `path`, like `write` does with content held in memory
+    pub async fn move_file(
+        store: Option<&Self>,
+        conn: &PgConnection,
+        vol: &Volume,
+        tmp: &Path,
+        path: &Path,
+        hash: &str,
+    ) -> Result<()> {
+        let storage = vol.storage();
+        let replaced = storage.stat(path).await.ok().filter(|stat| !stat.is_dir);
+
+        let mut linked = false;
+        if let Some(store) = store.filter(|store| store.serves(vol)) {
+            // volumes on another filesystem cannot link to the store
+            let device = tokio::fs::metadata(&store.root).await?.dev();
+            if tokio::fs::metadata(tmp).await?.dev() == device {
+                let blob = store.adopt(conn, &store.owner(vol, path), hash, tmp).await?;
+                store.link(conn, &blob, path).await?;
+                linked = true;
+            }
+        }
+        if !linked {
+            storage.rename(tmp, path).await?;
+        }
+
+        if let Some(replaced) = replaced {
+            Share::follow_replacement(conn, path, &replaced, &storage.stat(path).await?)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the file at the absolute `from` in `from_vol` to `to` in `to_vol`. Files
+    /// referring to a blob of the destination's owner are copied by linking to it, others
+    /// by their content.
+    pub async fn copy_file(
+        store: Option<&Self>,
+        conn: &PgConnection,
+        (from_vol, from): (&Volume, &Path),
+        (to_vol, to): (&Volume, &Path),
+    ) -> Result<()> {
+        if let Some(store) = store.filter(|store| store.serves(from_vol) && store.serves(to_vol)) {
+            let stat = privsep::stat(None, from).await?;
+            let owner = store.owner(to_vol, to);
+            if let Some(blob) = Self::find_by_stat(conn, &stat)?.filter(|blob| blob.owner == owner) {
+                match store.link(conn, &blob, to).await {
+                    Err(Error::IoError(e)) if e.raw_os_error() == Some(nix::libc::EXDEV) => {}
+                    linked => return linked,
+                }
+            }
+        }
+        let data = from_vol.storage().read_all(from).await?;
+        Self::write(store, conn, to_vol, to, &data).await
+    }
+
+    /// Refreshes the reference counts from the blobs' link counts and removes the blobs
+    /// nothing links to anymore. Returns the number of bytes freed.
+    pub async fn collect_garbage(&self, pool: &DbPool) -> Result<u64> {
+        use crate::schema::blobs::dsl;
+
+        let conn = pool.get().map_err(|_| UserError::DbError)?;
+        let grace = Utc::now() - Duration::minutes(GC_GRACE_MINUTES);
+        let mut freed = 0;
+        for blob in dsl::blobs.load::<Blob>(&conn)? {
+            let path = self.path(&blob.owner, &blob.hash);
+            let refs = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata.nlink() as i64 - 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
+
+            if refs > 0 || blob.last_used > grace {
+                if refs != blob.refs {
+                    diesel::update(dsl::blobs.find((&blob.owner, &blob.hash)))
+                        .set(dsl::refs.eq(refs))
+                        .execute(&conn)?;
+                }
+                continue;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            diesel::delete(dsl::blobs.find((&blob.owner, &blob.hash))).execute(&conn)?;
+            freed += blob.size as u64;
+        }
+        Ok(freed)
+    }
+
+    pub fn spawn_gc(self, pool: DbPool) {
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(GC_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.collect_garbage(&pool).await {
+                    log::error!("Could not collect unused blobs: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::volume::Access;
+
+    #[test]
+    fn owner_is_user_group_or_volume() {
+        let store = BlobStore {
+            finder_root: PathBuf::from("/srv/arca"),
+            root: PathBuf::from("/srv/arca").join(BLOB_DIR),
+        };
+        let home = Volume::new("/srv/arca/alice", "home", "Home", Access::Admin);
+        let owner = store.owner(&home, Path::new("/srv/arca/alice/a.txt"));
+        assert_eq!(owner, store.owner(&home, Path::new("/srv/arca/alice/docs/b.txt")));
+        assert_ne!(owner, store.owner(&home, Path::new("/srv/arca/bob/a.txt")));
+
+        let group = store.owner(&home, Path::new("/srv/arca/groups/team/a.txt"));
+        assert_eq!(group, store.owner(&home, Path::new("/srv/arca/groups/team/docs/b.txt")));
+        assert_ne!(group, store.owner(&home, Path::new("/srv/arca/groups/other/a.txt")));
+
+        let mounted = Volume::new("/mnt/projects", "projects", "Projects", Access::Admin);
+        let owner = store.owner(&mounted, Path::new("/mnt/projects/a/b.txt"));
+        assert_eq!(owner, store.owner(&mounted, Path::new("/mnt/projects/c.txt")));
+        let other = Volume::new("/mnt/media", "media", "Media", Access::Admin);
+        assert_ne!(owner, store.owner(&other, Path::new("/mnt/media/c.txt")));
+    }
+}
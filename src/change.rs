@@ -0,0 +1,191 @@
+//! Changes to files, pushed to finder sessions as they happen.
+//!
+//! Finder commands publish what they add, remove or change. Unless `WATCH_VOLUMES=off`,
+//! an inotify watcher on `FINDER_ROOT` and the volume base directories publishes changes
+//! made outside of arca as well, and commands leave changes in those directories to it.
+//! Changes are known by absolute path and storage, each session picks the ones inside its
+//! volumes.
+
+use super::blob::BLOB_DIR;
+use super::file::HIDDEN_DIRS;
+use super::storage::Driver;
+use super::volume::{Volume, ARCHIVE_DIR};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Changes kept for sessions which fall behind, older ones are dropped
+const BACKLOG: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Added => "added",
+            Kind::Removed => "removed",
+            Kind::Changed => "changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub(crate) kind: Kind,
+    pub(crate) driver: Driver,
+    pub(crate) netmount: Option<uuid::Uuid>,
+    /// Absolute path of the file or directory
+    pub(crate) path: PathBuf,
+}
+
+impl Change {
+    /// Whether the change happened inside the volume
+    pub fn concerns(&self, vol: &Volume) -> bool {
+        vol.driver == self.driver && vol.netmount == self.netmount && self.path.starts_with(&vol.path)
+    }
+}
+
+#[derive(Clone)]
+pub struct Changes {
+    sender: broadcast::Sender<Change>,
+    /// Directories the watcher takes care of
+    watched: Arc<Vec<PathBuf>>,
+}
+
+/// Directories below the roots which never hold volume contents
+fn skipped(name: &str) -> bool {
+    HIDDEN_DIRS.contains(&name) || name == BLOB_DIR || name == ARCHIVE_DIR
+}
+
+/// Watches `dir` and every directory below it
+fn add_tree(inotify: &Inotify, dirs: &mut HashMap<WatchDescriptor, PathBuf>, dir: &Path) {
+    let flags = AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_ONLYDIR;
+    let mut pending = vec![dir.to_owned()];
+    while let Some(dir) = pending.pop() {
+        match inotify.add_watch(&dir, flags) {
+            Ok(wd) => {
+                dirs.insert(wd, dir.clone());
+            }
+            Err(e) => {
+                log::warn!("Could not watch {}: {}", dir.display(), e);
+                continue;
+            }
+        }
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let is_dir = entry.file_type().map_or(false, |file_type| file_type.is_dir());
+            if is_dir && !skipped(&entry.file_name().to_string_lossy()) {
+                pending.push(entry.path());
+            }
+        }
+    }
+}
+
+/// Publishes the changes below `roots` until inotify fails
+fn watch(roots: &[PathBuf], sender: &broadcast::Sender<Change>) -> nix::Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    let mut dirs = HashMap::new();
+    for root in roots {
+        add_tree(&inotify, &mut dirs, root);
+    }
+
+    loop {
+        for event in inotify.read_events()? {
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                dirs.remove(&event.wd);
+                continue;
+            }
+            let (dir, name) = match (dirs.get(&event.wd), &event.name) {
+                (Some(dir), Some(name)) => (dir.clone(), name.to_string_lossy().to_string()),
+                _ => continue,
+            };
+            // temporary files of atomic writes show up as the file they replace
+            if skipped(&name) || (name.starts_with('.') && name.ends_with(".tmp")) {
+                continue;
+            }
+
+            let path = dir.join(&name);
+            let kind = if event.mask.intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO) {
+                if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                    add_tree(&inotify, &mut dirs, &path);
+                }
+                Kind::Added
+            } else if event.mask.intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM) {
+                Kind::Removed
+            } else {
+                Kind::Changed
+            };
+            let _ = sender.send(Change {
+                kind,
+                driver: Driver::Local,
+                netmount: None,
+                path,
+            });
+        }
+    }
+}
+
+impl Changes {
+    /// Reads `WATCH_VOLUMES` and, unless it is `off`, starts watching `roots`, the
+    /// directories local volumes live in
+    pub fn from_env(roots: &[PathBuf]) -> Self {
+        let (sender, _) = broadcast::channel(BACKLOG);
+        let watched = match std::env::var("WATCH_VOLUMES").as_deref() {
+            Ok("on") | Err(_) => {
+                // nested roots are watched along with the root they are in
+                let mut watched = roots.to_vec();
+                watched.sort();
+                watched.dedup_by(|inner, outer| inner.starts_with(outer));
+                watched
+            }
+            Ok("off") => Vec::new(),
+            Ok(_) => panic!("WATCH_VOLUMES must be on or off"),
+        };
+
+        if !watched.is_empty() {
+            let (roots, sender) = (watched.clone(), sender.clone());
+            std::thread::spawn(move || {
+                if let Err(e) = watch(&roots, &sender) {
+                    log::error!("Stopped watching volumes: {}", e);
+                }
+            });
+        }
+        Self {
+            sender,
+            watched: Arc::new(watched),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes a change made through arca to the absolute `path` in `vol`, unless the
+    /// watcher sees it anyway
+    pub fn publish(&self, vol: &Volume, path: &Path, kind: Kind) {
+        let watched = vol.driver.is_local() && self.watched.iter().any(|root| path.starts_with(root));
+        if watched {
+            return;
+        }
+        // nobody listening is fine
+        let _ = self.sender.send(Change {
+            kind,
+            driver: vol.driver,
+            netmount: vol.netmount,
+            path: path.to_owned(),
+        });
+    }
+}
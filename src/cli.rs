@@ -0,0 +1,238 @@
+use crate::api::finder;
+use crate::blob::BlobStore;
+use crate::env::DbPool;
+use crate::group::GROUP_DIR;
+use crate::privsep;
+use crate::user::auth::ADMIN_ROLE;
+use crate::user::deletion;
+use crate::user::password::{PasswordHasher, PasswordPolicy};
+use crate::user::User;
+use crate::volume::Volume;
+use diesel::prelude::*;
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::MetadataExt;
+
+embed_migrations!();
+
+const USAGE: &str = "Usage: arca [command]
+
+Without a command the web server is started.
+
+Commands:
+    users list                      List all accounts
+    users create <email> [--admin]  Create an account
+    users delete <email>            Delete an account and its volume
+    users reset-password <email>    Set a new password and end all sessions
+    create-admin <email>            Same as users create <email> --admin
+    migrate                         Run pending database migrations
+    check-volumes                   Verify every volume directory exists and has the expected owner
+    orphans                         List directories under FINDER_ROOT without an account
+    rebuild-blobs                   Recount the links to stored contents and remove unused ones
+
+Passwords are read from ARCA_PASSWORD or stdin.";
+
+/// Reads a password from `ARCA_PASSWORD` or, if unset, from a line on stdin
+fn read_password() -> io::Result<String> {
+    if let Ok(password) = std::env::var("ARCA_PASSWORD") {
+        return Ok(password);
+    }
+    print!("Password: ");
+    io::stdout().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn other(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn email_arg(args: &[String]) -> io::Result<&str> {
+    args.first()
+        .map(String::as_str)
+        .ok_or_else(|| other(USAGE))
+}
+
+fn list_users(pool: &DbPool) -> io::Result<()> {
+    use crate::schema::users::dsl;
+
+    let conn = pool.get().map_err(other)?;
+    let users = dsl::users
+        .order(dsl::email.asc())
+        .load::<User>(&conn)
+        .map_err(other)?;
+
+    for user in users {
+        let state = if user.deleted_at.is_some() {
+            "deleted"
+        } else if user.disabled {
+            "disabled"
+        } else {
+            "active"
+        };
+        println!("{}\t{}\t{}\t{}", user.id, user.email, user.role, state);
+    }
+    Ok(())
+}
+
+fn create_user(pool: &DbPool, args: &[String], admin: bool) -> io::Result<()> {
+    let email = email_arg(args)?;
+    let admin = admin || args.iter().any(|arg| arg == "--admin");
+    let password = read_password()?;
+    PasswordPolicy::from_env().check(email, &password).map_err(other)?;
+
+    let conn = pool.get().map_err(other)?;
+    let mut user = User::create(&conn, &PasswordHasher::from_env(), email, &password, None).map_err(other)?;
+    if admin {
+        user.role = ADMIN_ROLE.to_owned();
+        user = user.update(&conn).map_err(other)?;
+    }
+
+    println!("Created {} {} ({})", user.role, user.email, user.id);
+    Ok(())
+}
+
+async fn delete_user(pool: &DbPool, args: &[String]) -> io::Result<()> {
+    let user = {
+        let conn = pool.get().map_err(other)?;
+        User::find(&conn, email_arg(args)?).map_err(other)?
+    };
+    let email = user.email.clone();
+
+    let root = finder::init();
+    deletion::delete_account(pool, &root, deletion::grace_from_env(), user, None)
+        .await
+        .map_err(other)?;
+
+    println!("Deleted {}", email);
+    Ok(())
+}
+
+fn reset_password(pool: &DbPool, args: &[String]) -> io::Result<()> {
+    let conn = pool.get().map_err(other)?;
+    let mut user = User::find(&conn, email_arg(args)?).map_err(other)?;
+    let password = read_password()?;
+    PasswordPolicy::from_env().check(&user.email, &password).map_err(other)?;
+
+    user.set_password(&conn, &PasswordHasher::from_env(), &password)
+        .map_err(other)?;
+    user.bump_session_version(&conn).map_err(other)?;
+
+    println!("Password of {} reset", user.email);
+    Ok(())
+}
+
+fn migrate(pool: &DbPool) -> io::Result<()> {
+    let conn = pool.get().map_err(other)?;
+    embedded_migrations::run_with_output(&conn, &mut io::stdout()).map_err(other)
+}
+
+fn active_users(pool: &DbPool) -> io::Result<Vec<User>> {
+    use crate::schema::users::dsl;
+
+    let conn = pool.get().map_err(other)?;
+    dsl::users
+        .filter(dsl::deleted_at.is_null())
+        .load::<User>(&conn)
+        .map_err(other)
+}
+
+/// Reports volumes which are missing, not directories or owned by another uid than
+/// this process or the user's mapped Unix account
+fn check_volumes(pool: &DbPool) -> io::Result<()> {
+    let root = finder::init();
+    let mut problems = 0;
+
+    for user in active_users(pool)? {
+        let (uid, mut paths) = match &user.os_user {
+            Some(name) => match privsep::os_user(name) {
+                Ok(account) => (account.uid.as_raw(), vec![account.dir]),
+                Err(e) => {
+                    problems += 1;
+                    println!("{}\t{}\t{}", user.email, name, e);
+                    continue;
+                }
+            },
+            None => (nix::unistd::geteuid().as_raw(), vec![Volume::user_path(&root, user.id)]),
+        };
+        paths.extend(user.volumes.iter().flatten().map(|vol| vol.path.clone()));
+
+        for path in paths {
+            let problem = match std::fs::metadata(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => "missing".to_owned(),
+                Err(e) => e.to_string(),
+                Ok(metadata) if !metadata.is_dir() => "not a directory".to_owned(),
+                Ok(metadata) if metadata.uid() != uid => format!("owned by uid {}", metadata.uid()),
+                Ok(_) => continue,
+            };
+            problems += 1;
+            println!("{}\t{}\t{}", user.email, path.display(), problem);
+        }
+    }
+
+    if problems > 0 {
+        return Err(other(format!("{} volume problem(s) found", problems)));
+    }
+    println!("All volumes ok");
+    Ok(())
+}
+
+/// Lists directories under `FINDER_ROOT` that do not belong to an account
+fn orphans(pool: &DbPool) -> io::Result<()> {
+    let root = finder::init();
+    let known = active_users(pool)?
+        .into_iter()
+        .map(|user| user.id.to_simple().to_string())
+        .collect::<HashSet<_>>();
+
+    for entry in std::fs::read_dir(&root)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // internal directories such as .deleted and .exports, and group volumes
+        if name.starts_with('.') || name == GROUP_DIR {
+            continue;
+        }
+        if !known.contains(&name) {
+            println!("{}", entry.path().display());
+        }
+    }
+    Ok(())
+}
+
+/// Refreshes the reference counts of the content store from the blobs' link counts and
+/// removes unused blobs, which is otherwise done hourly by the server
+async fn rebuild_blobs(pool: &DbPool) -> io::Result<()> {
+    let root = finder::init();
+    let store = BlobStore::from_env(&root).ok_or_else(|| other("CONTENT_STORE is not on"))?;
+    let freed = store.collect_garbage(pool).await.map_err(other)?;
+    println!("Freed {} bytes", freed);
+    Ok(())
+}
+
+/// Runs a subcommand given on the command line, returns `None` if there is none
+/// and the server should be started
+pub async fn run(pool: &DbPool, args: &[String]) -> Option<io::Result<()>> {
+    let (command, args) = args.split_first()?;
+    let (subcommand, subargs) = args
+        .split_first()
+        .map(|(subcommand, subargs)| (subcommand.as_str(), subargs))
+        .unwrap_or(("", &[]));
+
+    Some(match (command.as_str(), subcommand) {
+        ("users", "list") => list_users(pool),
+        ("users", "create") => create_user(pool, subargs, false),
+        ("users", "delete") => delete_user(pool, subargs).await,
+        ("users", "reset-password") => reset_password(pool, subargs),
+        ("create-admin", _) => create_user(pool, args, true),
+        ("migrate", _) => migrate(pool),
+        ("check-volumes", _) => check_volumes(pool),
+        ("orphans", _) => orphans(pool),
+        ("rebuild-blobs", _) => rebuild_blobs(pool).await,
+        ("help", _) | ("--help", _) => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(other(USAGE)),
+    })
+}
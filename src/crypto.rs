@@ -0,0 +1,58 @@
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::env;
+
+const NONCE_LEN: usize = 12;
+
+/// Symmetric key used to encrypt secrets that are stored at rest (TOTP secrets, ...)
+#[derive(Clone)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// Reads the key from `ARCA_SECRET_KEY`, a hex encoded 32 byte value
+    pub fn from_env() -> Self {
+        let hex_key = env::var("ARCA_SECRET_KEY").expect("Could not find ARCA_SECRET_KEY in .env");
+        let bytes = hex::decode(hex_key.trim()).expect("ARCA_SECRET_KEY must be hex encoded");
+        if bytes.len() != 32 {
+            panic!("ARCA_SECRET_KEY must be 32 bytes long");
+        }
+        let mut key = [0; 32];
+        key.copy_from_slice(&bytes);
+        Self(key)
+    }
+
+    /// Key signing the session cookie, derived so the encryption key itself is never used for it
+    pub fn session_key(&self) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.0).expect("HMAC can take a key of any size");
+        mac.update(b"arca session cookie");
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Encrypts `plaintext`, returning the nonce followed by the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&self.0));
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(&nonce), plaintext)
+            .ok()?;
+        let mut out = nonce.to_vec();
+        out.extend(ciphertext);
+        Some(out)
+    }
+
+    /// Decrypts a value produced by `encrypt`
+    pub fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&self.0));
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        cipher
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
@@ -1,10 +1,32 @@
 use std::path::PathBuf;
 use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::pg::PgConnection;
+use crate::blob::BlobStore;
+use crate::change::Changes;
+use crate::crypto::SecretKey;
+use crate::mail::Mailer;
+use crate::privsep::Helper;
+use crate::user::auth::AuthProvider;
+use crate::user::oidc::OidcConfig;
+use crate::user::password::{PasswordHasher, PasswordPolicy};
+use crate::version::Retention;
+use std::sync::Arc;
 
-type DbPool = Pool<ConnectionManager<PgConnection>>;
+pub(crate) type DbPool = Pool<ConnectionManager<PgConnection>>;
 pub struct Environment {
     pub(crate) db_pool: DbPool,
     pub(crate) finder_root: PathBuf,
-    pub(crate) bind_addr: String
+    pub(crate) bind_addr: String,
+    pub(crate) secret_key: SecretKey,
+    pub(crate) oidc: Option<OidcConfig>,
+    pub(crate) auth: Arc<dyn AuthProvider>,
+    pub(crate) hasher: PasswordHasher,
+    pub(crate) password_policy: Arc<PasswordPolicy>,
+    pub(crate) mailer: Mailer,
+    pub(crate) deletion_grace: Option<chrono::Duration>,
+    pub(crate) volume_bases: Vec<PathBuf>,
+    pub(crate) fs_helper: Option<Arc<Helper>>,
+    pub(crate) versions: Retention,
+    pub(crate) blobs: Option<BlobStore>,
+    pub(crate) changes: Changes,
 }
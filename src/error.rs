@@ -9,6 +9,9 @@ pub enum Error {
     IoError(tokio::io::Error),
     PathError,
     InvalidParams,
+    Forbidden,
+    Gone,
+    QuotaExceeded,
     Other(String),
 }
 
@@ -22,6 +25,9 @@ impl fmt::Display for Error {
             IoError(_) => write!(f, "IO Error"),
             InvalidParams => write!(f, "Invalid Params"),
             PathError => write!(f, "Path Error"),
+            Forbidden => write!(f, "Forbidden"),
+            Gone => write!(f, "Gone"),
+            QuotaExceeded => write!(f, "Quota exceeded"),
             Other(ref s) => write!(f, "Internal Error: {}", s),
         }
     }
@@ -32,6 +38,9 @@ impl error::ResponseError for Error {
         use Error::*;
         match *self {
             InvalidParams => http::StatusCode::BAD_REQUEST,
+            Forbidden => http::StatusCode::FORBIDDEN,
+            Gone => http::StatusCode::GONE,
+            QuotaExceeded => http::StatusCode::INSUFFICIENT_STORAGE,
             UserError(ref e) => e.status_code(),
             _ => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -52,3 +61,9 @@ impl From<user::error::Error> for Error {
     }
 }
 
+
+impl From<diesel::result::Error> for Error {
+    fn from(e: diesel::result::Error) -> Self {
+        Self::UserError(e.into())
+    }
+}
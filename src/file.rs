@@ -1,9 +1,15 @@
 use super::error::{Error, Result};
+use super::storage::Stat;
+use super::trash::TRASH_DIR;
+use super::version::VERSIONS_DIR;
 use super::volume::Volume;
 use serde_derive::Serialize;
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::time::UNIX_EPOCH;
+use std::path::{Component, Path, PathBuf};
+
+/// Directories the server keeps inside volumes, invisible and unreachable through the finder
+pub(crate) const HIDDEN_DIRS: &[&str] = &[TRASH_DIR, VERSIONS_DIR];
+
 /// Serializable File descriptor which follows the ElFinder Protocol
 /// {
 ///     "name"   : "Images",             // (String) name of file/dir. Required
@@ -33,7 +39,7 @@ pub struct File {
     hash: String,
     phash: Option<String>,
     mime: String,
-    ts: u128,
+    ts: u64,
     size: i64,
     dirs: i8,
     read: i8,
@@ -51,75 +57,92 @@ pub struct File {
 }
 
 impl File {
-    fn check_path(vol: &Volume, path: impl AsRef<Path>) -> Result<PathBuf> {
-        let path = [vol.path.as_ref(), path.as_ref()]
-            .iter()
-            .collect::<PathBuf>();
-        if path < vol.path {
-            return Err(tokio::io::Error::new(
-                tokio::io::ErrorKind::PermissionDenied,
-                "Cannot access directory outside of volume root",
-            )
-            .into());
-        }
-        Ok(path)
-    }
-    async fn has_subdirs(path: impl AsRef<Path>) -> Result<bool> {
-        let mut dir = tokio::fs::read_dir(path).await?;
-        let mut subdirs = false;
-        while let Some(dir_entry) = dir.next_entry().await? {
-            if dir_entry.file_type().await?.is_dir() {
-                subdirs = true;
-                break;
+    /// Resolves a path relative to the volume root, rejecting paths which leave the volume
+    /// or reach into a trash or versions directory
+    pub(crate) fn check_path(vol: &Volume, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let mut full = vol.path.clone();
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(part) if HIDDEN_DIRS.iter().any(|dir| part == *dir) => return Err(Error::PathError),
+                Component::Normal(part) => full.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(tokio::io::Error::new(
+                        tokio::io::ErrorKind::PermissionDenied,
+                        "Cannot access directory outside of volume root",
+                    )
+                    .into())
+                }
             }
         }
-        Ok(subdirs)
+        Ok(full)
     }
 
-    async fn parent(vol: &Volume, path: impl AsRef<Path>) -> Result<Option<PathBuf>> {
-        let path = Self::check_path(vol, path)?; 
-        match tokio::fs::metadata(&path).await {
-            Ok(_) => Ok(Some(path.canonicalize()?)),
-            Err(err) if err.kind() == tokio::io::ErrorKind::NotFound => Ok(None),
-            Err(e) => Err(e.into()),
-        }
+    /// `/`-separated path of `path` relative to the volume root, `/` being the root itself
+    pub(crate) fn relative(path: impl AsRef<Path>) -> String {
+        let parts = path
+            .as_ref()
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        format!("/{}", parts.join("/"))
     }
-    pub async fn info(vol: &Volume, path: impl AsRef<Path>) -> Result<Self> {
-        let path = Self::check_path(vol, path)?; 
-        let metadata = tokio::fs::metadata(&path).await?;
-        let name = path
-            .canonicalize()?
-            .file_name()
-            .map(|os_str| os_str.to_string_lossy().to_string())
-            .unwrap_or(String::new());
-
-        let hash = path
-            .canonicalize()?
-            .strip_prefix(&vol.path)
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-
-        let ts = metadata
-            .modified()?
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        let mime = if metadata.file_type().is_dir() {
+
+    /// Hash of a path inside a volume: the volume id followed by the base64 encoded relative path
+    pub fn hash(vol: &Volume, path: impl AsRef<Path>) -> String {
+        format!(
+            "{}{}",
+            vol.id,
+            base64::encode_config(Self::relative(path), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Splits a hash into its volume id and the relative path it encodes
+    pub fn decode_hash(hash: &str) -> Result<(&str, PathBuf)> {
+        let split = hash.find('_').ok_or(Error::InvalidParams)? + 1;
+        let (id, encoded) = hash.split_at(split);
+        let path = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(Error::InvalidParams)?;
+        Ok((id, PathBuf::from(path)))
+    }
+
+    async fn has_subdirs(vol: &Volume, path: impl AsRef<Path>) -> Result<bool> {
+        Ok(vol.storage().list(path.as_ref())
+            .await?
+            .iter()
+            .any(|(name, stat)| stat.is_dir && !HIDDEN_DIRS.contains(&name.as_str())))
+    }
+
+    async fn from_metadata(vol: &Volume, path: &Path, metadata: Stat) -> Result<Self> {
+        let relative = Self::relative(path);
+        let is_root = relative == "/";
+        let name = if is_root {
+            vol.name.clone()
+        } else {
+            path.file_name()
+                .map(|os_str| os_str.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+
+        let ts = metadata.modified;
+        let is_dir = metadata.is_dir;
+        let mime = if is_dir {
             "directory".to_owned()
         } else {
             "file".to_owned() /* TODO */
         };
-        let dirs = Self::has_subdirs(&path).await?;
-        let readonly = metadata.permissions().readonly();
-        let phash = Self::parent(vol, &path)
-            .await?
-            .as_deref()
-            .map(|p| p.to_string_lossy().to_string());
-        let size = metadata.len() as i64;
+        let dirs = is_dir && Self::has_subdirs(vol, Self::check_path(vol, path)?).await?;
+        let readonly = metadata.readonly || !vol.access.can_write();
+        let phash = path.parent().filter(|_| !is_root).map(|p| Self::hash(vol, p));
+        let size = metadata.len as i64;
         Ok(Self {
             name,
-            hash,
+            hash: Self::hash(vol, path),
             phash,
             mime,
             ts,
@@ -127,84 +150,189 @@ impl File {
             dirs: if dirs { 1 } else { 0 },
             read: 1,
             write: if readonly { 0 } else { 1 },
-            // TODO
-            locked: 0,
+            // roots cannot be renamed, moved or removed
+            locked: if is_root { 1 } else { 0 },
+            tmb: None,
+            alias: None,
+            thash: None,
+            dim: None,
+            isowner: Some(vol.access == crate::volume::Access::Admin),
+            csscls: None,
+            volumeid: if is_root { Some(vol.id.clone()) } else { None },
+            netkey: vol.netmount.filter(|_| is_root).map(|id| id.to_string()),
+            options: None,
+        })
+    }
+
+    /// Root of a volume which does not exist as a single directory, such as the trash
+    pub fn virtual_root(id: &str, name: &str, dirs: bool) -> Self {
+        Self {
+            name: name.to_owned(),
+            hash: format!("{}{}", id, base64::encode_config("/", base64::URL_SAFE_NO_PAD)),
+            phash: None,
+            mime: "directory".to_owned(),
+            ts: 0,
+            size: 0,
+            dirs: if dirs { 1 } else { 0 },
+            read: 1,
+            write: 1,
+            locked: 1,
             tmb: None,
             alias: None,
             thash: None,
             dim: None,
             isowner: Some(true),
             csscls: None,
-            volumeid: None,
+            volumeid: Some(id.to_owned()),
             netkey: None,
             options: None,
-        })
+        }
+    }
+
+    /// Shows the file under another name, e.g. a trashed file under its original one
+    pub fn with_name(self, name: String) -> Self {
+        Self { name, ..self }
     }
+
+    pub fn is_dir(&self) -> bool {
+        self.mime == "directory"
+    }
+
+    /// Describes the file at `path`, relative to the volume root
+    pub async fn info(vol: &Volume, path: impl AsRef<Path>) -> Result<Self> {
+        let full = Self::check_path(vol, &path)?;
+        let metadata = vol.storage().stat(&full).await?;
+        Self::from_metadata(vol, &PathBuf::from(Self::relative(&path)), metadata).await
+    }
+
+    /// Lists the directory at `path`, relative to the volume root
     pub async fn open_dir<P: AsRef<Path>>(vol: &Volume, path: P) -> Result<Vec<Self>> {
-        let path = Self::check_path(vol, path)?; 
-        let mut dir = tokio::fs::read_dir(path).await?;
+        let full = Self::check_path(vol, &path)?;
+        let relative = PathBuf::from(Self::relative(&path));
         let mut all_dirs = Vec::new();
 
-        while let Some(dir_entry) = dir.next_entry().await? {
-            let metadata = dir_entry.metadata().await?;
-            let ts = metadata
-                .modified()?
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis();
-            let readonly = metadata.permissions().readonly();
-            let name = dir_entry.file_name().into_string().unwrap_or(String::new());
-            let hash = dir_entry
-                .path()
-                .canonicalize()?
-                .strip_prefix(&vol.path)
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-            let mime = if dir_entry.file_type().await?.is_dir() {
-                "directory".to_owned()
-            } else {
-                "file".to_owned() /* TODO */
-            };
-            let size = metadata.len() as i64;
-            let phash = Self::parent(vol, &dir_entry.path())
-                .await?
-                .as_deref()
-                .map(|p| p.to_string_lossy().to_string());
-            let dirs = Self::has_subdirs(dir_entry.path()).await?;
-            all_dirs.push(Self {
-                name,
-                hash,
-                phash,
-                mime,
-                ts,
-                size,
-                dirs: if dirs { 1 } else { 0 },
-                read: 1,
-                write: if readonly { 0 } else { 1 },
-                // TODO
-                locked: 0,
-                tmb: None,
-                alias: None,
-                thash: None,
-                dim: None,
-                isowner: Some(true),
-                csscls: None,
-                volumeid: None,
-                netkey: None,
-                options: None,
-            });
+        for (name, metadata) in vol.storage().list(&full).await? {
+            if HIDDEN_DIRS.contains(&name.as_str()) {
+                continue;
+            }
+            let path = relative.join(name);
+            all_dirs.push(Self::from_metadata(vol, &path, metadata).await?);
         }
         Ok(all_dirs)
     }
-    pub async fn chmod(vol: &Volume, path: impl AsRef<Path>, perm: std::fs::Permissions) -> Result<File> {
+
+    pub async fn chmod(vol: &Volume, path: impl AsRef<Path>, mode: u32) -> Result<File> {
+        vol.check_write()?;
         let orig_path = path;
-        let path = Self::check_path(vol, &orig_path)?;        
-        tokio::fs::set_permissions(path, perm).await?;
+        let path = Self::check_path(vol, &orig_path)?;
+        vol.storage().set_mode(&path, mode).await?;
         Self::info(vol, orig_path).await
     }
 
-    pub async fn duplicate(vol: &Volume, path: impl AsRef<Path>) -> Result<File> {
-        unimplemented!()
+    /// `path`, relative to the volume root, or if it is taken the first free name of the
+    /// form "name copy N.ext" next to it
+    pub async fn copy_name(vol: &Volume, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref();
+        let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+        let name = path.file_name().ok_or(Error::InvalidParams)?.to_string_lossy();
+        let (stem, extension) = match name.rfind('.').filter(|&i| i > 0) {
+            Some(i) => name.split_at(i),
+            None => (name.as_ref(), ""),
+        };
+
+        let storage = vol.storage();
+        let mut target = path.to_owned();
+        let mut n = 1;
+        while storage.stat(&Self::check_path(vol, &target)?).await.is_ok() {
+            target = parent.join(format!("{} copy {}{}", stem, n, extension));
+            n += 1;
+        }
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Driver;
+    use crate::volume::Access;
+
+    /// A volume of its own in the shared memory tree
+    async fn volume(name: &str) -> Volume {
+        let vol = Volume {
+            driver: Driver::Memory,
+            ..Volume::new(format!("/file-tests/{}", name), "m1_", name, Access::Write)
+        };
+        vol.storage().mkdir(&vol.path, true).await.unwrap();
+        vol
+    }
+
+    #[actix_rt::test]
+    async fn describes_files_and_folders() {
+        let vol = volume("describe").await;
+        let storage = vol.storage();
+        storage.mkdir(&vol.path.join("docs/drafts"), true).await.unwrap();
+        storage.write_all(&vol.path.join("docs/a.txt"), b"hello").await.unwrap();
+
+        let root = File::info(&vol, "/").await.unwrap();
+        assert_eq!(root.name, "describe");
+        assert_eq!(root.locked, 1);
+        assert_eq!(root.volumeid.as_deref(), Some("m1_"));
+
+        let docs = File::info(&vol, "/docs").await.unwrap();
+        assert!(docs.is_dir());
+        assert_eq!(docs.dirs, 1);
+        assert_eq!(docs.phash.as_deref(), Some(File::hash(&vol, "/").as_str()));
+
+        let file = File::info(&vol, "/docs/a.txt").await.unwrap();
+        assert!(!file.is_dir());
+        assert_eq!(file.size, 5);
+        assert_eq!(file.write, 1);
+    }
+
+    #[actix_rt::test]
+    async fn hides_trash_and_versions() {
+        let vol = volume("hidden").await;
+        let storage = vol.storage();
+        for dir in HIDDEN_DIRS {
+            storage.mkdir(&vol.path.join(dir), false).await.unwrap();
+        }
+        storage.write_all(&vol.path.join("a.txt"), b"").await.unwrap();
+
+        let names: Vec<_> = File::open_dir(&vol, "/").await.unwrap().into_iter().map(|file| file.name).collect();
+        assert_eq!(names, vec!["a.txt"]);
+        assert_eq!(File::info(&vol, "/").await.unwrap().dirs, 0);
+        assert!(File::check_path(&vol, "/.trash/x").is_err());
+        assert!(File::check_path(&vol, "/../other").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn finds_a_free_copy_name() {
+        let vol = volume("copies").await;
+        let storage = vol.storage();
+        storage.write_all(&vol.path.join("a.txt"), b"").await.unwrap();
+        storage.write_all(&vol.path.join("a copy 1.txt"), b"").await.unwrap();
+
+        assert_eq!(File::copy_name(&vol, "/b.txt").await.unwrap(), Path::new("/b.txt"));
+        assert_eq!(File::copy_name(&vol, "/a.txt").await.unwrap(), Path::new("/a copy 2.txt"));
+    }
+
+    #[actix_rt::test]
+    async fn read_only_volumes_refuse_changes() {
+        let vol = Volume {
+            access: Access::Read,
+            ..volume("readonly").await
+        };
+        vol.storage().write_all(&vol.path.join("a.txt"), b"").await.unwrap();
+
+        assert_eq!(File::info(&vol, "/a.txt").await.unwrap().write, 0);
+        assert!(File::chmod(&vol, "/a.txt", 0o600).await.is_err());
+    }
+
+    #[test]
+    fn hashes_round_trip() {
+        let vol = Volume::new("/", "m1_", "", Access::Read);
+        let hash = File::hash(&vol, "/docs/a b.txt");
+        assert_eq!(File::decode_hash(&hash).unwrap(), ("m1_", PathBuf::from("/docs/a b.txt")));
     }
 }
@@ -0,0 +1,122 @@
+use super::error::{Error, Result};
+use super::privsep::Stat;
+use super::schema::folder_grants;
+use super::user::error::Error as UserError;
+use super::volume::Access;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde_derive::Serialize;
+use std::path::Path;
+
+/// Access to a folder of one user's volume given to another user. Like public shares the
+/// folder is remembered by its path and inode, so a grant lapses once the folder is moved.
+#[derive(Queryable, Serialize)]
+pub struct Grant {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) owner_id: uuid::Uuid,
+    pub(crate) recipient_id: uuid::Uuid,
+    pub(crate) path: String,
+    #[serde(skip)]
+    device: i64,
+    #[serde(skip)]
+    inode: i64,
+    access: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "folder_grants"]
+struct NewGrant<'a> {
+    owner_id: uuid::Uuid,
+    recipient_id: uuid::Uuid,
+    path: &'a str,
+    device: i64,
+    inode: i64,
+    access: &'a str,
+}
+
+impl Grant {
+    /// Grants `recipient_id` access to the folder at the absolute `path`, which `stat`
+    /// describes, replacing an earlier grant of the same folder
+    pub fn create(
+        conn: &PgConnection,
+        owner_id: uuid::Uuid,
+        recipient_id: uuid::Uuid,
+        path: &Path,
+        stat: &Stat,
+        access: Access,
+    ) -> Result<Self> {
+        use crate::schema::folder_grants::dsl;
+
+        if !stat.is_dir || access == Access::Admin || owner_id == recipient_id {
+            return Err(Error::InvalidParams);
+        }
+
+        let path = path.to_string_lossy();
+        diesel::insert_into(dsl::folder_grants)
+            .values(&NewGrant {
+                owner_id,
+                recipient_id,
+                path: &path,
+                device: stat.dev as i64,
+                inode: stat.ino as i64,
+                access: access.as_str(),
+            })
+            .on_conflict((dsl::recipient_id, dsl::path))
+            .do_update()
+            .set((
+                dsl::owner_id.eq(owner_id),
+                dsl::device.eq(stat.dev as i64),
+                dsl::inode.eq(stat.ino as i64),
+                dsl::access.eq(access.as_str()),
+            ))
+            .get_result::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    /// Grants the user gave to others
+    pub fn given(conn: &PgConnection, owner_id: uuid::Uuid) -> Result<Vec<Self>> {
+        use crate::schema::folder_grants::dsl;
+
+        dsl::folder_grants
+            .filter(dsl::owner_id.eq(owner_id))
+            .order(dsl::created_at.asc())
+            .load::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    /// Grants other users gave to the user
+    pub fn received(conn: &PgConnection, recipient_id: uuid::Uuid) -> Result<Vec<Self>> {
+        use crate::schema::folder_grants::dsl;
+
+        dsl::folder_grants
+            .filter(dsl::recipient_id.eq(recipient_id))
+            .order(dsl::created_at.asc())
+            .load::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    /// Removes a grant, either by its owner or by the recipient giving it up
+    pub fn revoke(conn: &PgConnection, user_id: uuid::Uuid, id: uuid::Uuid) -> Result<Self> {
+        use crate::schema::folder_grants::dsl;
+
+        diesel::delete(
+            dsl::folder_grants
+                .filter(dsl::id.eq(id))
+                .filter(dsl::owner_id.eq(user_id).or(dsl::recipient_id.eq(user_id))),
+        )
+        .get_result::<Self>(conn)
+        .optional()?
+        .ok_or(Error::UserError(UserError::NotFound))
+    }
+
+    pub fn access(&self) -> Access {
+        self.access.parse().unwrap_or_default()
+    }
+
+    /// Whether `stat` still describes the granted folder
+    pub fn is_target(&self, stat: &Stat) -> bool {
+        stat.dev as i64 == self.device && stat.ino as i64 == self.inode
+    }
+}
@@ -0,0 +1,168 @@
+use super::error::{Error, Result};
+use super::schema::{group_members, groups};
+use super::user::User;
+use super::volume::{Access, Volume};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Directory under `FINDER_ROOT` holding group volumes
+pub const GROUP_DIR: &str = "groups";
+
+/// A team owning a shared volume
+#[derive(Queryable, Serialize)]
+pub struct Group {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) name: String,
+    pub(crate) created_at: DateTime<Utc>,
+    /// Space the group volume may use, taken from its creator and changed by administrators
+    pub(crate) quota_bytes: Option<i64>,
+}
+
+#[derive(Queryable, Insertable, Serialize)]
+#[table_name = "group_members"]
+pub struct Member {
+    pub(crate) group_id: uuid::Uuid,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) role: String,
+}
+
+impl Member {
+    pub fn access(&self) -> Access {
+        self.role.parse().unwrap_or_default()
+    }
+}
+
+impl Group {
+    pub fn path(finder_root: &Path, id: uuid::Uuid) -> PathBuf {
+        finder_root
+            .join(GROUP_DIR)
+            .join(id.to_simple().to_string())
+    }
+
+    /// Creates the group with `owner` as its first admin, along with its volume. The group
+    /// starts out with the owner's quota so it can't be used to store more than they may.
+    pub async fn create(conn: &PgConnection, finder_root: &Path, name: &str, owner: &User) -> Result<Self> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::InvalidParams);
+        }
+
+        let group = conn.transaction::<_, Error, _>(|| {
+            let group = diesel::insert_into(groups::table)
+                .values((groups::name.eq(name), groups::quota_bytes.eq(owner.quota_bytes)))
+                .get_result::<Self>(conn)?;
+            diesel::insert_into(group_members::table)
+                .values(&Member {
+                    group_id: group.id,
+                    user_id: owner.id,
+                    role: Access::Admin.as_str().to_owned(),
+                })
+                .execute(conn)?;
+            Ok(group)
+        })?;
+
+        tokio::fs::create_dir_all(Self::path(finder_root, group.id)).await?;
+        Ok(group)
+    }
+
+    /// Groups the user belongs to, with the user's membership
+    pub fn for_user(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Vec<(Self, Member)>> {
+        groups::table
+            .inner_join(group_members::table)
+            .filter(group_members::user_id.eq(user_id))
+            .order(groups::name.asc())
+            .load::<(Self, Member)>(conn)
+            .map_err(Into::into)
+    }
+
+    pub fn membership(conn: &PgConnection, id: uuid::Uuid, user_id: uuid::Uuid) -> Result<Option<Member>> {
+        group_members::table
+            .find((id, user_id))
+            .first::<Member>(conn)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Fails unless the user is a member with at least `access`
+    pub fn check_access(conn: &PgConnection, id: uuid::Uuid, user_id: uuid::Uuid, access: Access) -> Result<Member> {
+        match Self::membership(conn, id, user_id)? {
+            Some(member) if member.access() >= access => Ok(member),
+            _ => Err(Error::Forbidden),
+        }
+    }
+
+    pub fn members(conn: &PgConnection, id: uuid::Uuid) -> Result<Vec<Member>> {
+        group_members::table
+            .filter(group_members::group_id.eq(id))
+            .load::<Member>(conn)
+            .map_err(Into::into)
+    }
+
+    /// Adds a member or changes their role, refusing to demote the last admin
+    pub fn set_member(conn: &PgConnection, id: uuid::Uuid, user_id: uuid::Uuid, access: Access) -> Result<Member> {
+        let member = Member {
+            group_id: id,
+            user_id,
+            role: access.as_str().to_owned(),
+        };
+        conn.transaction::<_, Error, _>(|| {
+            let member = diesel::insert_into(group_members::table)
+                .values(&member)
+                .on_conflict((group_members::group_id, group_members::user_id))
+                .do_update()
+                .set(group_members::role.eq(&member.role))
+                .get_result::<Member>(conn)?;
+            Self::check_admins(conn, id)?;
+            Ok(member)
+        })
+    }
+
+    /// Removes a member, refusing to remove the last admin
+    pub fn remove_member(conn: &PgConnection, id: uuid::Uuid, user_id: uuid::Uuid) -> Result<()> {
+        conn.transaction::<_, Error, _>(|| {
+            diesel::delete(group_members::table.find((id, user_id))).execute(conn)?;
+            Self::check_admins(conn, id)
+        })
+    }
+
+    /// Fails if the group was left without an admin
+    fn check_admins(conn: &PgConnection, id: uuid::Uuid) -> Result<()> {
+        let admins = group_members::table
+            .filter(group_members::group_id.eq(id))
+            .filter(group_members::role.eq(Access::Admin.as_str()))
+            .count()
+            .get_result::<i64>(conn)?;
+        if admins == 0 {
+            return Err(Error::InvalidParams);
+        }
+        Ok(())
+    }
+
+    /// Deletes the group and its volume
+    pub async fn delete(conn: &PgConnection, finder_root: &Path, id: uuid::Uuid) -> Result<()> {
+        diesel::delete(groups::table.find(id)).execute(conn)?;
+        match tokio::fs::remove_dir_all(Self::path(finder_root, id)).await {
+            Err(e) if e.kind() != tokio::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Volumes of every group the user belongs to
+    pub fn volumes(conn: &PgConnection, finder_root: &Path, user_id: uuid::Uuid) -> Result<Vec<Volume>> {
+        Ok(Self::for_user(conn, user_id)?
+            .into_iter()
+            .map(|(group, member)| {
+                Volume::new(
+                    Self::path(finder_root, group.id),
+                    &Volume::group_id(group.id),
+                    &group.name,
+                    member.access(),
+                )
+                .with_quota_bytes(group.quota_bytes)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,64 @@
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// Sends notification emails through a local `sendmail` compatible binary.
+/// Without `SENDMAIL_PATH` sending fails, unless `MAIL_PRINT` is set for development, in which
+/// case the messages, links included, are only printed.
+#[derive(Clone)]
+pub struct Mailer {
+    sendmail: Option<String>,
+    print: bool,
+    from: String,
+    pub(crate) base_url: String,
+}
+
+impl Mailer {
+    pub fn from_env(bind_addr: &str) -> Self {
+        Self {
+            sendmail: env::var("SENDMAIL_PATH").ok(),
+            print: env::var("MAIL_PRINT").map_or(false, |v| v == "true" || v == "1"),
+            from: env::var("MAIL_FROM").unwrap_or_else(|_| "arca@localhost".to_owned()),
+            base_url: env::var("PUBLIC_URL")
+                .map(|url| url.trim_end_matches('/').to_owned())
+                .unwrap_or_else(|_| format!("http://{}", bind_addr)),
+        }
+    }
+
+    pub fn send(&self, to: &str, subject: &str, body: &str) -> io::Result<()> {
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            self.from, to, subject, body
+        );
+
+        let sendmail = match &self.sendmail {
+            Some(sendmail) => sendmail,
+            None if self.print => {
+                println!("{}", message);
+                return Ok(());
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no mail transport, set SENDMAIL_PATH",
+                ))
+            }
+        };
+
+        let mut child = Command::new(sendmail)
+            .args(&["-i", "--", to])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(message.as_bytes())?;
+
+        if child.wait()?.success() {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, "sendmail failed"))
+        }
+    }
+}
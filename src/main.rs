@@ -1,15 +1,32 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
 pub mod models;
 pub mod schema;
 
 mod api;
+mod blob;
+mod change;
 mod file;
+mod group;
 mod volume;
 mod user;
 mod env;
 mod error;
+mod crypto;
+mod audit;
+mod cli;
+mod mail;
+mod suffix;
+mod privsep;
+mod share;
+mod storage;
+mod grant;
+mod trash;
+mod netmount;
+mod version;
 
 use crate::api::finder;
 use actix_files::NamedFile;
@@ -22,6 +39,12 @@ use diesel::r2d2::{self, ConnectionManager, Pool};
 use dotenv::dotenv;
 use std::path::PathBuf;
 use env::Environment;
+use crypto::SecretKey;
+use mail::Mailer;
+use suffix::EmailValidator;
+use user::oidc::OidcConfig;
+use user::password::{PasswordHasher, PasswordPolicy};
+use std::sync::Arc;
 
 
 async fn app(req: HttpRequest) -> Result<NamedFile> {
@@ -38,19 +61,59 @@ async fn app(req: HttpRequest) -> Result<NamedFile> {
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
+    // RUST_LOG picks what is logged, e.g. RUST_LOG=debug
+    env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    // the file system helper is started by the server itself and needs no database
+    if args.first().map(String::as_str) == Some("fs-helper") {
+        return privsep::serve(args.iter().any(|arg| arg == "--mock"));
+    }
+    storage::S3::init();
+    storage::sftp::HostPolicy::init();
 
     let database_url = std::env::var("DATABASE_URL").expect("Canno find DATABASE_URL in .env");
-    let addr = std::env::var("BIND_ADDR").expect("Cannot find BIND_ADDR in .env");
 
-    println!("Connecting to database {}", database_url);
+    log::info!("Connecting to database {}", database_url);
 
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     let pool = Pool::builder()
         .build(manager)
         .expect("Failed to create connection pool");
 
+    if let Some(result) = cli::run(&pool, &args).await {
+        return result;
+    }
+
+    let addr = std::env::var("BIND_ADDR").expect("Cannot find BIND_ADDR in .env");
+
     let root = finder::init();
-    println!("Starting actix server on {}", &addr);
+    let volume_bases = volume::bases_from_env();
+    // refuses to start without ARCA_SECRET_KEY, sessions could be forged otherwise
+    let secret_key = SecretKey::from_env();
+    let session_key = secret_key.session_key();
+    let oidc = OidcConfig::from_env();
+    let hasher = PasswordHasher::from_env();
+    let password_policy = Arc::new(PasswordPolicy::from_env());
+    let auth = user::auth::from_env(hasher.clone());
+    let mailer = Mailer::from_env(&addr);
+    let email_validator = web::Data::new(EmailValidator::from_env());
+    EmailValidator::spawn_refresh(email_validator.clone());
+    user::export::ExportJob::spawn_cleanup(pool.clone(), root.clone());
+    let deletion_grace = user::deletion::grace_from_env();
+    if let Some(grace) = deletion_grace {
+        user::deletion::spawn_purge(pool.clone(), root.clone(), grace);
+    }
+    let fs_helper = privsep::Helper::from_env();
+    trash::TrashEntry::spawn_purge(pool.clone(), fs_helper.clone(), trash::retention_from_env());
+    let versions = version::Retention::from_env();
+    version::Version::spawn_prune(pool.clone(), fs_helper.clone(), versions);
+    let blobs = blob::BlobStore::from_env(&root);
+    if let Some(store) = &blobs {
+        store.clone().spawn_gc(pool.clone());
+    }
+    let changes = change::Changes::from_env(&[vec![root.clone()], volume_bases.clone()].concat());
+    log::info!("Starting actix server on {}", &addr);
     
     HttpServer::new({
         let addr = addr.clone();
@@ -58,7 +121,7 @@ async fn main() -> std::io::Result<()> {
         App::new()
             // '/' -> '/app'
             .wrap(
-                CookieSession::signed(&[0; 32]) // <- create cookie based session middleware
+                CookieSession::signed(&session_key) // <- create cookie based session middleware
                     .secure(true)
                     .http_only(true)
                     .same_site(SameSite::Strict),
@@ -77,10 +140,25 @@ async fn main() -> std::io::Result<()> {
                     .default_service(web::get().to(app)),
             )
             .service(api::service())
+            .service(api::share::public())
+            .service(api::dav::service())
+            .app_data(email_validator.clone())
             .data(Environment {
                 db_pool: pool.clone(),
                 finder_root: root.clone(),
                 bind_addr: addr.clone(),
+                secret_key: secret_key.clone(),
+                oidc: oidc.clone(),
+                auth: auth.clone(),
+                hasher: hasher.clone(),
+                password_policy: password_policy.clone(),
+                mailer: mailer.clone(),
+                deletion_grace,
+                volume_bases: volume_bases.clone(),
+                fs_helper: fs_helper.clone(),
+                versions,
+                blobs: blobs.clone(),
+                changes: changes.clone(),
             })
     }})
     .bind(addr)?
@@ -15,6 +15,15 @@ pub struct ClientUser {
   pub email: String,
 }
 
+impl From<&crate::user::User> for ClientUser {
+  fn from(user: &crate::user::User) -> Self {
+    Self {
+      id: user.id,
+      email: user.email.clone(),
+    }
+  }
+}
+
 #[derive(Insertable, Debug)]
 #[table_name = "users"]
 pub struct NewUser {
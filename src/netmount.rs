@@ -0,0 +1,165 @@
+use super::crypto::SecretKey;
+use super::error::{Error, Result};
+use super::schema::netmounts;
+use super::storage::sftp::{Auth, Config, Sftp};
+use super::storage::Driver;
+use super::user::error::Error as UserError;
+use super::volume::{Access, Volume};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde_derive::Serialize;
+use std::path::PathBuf;
+
+/// An SFTP server a user mounted in the finder. The credentials are kept encrypted with
+/// `ARCA_SECRET_KEY`, and the host key seen on mounting has to match on every connection.
+#[derive(Queryable, Serialize)]
+pub struct Netmount {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) host: String,
+    pub(crate) port: i32,
+    pub(crate) username: String,
+    #[serde(skip)]
+    password: Option<Vec<u8>>,
+    #[serde(skip)]
+    private_key: Option<Vec<u8>>,
+    pub(crate) host_key: String,
+    pub(crate) root: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "netmounts"]
+struct NewNetmount<'a> {
+    user_id: uuid::Uuid,
+    host: &'a str,
+    port: i32,
+    username: &'a str,
+    password: Option<Vec<u8>>,
+    private_key: Option<Vec<u8>>,
+    host_key: &'a str,
+    root: &'a str,
+}
+
+fn encrypt(key: &SecretKey, secret: Option<&str>) -> Result<Option<Vec<u8>>> {
+    secret
+        .map(|secret| {
+            key.encrypt(secret.as_bytes())
+                .ok_or_else(|| Error::Other("Could not encrypt the credentials".to_owned()))
+        })
+        .transpose()
+}
+
+fn decrypt(key: &SecretKey, secret: &Option<Vec<u8>>) -> Result<Option<String>> {
+    secret
+        .as_ref()
+        .map(|secret| {
+            key.decrypt(secret)
+                .and_then(|plain| String::from_utf8(plain).ok())
+                .ok_or_else(|| Error::Other("Could not decrypt the credentials".to_owned()))
+        })
+        .transpose()
+}
+
+impl Netmount {
+    /// Connects to the server to check the credentials and find `root`, the login directory
+    /// by default, then remembers the mount
+    pub async fn create(
+        conn: &PgConnection,
+        key: &SecretKey,
+        user_id: uuid::Uuid,
+        config: Config,
+        root: Option<PathBuf>,
+    ) -> Result<Self> {
+        use crate::schema::netmounts::dsl;
+
+        let (password, private_key) = match &config.auth {
+            Auth::Password(password) => (Some(password.clone()), None),
+            Auth::Key { private_key, passphrase } => (passphrase.clone(), Some(private_key.clone())),
+        };
+        let (host, port, username) = (config.host.clone(), config.port, config.user.clone());
+        let (host_key, root) = Sftp::probe(config, root).await?;
+
+        diesel::insert_into(dsl::netmounts)
+            .values(&NewNetmount {
+                user_id,
+                host: &host,
+                port: i32::from(port),
+                username: &username,
+                password: encrypt(key, password.as_deref())?,
+                private_key: encrypt(key, private_key.as_deref())?,
+                host_key: &host_key,
+                root: &root.to_string_lossy(),
+            })
+            .get_result::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    pub fn list(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Vec<Self>> {
+        use crate::schema::netmounts::dsl;
+
+        dsl::netmounts
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::created_at.asc())
+            .load::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    /// The user's netmounts among `ids`, in that order
+    pub fn find_all(conn: &PgConnection, user_id: uuid::Uuid, ids: &[uuid::Uuid]) -> Result<Vec<Self>> {
+        use crate::schema::netmounts::dsl;
+
+        let mut mounts = dsl::netmounts
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::id.eq_any(ids))
+            .load::<Self>(conn)?;
+        mounts.sort_by_key(|mount| ids.iter().position(|id| *id == mount.id));
+        Ok(mounts)
+    }
+
+    pub fn find(conn: &PgConnection, user_id: uuid::Uuid, id: uuid::Uuid) -> Result<Self> {
+        Self::find_all(conn, user_id, &[id])?
+            .pop()
+            .ok_or(Error::UserError(UserError::NotFound))
+    }
+
+    /// Forgets the mount along with its credentials
+    pub fn delete(self, conn: &PgConnection) -> Result<()> {
+        use crate::schema::netmounts::dsl;
+
+        diesel::delete(dsl::netmounts.filter(dsl::id.eq(self.id)).filter(dsl::user_id.eq(self.user_id)))
+            .execute(conn)?;
+        Sftp::unmount(self.id);
+        Ok(())
+    }
+
+    fn config(&self, key: &SecretKey) -> Result<Config> {
+        let password = decrypt(key, &self.password)?;
+        let auth = match decrypt(key, &self.private_key)? {
+            Some(private_key) => Auth::Key {
+                private_key,
+                passphrase: password,
+            },
+            None => Auth::Password(password.unwrap_or_default()),
+        };
+        Ok(Config {
+            host: self.host.clone(),
+            port: self.port as u16,
+            user: self.username.clone(),
+            auth,
+            host_key: Some(self.host_key.clone()),
+        })
+    }
+
+    /// The mount as a volume, connected on its first use
+    pub fn volume(&self, key: &SecretKey) -> Result<Volume> {
+        Sftp::mount(self.id, self.config(key)?);
+        let name = format!("{}@{}", self.username, self.host);
+        Ok(Volume {
+            driver: Driver::Sftp,
+            netmount: Some(self.id),
+            ..Volume::new(&self.root, &Volume::netmount_id(self.id), &name, Access::Write)
+        })
+    }
+}
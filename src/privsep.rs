@@ -0,0 +1,657 @@
+//! Filesystem access on behalf of mapped Unix accounts.
+//!
+//! With `OS_USER_MODE=helper` the server spawns `arca fs-helper` while it still runs as
+//! root and then drops to `SERVER_USER`. The two talk over a Unix socket handed to the
+//! helper as its stdin: one JSON request per line, answered by one JSON reply per line. The
+//! helper forks a child for each request which switches to the uid/gid of the requested
+//! account and performs the operation, so every file is accessed with the permissions of
+//! that account. File contents never pass through the helper: the child opens the file and
+//! its descriptor is sent back along with the reply, the server then reads or writes it
+//! directly. `OS_USER_MODE=mock` runs the same helper without switching users, which works
+//! unprivileged and is meant for testing.
+
+use crate::error::{Error, Result};
+use actix_web::error::BlockingError;
+use actix_web::web;
+use nix::errno::Errno;
+use nix::sys::socket::{self, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType};
+use nix::sys::uio::IoVec;
+use nix::sys::wait::waitpid;
+use nix::unistd::{self, ForkResult, Uid};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+const MESSAGE_CHUNK: usize = 64 * 1024;
+
+/// The parts of a file's metadata the finder needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stat {
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: u64,
+    pub readonly: bool,
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl From<&Metadata> for Stat {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_secs()),
+            readonly: metadata.permissions().readonly(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Op {
+    Stat(PathBuf),
+    ReadDir(PathBuf),
+    CreateDir(PathBuf),
+    CreateDirAll(PathBuf),
+    SetMode(PathBuf, u32),
+    Rename(PathBuf, PathBuf),
+    RemoveAll(PathBuf),
+    Size(PathBuf),
+    /// Opens a file for reading and passes it on
+    Open(PathBuf),
+    /// Creates a new file, which must not exist yet, and passes it on for writing
+    Create(PathBuf),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    user: String,
+    op: Op,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Reply {
+    Ok(serde_json::Value),
+    Err { errno: Option<i32>, message: String },
+}
+
+impl From<io::Result<serde_json::Value>> for Reply {
+    fn from(result: io::Result<serde_json::Value>) -> Self {
+        match result {
+            Ok(value) => Reply::Ok(value),
+            Err(e) => Reply::Err {
+                errno: e.raw_os_error(),
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+fn other(e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn nix_error(e: nix::Error) -> io::Error {
+    match e.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => other(e),
+    }
+}
+
+fn to_value(value: impl serde::Serialize) -> io::Result<serde_json::Value> {
+    serde_json::to_value(value).map_err(other)
+}
+
+/// Writes a message to the socket, passing `file` along with its first byte
+fn send_message(socket: RawFd, data: &[u8], file: Option<RawFd>) -> io::Result<()> {
+    let fds = file.into_iter().collect::<Vec<_>>();
+    let rights = [ControlMessage::ScmRights(&fds)];
+    let cmsgs: &[ControlMessage] = if fds.is_empty() { &[] } else { &rights };
+    let sent = socket::sendmsg(socket, &[IoVec::from_slice(data)], cmsgs, MsgFlags::empty(), None)
+        .map_err(nix_error)?;
+
+    let mut rest = &data[sent..];
+    while !rest.is_empty() {
+        let written = unistd::write(socket, rest).map_err(nix_error)?;
+        rest = &rest[written..];
+    }
+    Ok(())
+}
+
+/// Reads a message up to the end of its line, along with a file passed with it. Returns
+/// `None` once the other side closed the socket.
+fn receive_message(socket: RawFd) -> io::Result<Option<(Vec<u8>, Option<File>)>> {
+    let mut message = Vec::new();
+    let mut file = None;
+    let mut buf = vec![0; MESSAGE_CHUNK];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+
+    while message.last() != Some(&b'\n') {
+        let (len, fds) = {
+            let received = socket::recvmsg(
+                socket,
+                &[IoVec::from_mut_slice(&mut buf)],
+                Some(&mut cmsg),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )
+            .map_err(nix_error)?;
+            let fds = received
+                .cmsgs()
+                .filter_map(|cmsg| match cmsg {
+                    ControlMessageOwned::ScmRights(fds) => Some(fds),
+                    _ => None,
+                })
+                .flatten()
+                .collect::<Vec<_>>();
+            (received.bytes, fds)
+        };
+        // anything beyond the one expected file is closed right away
+        for fd in fds {
+            let received = unsafe { File::from_raw_fd(fd) };
+            file.get_or_insert(received);
+        }
+        if len == 0 {
+            if message.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        message.extend_from_slice(&buf[..len]);
+    }
+    Ok(Some((message, file)))
+}
+
+/// Total size of a file or everything below a directory, without following symlinks
+fn size_of(path: &Path) -> io::Result<u64> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += size_of(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+/// Performs an operation as whichever user the process currently runs as, returning the
+/// file it opened if any
+fn perform(op: &Op) -> io::Result<(serde_json::Value, Option<File>)> {
+    let value = match op {
+        Op::Stat(path) => to_value(Stat::from(&std::fs::metadata(path)?)),
+        Op::ReadDir(path) => {
+            let mut entries = Vec::new();
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                entries.push((name, Stat::from(&entry.metadata()?)));
+            }
+            to_value(entries)
+        }
+        Op::CreateDir(path) => to_value(std::fs::create_dir(path)?),
+        Op::CreateDirAll(path) => to_value(std::fs::create_dir_all(path)?),
+        Op::Rename(from, to) => to_value(std::fs::rename(from, to)?),
+        Op::RemoveAll(path) => to_value(remove_path(path)?),
+        Op::Size(path) => to_value(size_of(path)?),
+        Op::SetMode(path, mode) => {
+            to_value(std::fs::set_permissions(path, std::fs::Permissions::from_mode(*mode))?)
+        }
+        Op::Open(path) => return Ok((serde_json::Value::Null, Some(File::open(path)?))),
+        Op::Create(path) => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o644)
+                .open(path)?;
+            return Ok((serde_json::Value::Null, Some(file)));
+        }
+    };
+    Ok((value?, None))
+}
+
+/// Looks up a mapped account, refusing root
+pub fn os_user(name: &str) -> io::Result<unistd::User> {
+    let user = unistd::User::from_name(name)
+        .map_err(other)?
+        .ok_or_else(|| other(format!("No such user: {}", name)))?;
+    if user.uid.is_root() {
+        return Err(io::Error::from_raw_os_error(Errno::EPERM as i32));
+    }
+    Ok(user)
+}
+
+/// Switches the calling process to the account for good
+fn become_user(user: &unistd::User) -> io::Result<()> {
+    let name = CString::new(user.name.as_str()).map_err(other)?;
+    unistd::initgroups(&name, user.gid).map_err(other)?;
+    unistd::setgid(user.gid).map_err(other)?;
+    unistd::setuid(user.uid).map_err(other)?;
+    Ok(())
+}
+
+/// Runs a request in a forked child, which never returns to the caller's state. The child
+/// answers over a socket so it can pass on the file it opened.
+fn handle(request: &Request, mock: bool) -> io::Result<(Reply, Option<File>)> {
+    let user = os_user(&request.user)?;
+    let (parent_socket, child_socket) =
+        socket::socketpair(AddressFamily::Unix, SockType::Stream, None, SockFlag::SOCK_CLOEXEC)
+            .map_err(nix_error)?;
+    let parent_socket = unsafe { UnixStream::from_raw_fd(parent_socket) };
+    let child_socket = unsafe { UnixStream::from_raw_fd(child_socket) };
+
+    match unistd::fork().map_err(nix_error)? {
+        ForkResult::Child => {
+            drop(parent_socket);
+            let result = if mock { Ok(()) } else { become_user(&user) }.and_then(|_| perform(&request.op));
+            let (reply, file) = match result {
+                Ok((value, file)) => (Reply::Ok(value), file),
+                Err(e) => (Reply::from(Err(e)), None),
+            };
+            let sent = serde_json::to_vec(&reply).map_err(other).and_then(|mut message| {
+                message.push(b'\n');
+                send_message(child_socket.as_raw_fd(), &message, file.as_ref().map(AsRawFd::as_raw_fd))
+            });
+            std::process::exit(if sent.is_ok() { 0 } else { 1 })
+        }
+        ForkResult::Parent { child } => {
+            drop(child_socket);
+            let received = receive_message(parent_socket.as_raw_fd());
+            waitpid(child, None).map_err(nix_error)?;
+            let (message, file) = received?.ok_or_else(|| other("Helper child failed"))?;
+            let reply = serde_json::from_slice(&message).map_err(|_| other("Helper child failed"))?;
+            Ok((reply, file))
+        }
+    }
+}
+
+/// Main loop of `arca fs-helper`, serving the server on the socket it got as stdin
+pub fn serve(mock: bool) -> io::Result<()> {
+    let socket = io::stdin().as_raw_fd();
+
+    while let Some((line, _)) = receive_message(socket)? {
+        let (reply, file) = match serde_json::from_slice::<Request>(&line) {
+            Ok(request) => handle(&request, mock).unwrap_or_else(|e| (Reply::from(Err(e)), None)),
+            Err(e) => (Reply::from(Err(other(e))), None),
+        };
+        let mut message = serde_json::to_vec(&reply).map_err(other)?;
+        message.push(b'\n');
+        send_message(socket, &message, file.as_ref().map(AsRawFd::as_raw_fd))?;
+    }
+    Ok(())
+}
+
+/// Connection to the helper process, shared by all workers. Only requests and replies go
+/// through it, files are read and written by the workers themselves.
+pub struct Helper {
+    process: Mutex<(Child, UnixStream)>,
+}
+
+impl fmt::Debug for Helper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Helper")
+    }
+}
+
+impl Helper {
+    /// Starts the helper if `OS_USER_MODE` asks for one and drops the server's privileges
+    /// to `SERVER_USER` afterwards. A server running as root refuses to start without it.
+    pub fn from_env() -> Option<Arc<Self>> {
+        let mock = match std::env::var("OS_USER_MODE").as_deref() {
+            Ok("helper") => false,
+            Ok("mock") => true,
+            Ok("off") | Err(_) => return None,
+            Ok(other) => panic!("Unknown OS_USER_MODE: {}", other),
+        };
+
+        let mut command = Command::new(std::env::current_exe().expect("Could not find the arca binary"));
+        command.arg("fs-helper");
+        if mock {
+            command.arg("--mock");
+        }
+        let helper = Self::spawn(command).expect("Could not start the file system helper");
+
+        match std::env::var("SERVER_USER") {
+            Ok(name) => {
+                let user = os_user(&name).expect("Could not find SERVER_USER");
+                become_user(&user).expect("Could not drop privileges to SERVER_USER");
+            }
+            Err(_) if Uid::effective().is_root() => {
+                panic!("SERVER_USER must be set, the server would keep running as root")
+            }
+            Err(_) => {}
+        }
+        Some(helper)
+    }
+
+    /// Runs `command` as the helper, connected through a socket as its stdin
+    fn spawn(mut command: Command) -> io::Result<Arc<Self>> {
+        let (socket, helper_socket) = UnixStream::pair()?;
+        let child = command
+            .stdin(unsafe { Stdio::from_raw_fd(helper_socket.into_raw_fd()) })
+            .spawn()?;
+        Ok(Arc::new(Self {
+            process: Mutex::new((child, socket)),
+        }))
+    }
+
+    /// Sends a request and waits for its reply, holding the connection only meanwhile
+    fn exchange(&self, user: &str, op: Op) -> io::Result<(serde_json::Value, Option<File>)> {
+        let mut request = serde_json::to_vec(&Request {
+            user: user.to_owned(),
+            op,
+        })
+        .map_err(other)?;
+        request.push(b'\n');
+
+        let process = self.process.lock().map_err(|_| other("Helper lock poisoned"))?;
+        let socket = process.1.as_raw_fd();
+        send_message(socket, &request, None)?;
+        let received = receive_message(socket)?;
+        drop(process);
+
+        let (message, file) = received.ok_or_else(|| other("File system helper exited"))?;
+        match serde_json::from_slice(&message).map_err(other)? {
+            Reply::Ok(value) => Ok((value, file)),
+            Reply::Err { errno: Some(errno), .. } => Err(io::Error::from_raw_os_error(errno)),
+            Reply::Err { message, .. } => Err(other(message)),
+        }
+    }
+
+    fn call<T: DeserializeOwned>(&self, user: &str, op: Op) -> io::Result<T> {
+        let (value, _) = self.exchange(user, op)?;
+        serde_json::from_value(value).map_err(other)
+    }
+
+    fn open(&self, user: &str, op: Op) -> io::Result<File> {
+        let (_, file) = self.exchange(user, op)?;
+        file.ok_or_else(|| other("File system helper passed no file"))
+    }
+}
+
+/// The account file operations on a volume are performed as
+#[derive(Debug, Clone)]
+pub struct RunAs {
+    pub(crate) helper: Arc<Helper>,
+    pub(crate) user: String,
+}
+
+impl RunAs {
+    async fn call<T: DeserializeOwned + Send + 'static>(&self, op: Op) -> Result<T> {
+        let run_as = self.clone();
+        blocking(move || run_as.helper.call(&run_as.user, op)).await
+    }
+
+    async fn open(&self, op: Op) -> Result<tokio::fs::File> {
+        let run_as = self.clone();
+        let file = blocking(move || run_as.helper.open(&run_as.user, op)).await?;
+        Ok(tokio::fs::File::from_std(file))
+    }
+}
+
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> io::Result<T> + Send + 'static) -> Result<T> {
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => Error::IoError(e),
+        BlockingError::Canceled => Error::Other("File system operation canceled".to_owned()),
+    })
+}
+
+pub async fn stat(run_as: Option<&RunAs>, path: &Path) -> Result<Stat> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::Stat(path.to_owned())).await,
+        None => Ok(Stat::from(&tokio::fs::metadata(path).await?)),
+    }
+}
+
+pub async fn read_dir(run_as: Option<&RunAs>, path: &Path) -> Result<Vec<(String, Stat)>> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::ReadDir(path.to_owned())).await,
+        None => {
+            let mut dir = tokio::fs::read_dir(path).await?;
+            let mut entries = Vec::new();
+            while let Some(entry) = dir.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                entries.push((name, Stat::from(&entry.metadata().await?)));
+            }
+            Ok(entries)
+        }
+    }
+}
+
+pub async fn create_dir(run_as: Option<&RunAs>, path: &Path) -> Result<()> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::CreateDir(path.to_owned())).await,
+        None => Ok(tokio::fs::create_dir(path).await?),
+    }
+}
+
+pub async fn set_mode(run_as: Option<&RunAs>, path: &Path, mode: u32) -> Result<()> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::SetMode(path.to_owned(), mode)).await,
+        None => Ok(tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?),
+    }
+}
+
+pub async fn create_dir_all(run_as: Option<&RunAs>, path: &Path) -> Result<()> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::CreateDirAll(path.to_owned())).await,
+        None => Ok(tokio::fs::create_dir_all(path).await?),
+    }
+}
+
+pub async fn rename(run_as: Option<&RunAs>, from: &Path, to: &Path) -> Result<()> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::Rename(from.to_owned(), to.to_owned())).await,
+        None => Ok(tokio::fs::rename(from, to).await?),
+    }
+}
+
+/// Removes a file or a directory with everything in it
+pub async fn remove_all(run_as: Option<&RunAs>, path: &Path) -> Result<()> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::RemoveAll(path.to_owned())).await,
+        None => {
+            let path = path.to_owned();
+            blocking(move || remove_path(&path)).await
+        }
+    }
+}
+
+/// Total size of a file or directory in bytes
+pub async fn size(run_as: Option<&RunAs>, path: &Path) -> Result<u64> {
+    match run_as {
+        Some(run_as) => run_as.call(Op::Size(path.to_owned())).await,
+        None => {
+            let path = path.to_owned();
+            blocking(move || size_of(&path)).await
+        }
+    }
+}
+
+/// Opens the file at `path` for reading
+pub async fn open(run_as: Option<&RunAs>, path: &Path) -> Result<tokio::fs::File> {
+    match run_as {
+        Some(run_as) => run_as.open(Op::Open(path.to_owned())).await,
+        None => Ok(tokio::fs::File::open(path).await?),
+    }
+}
+
+/// Creates a new file at `path` for writing, failing if it exists
+pub async fn create(run_as: Option<&RunAs>, path: &Path) -> Result<tokio::fs::File> {
+    match run_as {
+        Some(run_as) => run_as.open(Op::Create(path.to_owned())).await,
+        None => Ok(tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Local, Storage};
+    use futures::stream::{self, StreamExt};
+
+    /// Any unprivileged account works, mock mode does not switch to it
+    const USER: &str = "nobody";
+    /// Set for the copy of the test binary serving as the helper
+    const HELPER_ENV: &str = "ARCA_TEST_FS_HELPER";
+
+    /// Not a test of its own: the helper the other tests spawn, a copy of the test binary
+    /// running only this in mock mode, so forks happen away from the other tests' threads
+    #[test]
+    #[ignore]
+    fn fs_helper() {
+        if std::env::var_os(HELPER_ENV).is_some() {
+            serve(true).unwrap();
+        }
+    }
+
+    fn spawn_helper() -> Arc<Helper> {
+        let mut command = Command::new(std::env::current_exe().unwrap());
+        command
+            .args(&["privsep::tests::fs_helper", "--exact", "--ignored", "--test-threads=1"])
+            .env(HELPER_ENV, "1")
+            .stdout(Stdio::null());
+        Helper::spawn(command).unwrap()
+    }
+
+    fn storage() -> (Arc<Helper>, Local) {
+        let helper = spawn_helper();
+        let run_as = RunAs {
+            helper: helper.clone(),
+            user: USER.to_owned(),
+        };
+        (helper, Local::new(Some(run_as)))
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arca-privsep-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[actix_rt::test]
+    async fn writes_reads_and_lists_files() {
+        let (_, storage) = storage();
+        let dir = scratch_dir("files");
+        let file = dir.join("a.txt");
+
+        storage.write_all(&file, b"hello").await.unwrap();
+        assert_eq!(storage.read_all(&file).await.unwrap(), b"hello");
+
+        let stat = storage.stat(&file).await.unwrap();
+        assert!(!stat.is_dir);
+        assert_eq!(stat.len, 5);
+
+        storage.mkdir(&dir.join("b/c"), true).await.unwrap();
+        let mut entries = storage.list(&dir).await.unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let names: Vec<_> = entries.iter().map(|(name, stat)| (name.as_str(), stat.is_dir)).collect();
+        assert_eq!(names, vec![("a.txt", false), ("b", true)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn streams_large_files() {
+        let (_, storage) = storage();
+        let dir = scratch_dir("large");
+        let file = dir.join("large");
+
+        let chunk = vec![7; MESSAGE_CHUNK];
+        let chunks = (0..64).map(|_| Ok(actix_web::web::Bytes::from(chunk.clone()))).collect::<Vec<_>>();
+        let written = storage.write(&file, stream::iter(chunks).boxed_local()).await.unwrap();
+        assert_eq!(written, 64 * MESSAGE_CHUNK as u64);
+
+        let mut read = 0;
+        let mut data = storage.read(&file).await.unwrap();
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.iter().all(|&byte| byte == 7));
+            read += chunk.len();
+        }
+        assert_eq!(read, 64 * MESSAGE_CHUNK);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn renames_sizes_and_removes() {
+        let (_, storage) = storage();
+        let dir = scratch_dir("tree");
+        storage.mkdir(&dir.join("sub"), false).await.unwrap();
+        storage.write_all(&dir.join("sub/a"), b"1234").await.unwrap();
+        storage.write_all(&dir.join("b"), b"56").await.unwrap();
+        storage.rename(&dir.join("b"), &dir.join("sub/b")).await.unwrap();
+
+        assert_eq!(storage.size(&dir.join("sub")).await.unwrap(), 6);
+        assert!(!dir.join("b").exists());
+
+        storage.set_mode(&dir.join("sub/a"), 0o600).await.unwrap();
+        let mode = std::fs::metadata(dir.join("sub/a")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        storage.remove(&dir.join("sub")).await.unwrap();
+        assert!(!dir.join("sub").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_the_errno() {
+        let helper = spawn_helper();
+        let dir = scratch_dir("errno");
+        let err = helper.call::<Stat>(USER, Op::Stat(dir.join("missing"))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = helper.open(USER, Op::Open(dir.join("missing"))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn writes_leave_hard_links_alone() {
+        let (_, storage) = storage();
+        let dir = scratch_dir("links");
+        std::fs::write(dir.join("blob"), "stored").unwrap();
+        std::fs::hard_link(dir.join("blob"), dir.join("file")).unwrap();
+
+        storage.write_all(&dir.join("file"), b"changed").await.unwrap();
+        assert_eq!(std::fs::read(dir.join("blob")).unwrap(), b"stored");
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"changed");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_root() {
+        let helper = spawn_helper();
+        let err = helper.call::<Stat>("root", Op::Stat(PathBuf::from("/"))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -1,8 +1,236 @@
+table! {
+    api_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scope -> Varchar,
+        volume_path -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    audit_events (id) {
+        id -> Int8,
+        user_id -> Nullable<Uuid>,
+        actor_id -> Nullable<Uuid>,
+        action -> Varchar,
+        detail -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    blobs (owner, hash) {
+        hash -> Varchar,
+        size -> Int8,
+        device -> Int8,
+        inode -> Int8,
+        refs -> Int8,
+        created_at -> Timestamptz,
+        last_used -> Timestamptz,
+        owner -> Varchar,
+    }
+}
+
+table! {
+    email_changes (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        new_email -> Varchar,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    export_jobs (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        status -> Varchar,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    file_versions (id) {
+        id -> Uuid,
+        store_path -> Varchar,
+        path -> Varchar,
+        size -> Int8,
+        mtime -> Timestamptz,
+        author -> Nullable<Uuid>,
+        replaced_by -> Nullable<Uuid>,
+        hash -> Varchar,
+        created_at -> Timestamptz,
+        os_user -> Nullable<Varchar>,
+        driver -> Varchar,
+    }
+}
+
+table! {
+    folder_grants (id) {
+        id -> Uuid,
+        owner_id -> Uuid,
+        recipient_id -> Uuid,
+        path -> Varchar,
+        device -> Int8,
+        inode -> Int8,
+        access -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    group_members (group_id, user_id) {
+        group_id -> Uuid,
+        user_id -> Uuid,
+        role -> Varchar,
+    }
+}
+
+table! {
+    groups (id) {
+        id -> Uuid,
+        name -> Varchar,
+        created_at -> Timestamptz,
+        quota_bytes -> Nullable<Int8>,
+    }
+}
+
+table! {
+    netmounts (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        host -> Varchar,
+        port -> Int4,
+        username -> Varchar,
+        password -> Nullable<Bytea>,
+        private_key -> Nullable<Bytea>,
+        host_key -> Varchar,
+        root -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    password_resets (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Uuid,
+        code_hash -> Varchar,
+        used -> Bool,
+    }
+}
+
+table! {
+    shares (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token -> Varchar,
+        pass_hash -> Nullable<Varchar>,
+        path -> Varchar,
+        device -> Int8,
+        inode -> Int8,
+        mode -> Varchar,
+        expires_at -> Nullable<Timestamptz>,
+        max_downloads -> Nullable<Int4>,
+        downloads -> Int4,
+        created_at -> Timestamptz,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    trash_entries (id) {
+        id -> Uuid,
+        volume_path -> Varchar,
+        original_path -> Varchar,
+        deleted_by -> Nullable<Uuid>,
+        deleted_at -> Timestamptz,
+        size -> Int8,
+        is_dir -> Bool,
+        os_user -> Nullable<Varchar>,
+        driver -> Varchar,
+    }
+}
+
+table! {
+    user_identities (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        issuer -> Varchar,
+        subject -> Varchar,
+        email -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    user_totp (user_id) {
+        user_id -> Uuid,
+        secret -> Bytea,
+        enabled -> Bool,
+        last_step -> Int8,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     users (id) {
         id -> Uuid,
         email -> Varchar,
         pass_hash -> Varchar,
         volumes -> Nullable<Array<Text>>,
+        role -> Varchar,
+        session_version -> Int4,
+        deleted_at -> Nullable<Timestamptz>,
+        disabled -> Bool,
+        quota_bytes -> Nullable<Int8>,
+        os_user -> Nullable<Varchar>,
     }
 }
+
+joinable!(api_tokens -> users (user_id));
+joinable!(email_changes -> users (user_id));
+joinable!(export_jobs -> users (user_id));
+joinable!(group_members -> groups (group_id));
+joinable!(group_members -> users (user_id));
+joinable!(netmounts -> users (user_id));
+joinable!(password_resets -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(shares -> users (user_id));
+joinable!(trash_entries -> users (deleted_by));
+joinable!(user_identities -> users (user_id));
+joinable!(user_totp -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_events,
+    blobs,
+    email_changes,
+    export_jobs,
+    file_versions,
+    folder_grants,
+    group_members,
+    groups,
+    netmounts,
+    password_resets,
+    recovery_codes,
+    shares,
+    trash_entries,
+    user_identities,
+    user_totp,
+    users,
+);
@@ -0,0 +1,270 @@
+use super::error::{Error, Result};
+use super::privsep::Stat;
+use super::schema::shares;
+use super::user::error::Error as UserError;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+
+const TOKEN_LEN: usize = 32;
+
+/// Wrong passwords in a row after which a share can't be unlocked for `LOCKOUT_MINS`
+const MAX_ATTEMPTS: i32 = 5;
+const LOCKOUT_MINS: i64 = 15;
+
+/// What visitors of a share link may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Download the file or browse and download the folder
+    Read,
+    /// Only add new files to the folder ("file drop")
+    Upload,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Read => "read",
+            Mode::Upload => "upload",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Mode::Read),
+            "upload" => Ok(Mode::Upload),
+            _ => Err(Error::InvalidParams),
+        }
+    }
+}
+
+/// A public link to a file or folder. The target is remembered by its absolute path and
+/// inode, so a share stops working instead of exposing whatever later appears at the same
+/// path once the target is moved or deleted. Files arca writes are replaced by new ones,
+/// their shares are moved on to the new inode.
+#[derive(Queryable, Serialize)]
+pub struct Share {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) token: String,
+    #[serde(skip)]
+    pass_hash: Option<String>,
+    pub(crate) path: String,
+    #[serde(skip)]
+    device: i64,
+    #[serde(skip)]
+    inode: i64,
+    mode: String,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    pub(crate) max_downloads: Option<i32>,
+    pub(crate) downloads: i32,
+    pub(crate) created_at: DateTime<Utc>,
+    #[serde(skip)]
+    failed_attempts: i32,
+    #[serde(skip)]
+    locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "shares"]
+struct NewShare<'a> {
+    user_id: uuid::Uuid,
+    token: String,
+    pass_hash: Option<String>,
+    path: &'a str,
+    device: i64,
+    inode: i64,
+    mode: &'a str,
+    expires_at: Option<DateTime<Utc>>,
+    max_downloads: Option<i32>,
+}
+
+impl Share {
+    /// Shares the file or folder at the absolute `path`, which `stat` describes
+    pub fn create(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        path: &Path,
+        stat: &Stat,
+        mode: Mode,
+        password: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+        max_downloads: Option<i32>,
+    ) -> Result<Self> {
+        use crate::schema::shares::dsl;
+
+        if mode == Mode::Upload && !stat.is_dir {
+            return Err(Error::InvalidParams);
+        }
+        if expires_at.map_or(false, |expires_at| expires_at <= Utc::now())
+            || max_downloads.map_or(false, |max| max < 1)
+        {
+            return Err(Error::InvalidParams);
+        }
+        let pass_hash = match password.filter(|password| !password.is_empty()) {
+            Some(password) => Some(bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(UserError::from)?),
+            None => None,
+        };
+
+        let token = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .collect::<String>();
+
+        diesel::insert_into(dsl::shares)
+            .values(&NewShare {
+                user_id,
+                token,
+                pass_hash,
+                path: &path.to_string_lossy(),
+                device: stat.dev as i64,
+                inode: stat.ino as i64,
+                mode: mode.as_str(),
+                expires_at,
+                max_downloads,
+            })
+            .get_result::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    pub fn list(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Vec<Self>> {
+        use crate::schema::shares::dsl;
+
+        dsl::shares
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::created_at.asc())
+            .load::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    pub fn revoke(conn: &PgConnection, user_id: uuid::Uuid, id: uuid::Uuid) -> Result<Self> {
+        use crate::schema::shares::dsl;
+
+        diesel::delete(
+            dsl::shares
+                .filter(dsl::id.eq(id))
+                .filter(dsl::user_id.eq(user_id)),
+        )
+        .get_result::<Self>(conn)
+        .optional()?
+        .ok_or(Error::UserError(UserError::NotFound))
+    }
+
+    /// Looks up a share for a visitor, expired shares count as gone
+    pub fn find_by_token(conn: &PgConnection, token: &str) -> Result<Self> {
+        use crate::schema::shares::dsl;
+
+        let share = dsl::shares
+            .filter(dsl::token.eq(token))
+            .first::<Self>(conn)
+            .optional()?
+            .ok_or(Error::UserError(UserError::NotFound))?;
+        if share.expires_at.map_or(false, |expires_at| expires_at <= Utc::now()) {
+            return Err(Error::Gone);
+        }
+        Ok(share)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode.parse().unwrap_or(Mode::Read)
+    }
+
+    pub fn has_password(&self) -> bool {
+        self.pass_hash.is_some()
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        self.pass_hash
+            .as_ref()
+            .map_or(true, |hash| bcrypt::verify(password, hash).unwrap_or(false))
+    }
+
+    /// Checks the password of a visitor unlocking the share. After `MAX_ATTEMPTS` wrong
+    /// passwords in a row no password is accepted for `LOCKOUT_MINS`.
+    pub fn check_password(&self, conn: &PgConnection, password: &str) -> Result<()> {
+        use crate::schema::shares::dsl;
+
+        let now = Utc::now();
+        if self.locked_until.map_or(false, |until| until > now) {
+            return Err(UserError::TooManyAttempts.into());
+        }
+
+        if self.verify_password(password) {
+            if self.failed_attempts > 0 {
+                diesel::update(dsl::shares.find(self.id))
+                    .set(dsl::failed_attempts.eq(0))
+                    .execute(conn)?;
+            }
+            return Ok(());
+        }
+        // counted in the database so concurrent attempts add up
+        let failed = diesel::update(dsl::shares.find(self.id))
+            .set(dsl::failed_attempts.eq(dsl::failed_attempts + 1))
+            .returning(dsl::failed_attempts)
+            .get_result::<i32>(conn)?;
+        if failed >= MAX_ATTEMPTS {
+            diesel::update(dsl::shares.find(self.id))
+                .set((
+                    dsl::failed_attempts.eq(0),
+                    dsl::locked_until.eq(now + Duration::minutes(LOCKOUT_MINS)),
+                ))
+                .execute(conn)?;
+        }
+        Err(UserError::NotAuthenticated.into())
+    }
+
+    /// Whether `stat` still describes the shared file
+    pub fn is_target(&self, stat: &Stat) -> bool {
+        stat.dev as i64 == self.device && stat.ino as i64 == self.inode
+    }
+
+    /// Moves the shares of the file at the absolute `path`, which `old` described, on to the
+    /// file that replaced it
+    pub fn follow_replacement(conn: &PgConnection, path: &Path, old: &Stat, new: &Stat) -> Result<()> {
+        use crate::schema::shares::dsl;
+
+        let path = path.to_string_lossy();
+        diesel::update(
+            dsl::shares
+                .filter(dsl::path.eq(&*path))
+                .filter(dsl::device.eq(old.dev as i64))
+                .filter(dsl::inode.eq(old.ino as i64)),
+        )
+        .set((dsl::device.eq(new.dev as i64), dsl::inode.eq(new.ino as i64)))
+        .execute(conn)?;
+        Ok(())
+    }
+
+    /// Whether the download limit, if any, is not reached yet
+    pub fn can_download(&self) -> bool {
+        self.max_downloads.map_or(true, |max| self.downloads < max)
+    }
+
+    /// Counts a download, failing once the limit is reached
+    pub fn count_download(&self, conn: &PgConnection) -> Result<()> {
+        use crate::schema::shares::dsl;
+
+        let counted = diesel::update(
+            dsl::shares
+                .filter(dsl::id.eq(self.id))
+                .filter(sql::<Bool>("(max_downloads IS NULL OR downloads < max_downloads)")),
+        )
+        .set(dsl::downloads.eq(dsl::downloads + 1))
+        .execute(conn)?;
+        if counted == 0 {
+            return Err(Error::Gone);
+        }
+        Ok(())
+    }
+}
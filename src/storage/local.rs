@@ -0,0 +1,111 @@
+use super::{temp_path, ByteStream, Stat, Storage};
+use crate::error::Result;
+use crate::privsep::{self, RunAs};
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The local filesystem, accessed through the fs helper when `run_as` is set
+#[derive(Debug, Clone)]
+pub struct Local {
+    run_as: Option<RunAs>,
+}
+
+impl Local {
+    pub fn new(run_as: Option<RunAs>) -> Self {
+        Self { run_as }
+    }
+}
+
+/// Reads a file in chunks, ending after the first error
+fn chunks(file: tokio::fs::File) -> ByteStream {
+    stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(file)))
+            }
+            Err(e) => Some((Err(e.into()), None)),
+        }
+    })
+    .boxed_local()
+}
+
+#[async_trait(?Send)]
+impl Storage for Local {
+    async fn stat(&self, path: &Path) -> Result<Stat> {
+        privsep::stat(self.run_as.as_ref(), path).await
+    }
+
+    async fn list(&self, path: &Path) -> Result<Vec<(String, Stat)>> {
+        privsep::read_dir(self.run_as.as_ref(), path).await
+    }
+
+    async fn read(&self, path: &Path) -> Result<ByteStream> {
+        Ok(chunks(privsep::open(self.run_as.as_ref(), path).await?))
+    }
+
+    async fn write(&self, path: &Path, mut data: ByteStream) -> Result<u64> {
+        let tmp = temp_path(path);
+        let mut file = privsep::create(self.run_as.as_ref(), &tmp).await?;
+        let mut written = 0;
+        let mut result = Ok(());
+        while let Some(chunk) = data.next().await {
+            result = match chunk {
+                Ok(chunk) => {
+                    written += chunk.len() as u64;
+                    file.write_all(&chunk).await.map_err(Into::into)
+                }
+                Err(e) => Err(e),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        // tokio finishes writes in the background, they have to land before the rename
+        if result.is_ok() {
+            result = file.flush().await.map_err(Into::into);
+        }
+        drop(file);
+        if let Err(e) = result {
+            let _ = self.remove(&tmp).await;
+            return Err(e);
+        }
+        if let Err(e) = self.rename(&tmp, path).await {
+            let _ = self.remove(&tmp).await;
+            return Err(e);
+        }
+        Ok(written)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        privsep::rename(self.run_as.as_ref(), from, to).await
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        privsep::remove_all(self.run_as.as_ref(), path).await
+    }
+
+    async fn mkdir(&self, path: &Path, parents: bool) -> Result<()> {
+        if parents {
+            privsep::create_dir_all(self.run_as.as_ref(), path).await
+        } else {
+            privsep::create_dir(self.run_as.as_ref(), path).await
+        }
+    }
+
+    async fn size(&self, path: &Path) -> Result<u64> {
+        privsep::size(self.run_as.as_ref(), path).await
+    }
+
+    async fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        privsep::set_mode(self.run_as.as_ref(), path, mode).await
+    }
+}
@@ -0,0 +1,345 @@
+use super::{ByteStream, Stat, Storage};
+use crate::error::{Error, Result};
+use actix_web::web::{Bytes, BytesMut};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The tree all volumes on the memory driver live in, told apart by their paths
+static SHARED: Lazy<Arc<Memory>> = Lazy::new(|| Arc::new(Memory::new()));
+
+#[derive(Debug, Clone)]
+enum Node {
+    Dir { ino: u64, modified: u64 },
+    File { ino: u64, modified: u64, data: Bytes },
+}
+
+/// A file tree kept in memory, for tests and throwaway volumes
+#[derive(Debug)]
+pub struct Memory {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+    next_ino: AtomicU64,
+}
+
+fn error(kind: io::ErrorKind) -> Error {
+    io::Error::from(kind).into()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+impl Node {
+    fn stat(&self) -> Stat {
+        match self {
+            Node::Dir { ino, modified } => Stat {
+                is_dir: true,
+                len: 0,
+                modified: *modified,
+                readonly: false,
+                dev: 0,
+                ino: *ino,
+            },
+            Node::File { ino, modified, data } => Stat {
+                is_dir: false,
+                len: data.len() as u64,
+                modified: *modified,
+                readonly: false,
+                dev: 0,
+                ino: *ino,
+            },
+        }
+    }
+}
+
+impl Memory {
+    /// An empty tree holding only the root directory
+    pub fn new() -> Self {
+        let memory = Self {
+            nodes: Mutex::new(BTreeMap::new()),
+            next_ino: AtomicU64::new(1),
+        };
+        let root = memory.dir();
+        memory
+            .nodes
+            .lock()
+            .expect("Memory storage lock poisoned")
+            .insert(PathBuf::from("/"), root);
+        memory
+    }
+
+    pub fn shared() -> Arc<Self> {
+        SHARED.clone()
+    }
+
+    fn ino(&self) -> u64 {
+        self.next_ino.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn dir(&self) -> Node {
+        Node::Dir {
+            ino: self.ino(),
+            modified: now(),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, BTreeMap<PathBuf, Node>>> {
+        self.nodes
+            .lock()
+            .map_err(|_| Error::Other("Memory storage lock poisoned".to_owned()))
+    }
+
+    /// Fails unless the parent of `path` is a directory
+    fn check_parent(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Result<()> {
+        match path.parent().and_then(|parent| nodes.get(parent)) {
+            Some(Node::Dir { .. }) => Ok(()),
+            Some(Node::File { .. }) => Err(error(io::ErrorKind::InvalidInput)),
+            None => Err(error(io::ErrorKind::NotFound)),
+        }
+    }
+
+    /// `path` and everything below it
+    fn subtree(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Vec<PathBuf> {
+        nodes
+            .range(path.to_owned()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(path))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for Memory {
+    async fn stat(&self, path: &Path) -> Result<Stat> {
+        self.lock()?
+            .get(path)
+            .map(Node::stat)
+            .ok_or_else(|| error(io::ErrorKind::NotFound))
+    }
+
+    async fn list(&self, path: &Path) -> Result<Vec<(String, Stat)>> {
+        let nodes = self.lock()?;
+        match nodes.get(path) {
+            Some(Node::Dir { .. }) => {}
+            Some(Node::File { .. }) => return Err(error(io::ErrorKind::InvalidInput)),
+            None => return Err(error(io::ErrorKind::NotFound)),
+        }
+        Ok(nodes
+            .range(path.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(path))
+            .filter(|(key, _)| key.parent() == Some(path))
+            .map(|(key, node)| {
+                let name = key.file_name().unwrap_or_default().to_string_lossy().to_string();
+                (name, node.stat())
+            })
+            .collect())
+    }
+
+    async fn read(&self, path: &Path) -> Result<ByteStream> {
+        let data = match self.lock()?.get(path) {
+            Some(Node::File { data, .. }) => data.clone(),
+            Some(Node::Dir { .. }) => return Err(error(io::ErrorKind::InvalidInput)),
+            None => return Err(error(io::ErrorKind::NotFound)),
+        };
+        Ok(stream::once(async move { Ok(data) }).boxed_local())
+    }
+
+    async fn write(&self, path: &Path, mut data: ByteStream) -> Result<u64> {
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = data.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        let len = buf.len() as u64;
+
+        let mut nodes = self.lock()?;
+        Self::check_parent(&nodes, path)?;
+        if let Some(Node::Dir { .. }) = nodes.get(path) {
+            return Err(error(io::ErrorKind::InvalidInput));
+        }
+        let node = Node::File {
+            ino: self.ino(),
+            modified: now(),
+            data: buf.freeze(),
+        };
+        nodes.insert(path.to_owned(), node);
+        Ok(len)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut nodes = self.lock()?;
+        if !nodes.contains_key(from) {
+            return Err(error(io::ErrorKind::NotFound));
+        }
+        Self::check_parent(&nodes, to)?;
+        if to == from {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(error(io::ErrorKind::InvalidInput));
+        }
+        for key in Self::subtree(&nodes, to) {
+            nodes.remove(&key);
+        }
+        for key in Self::subtree(&nodes, from) {
+            if let Some(node) = nodes.remove(&key) {
+                let rest = key.strip_prefix(from).unwrap_or(&key);
+                nodes.insert(to.join(rest), node);
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        let mut nodes = self.lock()?;
+        let removed = Self::subtree(&nodes, path);
+        if removed.is_empty() {
+            return Err(error(io::ErrorKind::NotFound));
+        }
+        for key in removed {
+            nodes.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn mkdir(&self, path: &Path, parents: bool) -> Result<()> {
+        let mut nodes = self.lock()?;
+        if parents {
+            for ancestor in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+                match nodes.get(ancestor) {
+                    Some(Node::Dir { .. }) => {}
+                    Some(Node::File { .. }) => return Err(error(io::ErrorKind::AlreadyExists)),
+                    None => {
+                        let dir = self.dir();
+                        nodes.insert(ancestor.to_owned(), dir);
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        Self::check_parent(&nodes, path)?;
+        if nodes.contains_key(path) {
+            return Err(error(io::ErrorKind::AlreadyExists));
+        }
+        let dir = self.dir();
+        nodes.insert(path.to_owned(), dir);
+        Ok(())
+    }
+
+    async fn size(&self, path: &Path) -> Result<u64> {
+        let nodes = self.lock()?;
+        if !nodes.contains_key(path) {
+            return Err(error(io::ErrorKind::NotFound));
+        }
+        Ok(nodes
+            .range(path.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(path))
+            .map(|(_, node)| node.stat().len)
+            .sum())
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut nodes = self.lock()?;
+        Self::check_parent(&nodes, to)?;
+        if nodes.contains_key(to) {
+            return Err(error(io::ErrorKind::AlreadyExists));
+        }
+        let copied = Self::subtree(&nodes, from);
+        if copied.is_empty() {
+            return Err(error(io::ErrorKind::NotFound));
+        }
+        for key in copied {
+            let node = match nodes[&key].clone() {
+                Node::Dir { modified, .. } => Node::Dir { ino: self.ino(), modified },
+                Node::File { modified, data, .. } => Node::File {
+                    ino: self.ino(),
+                    modified,
+                    data,
+                },
+            };
+            let rest = key.strip_prefix(from).unwrap_or(&key);
+            nodes.insert(to.join(rest), node);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(memory: &Memory, path: &str) -> Vec<u8> {
+        memory.read_all(Path::new(path)).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn writes_need_a_parent_directory() {
+        let memory = Memory::new();
+        assert!(memory.write_all(Path::new("/a/b.txt"), b"x").await.is_err());
+        memory.mkdir(Path::new("/a"), false).await.unwrap();
+        memory.write_all(Path::new("/a/b.txt"), b"x").await.unwrap();
+        assert!(memory.mkdir(Path::new("/a/b.txt/c"), true).await.is_err());
+        assert_eq!(read(&memory, "/a/b.txt").await, b"x");
+    }
+
+    #[actix_rt::test]
+    async fn renames_whole_trees() {
+        let memory = Memory::new();
+        memory.mkdir(Path::new("/a/b"), true).await.unwrap();
+        memory.write_all(Path::new("/a/b/c.txt"), b"c").await.unwrap();
+        memory.write_all(Path::new("/a-b"), b"sibling").await.unwrap();
+
+        memory.rename(Path::new("/a"), Path::new("/z")).await.unwrap();
+        assert_eq!(read(&memory, "/z/b/c.txt").await, b"c");
+        assert!(memory.stat(Path::new("/a")).await.is_err());
+        // names sharing a prefix are not part of the tree
+        assert_eq!(read(&memory, "/a-b").await, b"sibling");
+        assert!(memory.rename(Path::new("/z"), Path::new("/z/b/y")).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn copies_get_their_own_inodes() {
+        let memory = Memory::new();
+        memory.mkdir(Path::new("/a"), false).await.unwrap();
+        memory.write_all(Path::new("/a/f"), b"1234").await.unwrap();
+
+        memory.copy(Path::new("/a"), Path::new("/b")).await.unwrap();
+        let (a, b) = (
+            memory.stat(Path::new("/a/f")).await.unwrap(),
+            memory.stat(Path::new("/b/f")).await.unwrap(),
+        );
+        assert_ne!(a.ino, b.ino);
+        assert_eq!(memory.size(Path::new("/")).await.unwrap(), 8);
+        assert!(memory.copy(Path::new("/a"), Path::new("/b")).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn lists_and_removes_directories() {
+        let memory = Memory::new();
+        memory.mkdir(Path::new("/d/e"), true).await.unwrap();
+        memory.write_all(Path::new("/d/f"), b"").await.unwrap();
+
+        let mut names: Vec<_> = memory.list(Path::new("/d")).await.unwrap().into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        assert_eq!(names, vec!["e", "f"]);
+
+        memory.remove(Path::new("/d")).await.unwrap();
+        assert!(memory.stat(Path::new("/d/e")).await.is_err());
+        assert!(memory.remove(Path::new("/d")).await.is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Where the files of a volume live.
+//!
+//! `File`, the finder commands and everything else working inside a volume go through the
+//! volume's `Storage` rather than the filesystem, using absolute paths below the volume's
+//! `path`. Which driver a volume uses is recorded along with it.
+
+mod local;
+mod memory;
+mod s3;
+pub mod sftp;
+
+pub use self::local::Local;
+pub use self::memory::Memory;
+pub use self::s3::S3;
+pub use self::sftp::Sftp;
+pub use crate::privsep::Stat;
+
+use crate::error::{Error, Result};
+use crate::privsep::RunAs;
+use actix_web::web::{Bytes, BytesMut};
+use async_trait::async_trait;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Contents of a file as they are read or written. Like everything else in a worker they
+/// never leave its thread, so neither streams nor drivers need to be `Send`.
+pub type ByteStream = LocalBoxStream<'static, Result<Bytes>>;
+
+/// The storage drivers a volume can be mounted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    /// The local filesystem, as the server or the user's mapped Unix account
+    Local,
+    /// A tree kept in memory for as long as the server runs, meant for tests
+    Memory,
+    /// Key prefixes in the S3 compatible bucket configured by the `S3_*` variables
+    S3,
+    /// A remote directory mounted over SFTP for the length of a session
+    Sftp,
+}
+
+impl Driver {
+    pub fn is_local(&self) -> bool {
+        *self == Driver::Local
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Driver::Local => "local",
+            Driver::Memory => "memory",
+            Driver::S3 => "s3",
+            Driver::Sftp => "sftp",
+        }
+    }
+
+    /// The storage of files recorded apart from their volume, such as trash entries, which
+    /// were stored through this driver as `run_as`. SFTP servers can only be reached while
+    /// they are mounted.
+    pub fn storage(&self, run_as: Option<RunAs>) -> Option<Arc<dyn Storage>> {
+        match self {
+            Driver::Local => Some(Arc::new(Local::new(run_as))),
+            Driver::Memory => Some(Memory::shared()),
+            Driver::S3 => Some(S3::shared()),
+            Driver::Sftp => None,
+        }
+    }
+}
+
+impl FromStr for Driver {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "local" => Ok(Driver::Local),
+            "memory" => Ok(Driver::Memory),
+            "s3" => Ok(Driver::S3),
+            "sftp" => Ok(Driver::Sftp),
+            _ => Err(Error::InvalidParams),
+        }
+    }
+}
+
+impl Default for Driver {
+    fn default() -> Self {
+        Driver::Local
+    }
+}
+
+#[async_trait(?Send)]
+pub trait Storage: fmt::Debug + Send + Sync {
+    async fn stat(&self, path: &Path) -> Result<Stat>;
+
+    /// Names and metadata of the entries of a directory
+    async fn list(&self, path: &Path) -> Result<Vec<(String, Stat)>>;
+
+    async fn read(&self, path: &Path) -> Result<ByteStream>;
+
+    /// Creates or replaces the file at `path`, returns the number of bytes written
+    async fn write(&self, path: &Path, data: ByteStream) -> Result<u64>;
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Removes a file or a directory with everything in it
+    async fn remove(&self, path: &Path) -> Result<()>;
+
+    /// Creates a directory, along with missing parents if `parents` is set
+    async fn mkdir(&self, path: &Path, parents: bool) -> Result<()>;
+
+    /// Total size of a file or directory in bytes
+    async fn size(&self, path: &Path) -> Result<u64>;
+
+    async fn set_mode(&self, _path: &Path, _mode: u32) -> Result<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "Modes are not supported by this storage").into())
+    }
+
+    /// Copies a file or a directory with everything in it
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut pending = vec![(from.to_owned(), to.to_owned())];
+        while let Some((from, to)) = pending.pop() {
+            if self.stat(&from).await?.is_dir {
+                self.mkdir(&to, false).await?;
+                for (name, _) in self.list(&from).await? {
+                    pending.push((from.join(&name), to.join(&name)));
+                }
+            } else {
+                let data = self.read(&from).await?;
+                self.write(&to, data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where clients can fetch the file from directly instead of through the server, as an
+    /// attachment if `download` is set
+    fn download_url(&self, _path: &Path, _download: bool) -> Option<String> {
+        None
+    }
+
+    async fn read_all(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut data = self.read(path).await?;
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = data.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        Ok(buf.to_vec())
+    }
+
+    async fn write_all(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let data = Bytes::copy_from_slice(data);
+        self.write(path, stream::once(async move { Ok(data) }).boxed_local()).await?;
+        Ok(())
+    }
+}
+
+/// Name for a file written next to `path` before it is moved into place
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{:08x}.tmp", name, rand::random::<u32>()))
+}
@@ -0,0 +1,559 @@
+//! Remote directories reached over SFTP, mounted by users with the finder's `netmount`
+//! command. libssh2 blocks, so every operation runs on the blocking thread pool, one at a
+//! time per mount.
+//!
+//! Users must not reach the server's own network through their mounts, so hosts resolving to
+//! loopback, link-local, private or other internal addresses are refused. `NETMOUNT_ALLOWED_HOSTS`
+//! lists addresses or networks such as `10.0.0.0/24` which may be mounted anyway, and
+//! `NETMOUNT_DENIED_HOSTS` further ones which may not, both separated by commas.
+
+use super::{ByteStream, Stat, Storage};
+use crate::error::{Error, Result};
+use actix_web::error::BlockingError;
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use once_cell::sync::{Lazy, OnceCell};
+use ssh2::{FileStat, HashType, Session};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Mounts by the id of their netmount, shared by all workers so each mount keeps a single
+/// connection
+static MOUNTED: Lazy<Mutex<HashMap<uuid::Uuid, Arc<Sftp>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static HOSTS: OnceCell<HostPolicy> = OnceCell::new();
+
+/// Networks refused unless `NETMOUNT_ALLOWED_HOSTS` allows them: "this" network, private,
+/// shared, loopback, link-local, multicast and broadcast addresses and their IPv6 kin
+const INTERNAL: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+const TIMEOUT_MS: u32 = 30_000;
+const CHUNK_SIZE: usize = 64 * 1024;
+const DIR_MODE: i32 = 0o755;
+/// libssh2's code for errors reported by the SFTP server, anything else is the connection's
+const SFTP_PROTOCOL_ERROR: i32 = -31;
+
+#[derive(Clone)]
+pub enum Auth {
+    Password(String),
+    /// A private key in PEM format and the passphrase it is encrypted with, if any
+    Key { private_key: String, passphrase: Option<String> },
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub auth: Auth,
+    /// Hex encoded SHA-256 fingerprint the server's host key has to match, `None` to accept
+    /// any on the first connection
+    pub host_key: Option<String>,
+}
+
+pub struct Sftp {
+    config: Option<Config>,
+    conn: Arc<Mutex<Option<ssh2::Sftp>>>,
+}
+
+fn ssh_error(e: ssh2::Error) -> Error {
+    io::Error::from(e).into()
+}
+
+/// Runs blocking code on the thread pool
+async fn blocking<T, F>(op: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    web::block(op).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => Error::Other("SFTP operation canceled".to_owned()),
+    })
+}
+
+fn to_stat(stat: &FileStat) -> Stat {
+    Stat {
+        is_dir: stat.is_dir(),
+        len: stat.size.unwrap_or(0),
+        modified: stat.mtime.unwrap_or(0),
+        readonly: false,
+        dev: 0,
+        ino: 0,
+    }
+}
+
+/// Everything below `path` with the deepest entries first, `path` itself last
+fn walk(sftp: &ssh2::Sftp, path: &Path) -> std::result::Result<Vec<(PathBuf, FileStat)>, ssh2::Error> {
+    let mut entries = Vec::new();
+    let mut pending = vec![(path.to_owned(), sftp.stat(path)?)];
+    while let Some((path, stat)) = pending.pop() {
+        if stat.is_dir() {
+            pending.extend(sftp.readdir(&path)?);
+        }
+        entries.push((path, stat));
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+/// An address, or a network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = parts
+            .next()
+            .and_then(|addr| addr.parse::<IpAddr>().ok())
+            .ok_or(Error::InvalidParams)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max).ok_or(Error::InvalidParams)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Which addresses netmounts may connect to
+#[derive(Debug, Default)]
+pub struct HostPolicy {
+    allowed: Vec<Network>,
+    denied: Vec<Network>,
+}
+
+impl HostPolicy {
+    fn parse(allowed: &str, denied: &str) -> Result<Self> {
+        let networks = |list: &str| {
+            list.split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<Network>>>()
+        };
+        Ok(Self {
+            allowed: networks(allowed)?,
+            denied: networks(denied)?,
+        })
+    }
+
+    /// Reads `NETMOUNT_ALLOWED_HOSTS` and `NETMOUNT_DENIED_HOSTS`, which apply to every
+    /// connection from then on
+    pub fn init() {
+        let var = |name: &str| std::env::var(name).unwrap_or_default();
+        let policy = Self::parse(&var("NETMOUNT_ALLOWED_HOSTS"), &var("NETMOUNT_DENIED_HOSTS"))
+            .expect("NETMOUNT_ALLOWED_HOSTS and NETMOUNT_DENIED_HOSTS must list addresses or networks");
+        if HOSTS.set(policy).is_err() {
+            panic!("The netmount host policy is initialized twice");
+        }
+    }
+
+    /// The policy set by `init`, refusing internal addresses if it was not called
+    fn current() -> &'static Self {
+        HOSTS.get_or_init(Self::default)
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4 addresses embedded in IPv6 ones are judged as what they are
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        if self.denied.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allowed.iter().any(|net| net.contains(ip))
+            || !INTERNAL
+                .iter()
+                .filter_map(|net| net.parse::<Network>().ok())
+                .any(|net| net.contains(ip))
+    }
+}
+
+impl Config {
+    /// Opens an SFTP channel to the server and returns it along with the fingerprint of the
+    /// server's host key. Hosts are refused if any address they resolve to is not permitted.
+    pub fn connect(&self) -> Result<(ssh2::Sftp, String)> {
+        let addrs = (self.host.as_str(), self.port).to_socket_addrs()?.collect::<Vec<_>>();
+        let policy = HostPolicy::current();
+        if addrs.is_empty() || !addrs.iter().all(|addr| policy.permits(addr.ip())) {
+            return Err(Error::Forbidden);
+        }
+        let tcp = TcpStream::connect(&addrs[..])?;
+        let mut session = Session::new().map_err(ssh_error)?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(TIMEOUT_MS);
+        session.handshake().map_err(ssh_error)?;
+
+        let host_key = session
+            .host_key_hash(HashType::Sha256)
+            .map(hex::encode)
+            .ok_or_else(|| Error::Other("The server did not send a host key".to_owned()))?;
+        if self.host_key.as_ref().map_or(false, |expected| *expected != host_key) {
+            return Err(Error::Other(format!("The host key of {} changed", self.host)));
+        }
+
+        let authenticated = match &self.auth {
+            Auth::Password(password) => session.userauth_password(&self.user, password),
+            Auth::Key { private_key, passphrase } => {
+                session.userauth_pubkey_memory(&self.user, None, private_key, passphrase.as_deref())
+            }
+        };
+        if authenticated.is_err() || !session.authenticated() {
+            return Err(Error::Forbidden);
+        }
+        Ok((session.sftp().map_err(ssh_error)?, host_key))
+    }
+}
+
+impl Sftp {
+    /// Connects once to check the configuration, returns the host key fingerprint and the
+    /// absolute path of `root`, the user's login directory by default
+    pub async fn probe(config: Config, root: Option<PathBuf>) -> Result<(String, PathBuf)> {
+        blocking(move || {
+            let (sftp, host_key) = config.connect()?;
+            let root = sftp
+                .realpath(&root.unwrap_or_else(|| PathBuf::from(".")))
+                .map_err(ssh_error)?;
+            if !sftp.stat(&root).map_err(ssh_error)?.is_dir() {
+                return Err(Error::PathError);
+            }
+            Ok((host_key, root))
+        })
+        .await
+    }
+
+    /// The mount with the given id, registered with `config` unless it is already
+    pub fn mount(id: uuid::Uuid, config: Config) -> Arc<Self> {
+        let mut mounted = MOUNTED.lock().expect("SFTP mounts lock poisoned");
+        mounted
+            .entry(id)
+            .or_insert_with(|| {
+                Arc::new(Self {
+                    config: Some(config),
+                    conn: Arc::new(Mutex::new(None)),
+                })
+            })
+            .clone()
+    }
+
+    /// The registered mount, or one failing every operation if there is none
+    pub fn mounted(id: Option<uuid::Uuid>) -> Arc<Self> {
+        let mounted = MOUNTED.lock().expect("SFTP mounts lock poisoned");
+        match id.and_then(|id| mounted.get(&id)) {
+            Some(sftp) => sftp.clone(),
+            None => Arc::new(Self {
+                config: None,
+                conn: Arc::new(Mutex::new(None)),
+            }),
+        }
+    }
+
+    /// Forgets a mount, its connection is closed once the last operation on it finishes
+    pub fn unmount(id: uuid::Uuid) {
+        MOUNTED.lock().expect("SFTP mounts lock poisoned").remove(&id);
+    }
+
+    /// Runs `op` on the mount's connection, connecting first if needed
+    async fn run<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ssh2::Sftp) -> std::result::Result<T, ssh2::Error> + Send + 'static,
+    {
+        let config = self
+            .config
+            .clone()
+            .ok_or_else(|| Error::Other("The SFTP server is not mounted".to_owned()))?;
+        let conn = self.conn.clone();
+        blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| Error::Other("SFTP connection lock poisoned".to_owned()))?;
+            let sftp = match conn.take() {
+                Some(sftp) => sftp,
+                None => config.connect()?.0,
+            };
+            let result = op(&sftp);
+            // a broken connection is opened anew by the next operation
+            match &result {
+                Err(e) if e.code() != SFTP_PROTOCOL_ERROR => {}
+                _ => *conn = Some(sftp),
+            }
+            result.map_err(ssh_error)
+        })
+        .await
+    }
+}
+
+impl std::fmt::Debug for Sftp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.config {
+            Some(config) => write!(f, "Sftp({}@{}:{})", config.user, config.host, config.port),
+            None => write!(f, "Sftp(unmounted)"),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for Sftp {
+    async fn stat(&self, path: &Path) -> Result<Stat> {
+        let path = path.to_owned();
+        self.run(move |sftp| sftp.stat(&path).map(|stat| to_stat(&stat))).await
+    }
+
+    async fn list(&self, path: &Path) -> Result<Vec<(String, Stat)>> {
+        let path = path.to_owned();
+        let entries = self.run(move |sftp| sftp.readdir(&path)).await?;
+        Ok(entries
+            .iter()
+            .map(|(path, stat)| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                (name, to_stat(stat))
+            })
+            .collect())
+    }
+
+    /// Streams the file in chunks, each read on the thread pool
+    async fn read(&self, path: &Path) -> Result<ByteStream> {
+        let path = path.to_owned();
+        let file = self.run(move |sftp| sftp.open(&path)).await?;
+        Ok(stream::unfold(Some(file), |file| async move {
+            let mut file = file?;
+            let read = blocking(move || {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let n = file.read(&mut buf)?;
+                buf.truncate(n);
+                Ok((file, buf))
+            })
+            .await;
+            match read {
+                Ok((_, buf)) if buf.is_empty() => None,
+                Ok((file, buf)) => Some((Ok(Bytes::from(buf)), Some(file))),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+        .boxed_local())
+    }
+
+    async fn write(&self, path: &Path, mut data: ByteStream) -> Result<u64> {
+        let path = path.to_owned();
+        let mut file = self.run(move |sftp| sftp.create(&path)).await?;
+        let mut written = 0;
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk?;
+            written += chunk.len() as u64;
+            file = blocking(move || {
+                file.write_all(&chunk)?;
+                Ok(file)
+            })
+            .await?;
+        }
+        // closing the handle waits for the server
+        blocking(move || {
+            drop(file);
+            Ok(())
+        })
+        .await?;
+        Ok(written)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from, to) = (from.to_owned(), to.to_owned());
+        self.run(move |sftp| sftp.rename(&from, &to, None)).await
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        let path = path.to_owned();
+        self.run(move |sftp| {
+            for (path, stat) in walk(sftp, &path)? {
+                if stat.is_dir() {
+                    sftp.rmdir(&path)?;
+                } else {
+                    sftp.unlink(&path)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn mkdir(&self, path: &Path, parents: bool) -> Result<()> {
+        let path = path.to_owned();
+        self.run(move |sftp| {
+            if !parents {
+                return sftp.mkdir(&path, DIR_MODE);
+            }
+            for ancestor in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+                if sftp.stat(ancestor).is_err() {
+                    sftp.mkdir(ancestor, DIR_MODE)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn size(&self, path: &Path) -> Result<u64> {
+        let path = path.to_owned();
+        self.run(move |sftp| {
+            Ok(walk(sftp, &path)?
+                .iter()
+                .filter(|(_, stat)| !stat.is_dir())
+                .map(|(_, stat)| stat.size.unwrap_or(0))
+                .sum())
+        })
+        .await
+    }
+
+    async fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        let path = path.to_owned();
+        self.run(move |sftp| {
+            let stat = FileStat {
+                size: None,
+                uid: None,
+                gid: None,
+                perm: Some(mode),
+                atime: None,
+                mtime: None,
+            };
+            sftp.setstat(&path, stat)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn refuses_internal_addresses_by_default() {
+        let policy = HostPolicy::default();
+        for internal in &[
+            "127.0.0.1", "10.1.2.3", "172.31.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1", "::ffff:10.0.0.1",
+        ] {
+            assert!(!policy.permits(ip(internal)), "{} is permitted", internal);
+        }
+        for public in &["93.184.216.34", "172.32.0.1", "2606:2800:220:1::1"] {
+            assert!(policy.permits(ip(public)), "{} is refused", public);
+        }
+    }
+
+    #[test]
+    fn applies_allowed_and_denied_networks() {
+        let policy = HostPolicy::parse("10.0.0.0/24, 127.0.0.1", "93.184.216.0/24").unwrap();
+        assert!(policy.permits(ip("10.0.0.5")));
+        assert!(!policy.permits(ip("10.0.1.5")));
+        assert!(policy.permits(ip("127.0.0.1")));
+        assert!(!policy.permits(ip("127.0.0.2")));
+        assert!(!policy.permits(ip("93.184.216.34")));
+        assert!(policy.permits(ip("93.184.217.34")));
+    }
+
+    #[test]
+    fn rejects_malformed_networks() {
+        assert!(HostPolicy::parse("10.0.0.0/33", "").is_err());
+        assert!(HostPolicy::parse("", "example.com").is_err());
+        assert_eq!("::/0".parse::<Network>().unwrap().prefix, 0);
+        assert!("::/0".parse::<Network>().unwrap().contains(ip("2001:db8::1")));
+    }
+
+    /// Credentials of an account on the sshd at `SFTP_TEST_PORT` (22 by default) of this host,
+    /// given by `SFTP_TEST_USER` and `SFTP_TEST_PASSWORD`
+    fn local_sshd() -> Config {
+        let _ = HOSTS.set(HostPolicy::parse("127.0.0.1", "").unwrap());
+        Config {
+            host: "127.0.0.1".to_owned(),
+            port: std::env::var("SFTP_TEST_PORT").map_or(22, |port| port.parse().unwrap()),
+            user: std::env::var("SFTP_TEST_USER").expect("SFTP_TEST_USER must be set"),
+            auth: Auth::Password(std::env::var("SFTP_TEST_PASSWORD").expect("SFTP_TEST_PASSWORD must be set")),
+            host_key: None,
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn probes_the_local_sshd() {
+        let (host_key, root) = Sftp::probe(local_sshd(), None).await.unwrap();
+        assert_eq!(host_key.len(), 64);
+        assert!(root.is_absolute());
+
+        let pinned = Config {
+            host_key: Some(host_key),
+            ..local_sshd()
+        };
+        assert!(Sftp::probe(pinned, None).await.is_ok());
+        let changed = Config {
+            host_key: Some("0".repeat(64)),
+            ..local_sshd()
+        };
+        assert!(Sftp::probe(changed, None).await.is_err());
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn round_trips_through_the_local_sshd() {
+        let (_, root) = Sftp::probe(local_sshd(), None).await.unwrap();
+        let id = uuid::Uuid::from_u128(rand::random());
+        let sftp = Sftp::mount(id, local_sshd());
+        let dir = root.join(format!("arca-test-{}", id.to_simple()));
+
+        sftp.mkdir(&dir.join("docs"), true).await.unwrap();
+        sftp.write_all(&dir.join("docs/a.txt"), b"hello").await.unwrap();
+        assert_eq!(sftp.read_all(&dir.join("docs/a.txt")).await.unwrap(), b"hello");
+        let names: Vec<_> = sftp.list(&dir.join("docs")).await.unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["a.txt"]);
+
+        sftp.rename(&dir.join("docs"), &dir.join("moved")).await.unwrap();
+        assert_eq!(sftp.size(&dir).await.unwrap(), 5);
+        sftp.remove(&dir).await.unwrap();
+        assert!(sftp.stat(&dir).await.is_err());
+        Sftp::unmount(id);
+    }
+}
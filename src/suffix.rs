@@ -0,0 +1,95 @@
+use actix_web::web;
+use publicsuffix::List;
+use std::env;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+const DEFAULT_LIST_PATH: &str = "public_suffix_list.dat";
+
+/// Validates email addresses against a public suffix list read from a local file.
+/// When no list can be loaded only the syntax of the address is checked.
+pub struct EmailValidator {
+    path: PathBuf,
+    list: RwLock<Option<List>>,
+}
+
+impl EmailValidator {
+    /// Reads the list from `PUBLIC_SUFFIX_LIST` (`public_suffix_list.dat` by default)
+    pub fn from_env() -> Self {
+        let path = env::var("PUBLIC_SUFFIX_LIST")
+            .unwrap_or_else(|_| DEFAULT_LIST_PATH.to_owned())
+            .into();
+        let validator = Self {
+            path,
+            list: RwLock::new(None),
+        };
+        validator.refresh();
+        validator
+    }
+
+    /// Reloads the list from disk, keeping the previous one if the file cannot be parsed
+    pub fn refresh(&self) {
+        match List::from_path(&self.path) {
+            Ok(list) => {
+                *self.list.write().expect("Public suffix list lock poisoned") = Some(list);
+            }
+            Err(_) => log::warn!(
+                "Could not load public suffix list from {}, falling back to syntactic email validation",
+                self.path.display()
+            ),
+        }
+    }
+
+    /// Periodically reloads the list every `PUBLIC_SUFFIX_REFRESH_SECS` seconds, if set
+    pub fn spawn_refresh(validator: web::Data<Self>) {
+        let secs = match env::var("PUBLIC_SUFFIX_REFRESH_SECS") {
+            Ok(secs) => secs
+                .parse::<u64>()
+                .expect("PUBLIC_SUFFIX_REFRESH_SECS must be a number"),
+            Err(_) => return,
+        };
+
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(secs));
+            // the first tick completes immediately, the list was just loaded
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                validator.refresh();
+            }
+        });
+    }
+
+    pub fn is_valid(&self, email: &str) -> bool {
+        match &*self.list.read().expect("Public suffix list lock poisoned") {
+            Some(list) => list.parse_email(email).is_ok(),
+            None => is_valid_syntax(email),
+        }
+    }
+}
+
+/// Conservative `local@domain.tld` check used when no suffix list is available
+fn is_valid_syntax(email: &str) -> bool {
+    let mut parts = email.rsplitn(2, '@');
+    let (domain, local) = match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) => (domain, local),
+        _ => return false,
+    };
+
+    if local.is_empty() || local.len() > 64 || local.chars().any(|c| c.is_whitespace() || c == '@') {
+        return false;
+    }
+
+    let labels = domain.split('.').collect::<Vec<_>>();
+    let valid_labels = labels.iter().all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    let tld = labels.last().copied().unwrap_or_default();
+
+    labels.len() >= 2 && valid_labels && tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic())
+}
@@ -0,0 +1,260 @@
+use super::env::DbPool;
+use super::error::{Error, Result};
+use super::privsep::{Helper, RunAs};
+use super::schema::trash_entries;
+use super::storage::{Driver, Storage};
+use super::user::error::Error as UserError;
+use super::version::Version;
+use super::volume::Volume;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Directory inside every volume holding its deleted files, hidden from the finder
+pub const TRASH_DIR: &str = ".trash";
+
+/// elFinder volume id of the trash root
+pub const TRASH_ID: &str = "trash_";
+
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+const PURGE_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Reads `TRASH_RETENTION_DAYS`, how long deleted files are kept (30 days by default)
+pub fn retention_from_env() -> Duration {
+    let days = std::env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .map(|days| days.parse().expect("TRASH_RETENTION_DAYS must be a number of days"))
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    Duration::days(days)
+}
+
+/// A file or directory moved to the trash of the volume at `volume_path`, which is only unique
+/// along with the `driver` storing it
+#[derive(Queryable, Serialize)]
+pub struct TrashEntry {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) volume_path: String,
+    pub(crate) original_path: String,
+    pub(crate) deleted_by: Option<uuid::Uuid>,
+    pub(crate) deleted_at: DateTime<Utc>,
+    pub(crate) size: i64,
+    pub(crate) is_dir: bool,
+    #[serde(skip)]
+    os_user: Option<String>,
+    #[serde(skip)]
+    pub(crate) driver: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "trash_entries"]
+struct NewTrashEntry<'a> {
+    volume_path: &'a str,
+    original_path: &'a str,
+    deleted_by: uuid::Uuid,
+    size: i64,
+    is_dir: bool,
+    os_user: Option<&'a str>,
+    driver: &'a str,
+}
+
+/// Escapes the wildcards of a `LIKE` pattern
+pub(crate) fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+impl TrashEntry {
+    /// Trash directory of the volume at `volume_path`
+    pub fn dir(volume_path: &Path) -> PathBuf {
+        volume_path.join(TRASH_DIR)
+    }
+
+    /// Where the entry's file or directory is kept
+    pub fn path(&self) -> PathBuf {
+        Self::dir(Path::new(&self.volume_path)).join(self.id.to_simple().to_string())
+    }
+
+    /// Name the entry had before it was deleted
+    pub fn name(&self) -> String {
+        Path::new(&self.original_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Moves `path`, relative to the volume root, into the volume's trash
+    pub async fn trash(conn: &PgConnection, vol: &Volume, path: &Path, user_id: uuid::Uuid) -> Result<Self> {
+        use crate::schema::trash_entries::dsl;
+
+        vol.check_write()?;
+        let run_as = vol.run_as.as_ref();
+        let storage = vol.storage();
+        let full = crate::file::File::check_path(vol, path)?;
+        if full == vol.path {
+            return Err(Error::InvalidParams);
+        }
+        let stat = storage.stat(&full).await?;
+        let size = storage.size(&full).await?;
+
+        let volume_path = vol.path.to_string_lossy();
+        let original_path = crate::file::File::relative(path);
+        let entry = diesel::insert_into(dsl::trash_entries)
+            .values(&NewTrashEntry {
+                volume_path: &volume_path,
+                original_path: &original_path,
+                deleted_by: user_id,
+                size: size as i64,
+                is_dir: stat.is_dir,
+                os_user: run_as.map(|run_as| run_as.user.as_str()),
+                driver: vol.driver.as_str(),
+            })
+            .get_result::<Self>(conn)?;
+
+        let moved = match storage.mkdir(&Self::dir(&vol.path), true).await {
+            Ok(()) => storage.rename(&full, &entry.path()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = moved {
+            diesel::delete(dsl::trash_entries.find(entry.id)).execute(conn)?;
+            return Err(e);
+        }
+        // a new file at the same path starts without versions
+        Version::moved(conn, vol.driver, &full, &entry.path())?;
+        Ok(entry)
+    }
+
+    /// Entries deleted from any of the given volumes or folders below them
+    pub fn below(conn: &PgConnection, roots: &[&Volume]) -> Result<Vec<Self>> {
+        use crate::schema::trash_entries::dsl;
+
+        let mut entries = Vec::new();
+        for vol in roots {
+            let root = vol.path.to_string_lossy();
+            let found = dsl::trash_entries
+                .filter(dsl::driver.eq(vol.driver.as_str()))
+                .filter(
+                    dsl::volume_path
+                        .eq(root.as_ref())
+                        .or(dsl::volume_path.like(format!("{}/%", escape_like(&root)))),
+                )
+                .order(dsl::deleted_at.desc())
+                .load::<Self>(conn)?;
+            for entry in found {
+                if !entries.iter().any(|e: &Self| e.id == entry.id) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn find(conn: &PgConnection, id: uuid::Uuid) -> Result<Self> {
+        use crate::schema::trash_entries::dsl;
+
+        dsl::trash_entries
+            .find(id)
+            .first::<Self>(conn)
+            .optional()?
+            .ok_or(Error::UserError(UserError::NotFound))
+    }
+
+    /// Moves the entry back to `path`, relative to `vol` which has to contain it. If something
+    /// else took its place in the meantime, " (restored)" and a counter are added to the name.
+    /// Returns where the entry ended up.
+    pub async fn restore(self, conn: &PgConnection, vol: &Volume, path: &Path) -> Result<PathBuf> {
+        use crate::schema::trash_entries::dsl;
+
+        vol.check_write()?;
+        if vol.driver.as_str() != self.driver {
+            return Err(Error::PathError);
+        }
+        let storage = vol.storage();
+        let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+        let name = self.name();
+        let (stem, extension) = match name.rfind('.').filter(|&i| i > 0 && !self.is_dir) {
+            Some(i) => name.split_at(i),
+            None => (name.as_str(), ""),
+        };
+
+        let mut target = path.to_owned();
+        let mut n = 1;
+        while storage.stat(&crate::file::File::check_path(vol, &target)?).await.is_ok() {
+            let suffix = if n == 1 {
+                " (restored)".to_owned()
+            } else {
+                format!(" (restored {})", n)
+            };
+            target = parent.join(format!("{}{}{}", stem, suffix, extension));
+            n += 1;
+        }
+
+        storage.mkdir(&crate::file::File::check_path(vol, parent)?, true).await?;
+        let restored = crate::file::File::check_path(vol, &target)?;
+        storage.rename(&self.path(), &restored).await?;
+        Version::moved(conn, vol.driver, &self.path(), &restored)?;
+        diesel::delete(dsl::trash_entries.find(self.id)).execute(conn)?;
+        Ok(target)
+    }
+
+    /// Deletes the entry for good from `storage`, the storage of its volume
+    pub async fn remove(self, conn: &PgConnection, storage: &dyn Storage) -> Result<()> {
+        use crate::schema::trash_entries::dsl;
+
+        match storage.remove(&self.path()).await {
+            Err(Error::IoError(e)) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(Error::IoError(e))
+            }
+            _ => {}
+        }
+        diesel::delete(dsl::trash_entries.find(self.id)).execute(conn)?;
+        Ok(())
+    }
+
+    /// Deletes every entry older than `retention`, returns how many could be deleted
+    pub async fn purge_expired(pool: &DbPool, helper: Option<&Arc<Helper>>, retention: Duration) -> Result<usize> {
+        use crate::schema::trash_entries::dsl;
+
+        let conn = pool.get().map_err(|_| UserError::DbError)?;
+        let expired = dsl::trash_entries
+            .filter(dsl::deleted_at.lt(Utc::now() - retention))
+            .load::<Self>(&conn)?;
+
+        let mut purged = 0;
+        for entry in expired {
+            let run_as = match (&entry.os_user, helper) {
+                (Some(user), Some(helper)) => Some(RunAs {
+                    helper: helper.clone(),
+                    user: user.clone(),
+                }),
+                // the account's files cannot be reached without the helper
+                (Some(_), None) => continue,
+                (None, _) => None,
+            };
+            let storage = match entry.driver.parse::<Driver>().ok().and_then(|driver| driver.storage(run_as)) {
+                Some(storage) => storage,
+                None => continue,
+            };
+            // one entry that cannot be removed must not keep the others in the trash
+            let id = entry.id;
+            match entry.remove(&conn, &*storage).await {
+                Ok(()) => purged += 1,
+                Err(e) => log::error!("Could not purge trash entry {}: {}", id, e),
+            }
+        }
+        Ok(purged)
+    }
+
+    pub fn spawn_purge(pool: DbPool, helper: Option<Arc<Helper>>, retention: Duration) {
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(PURGE_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                if let Err(e) = Self::purge_expired(&pool, helper.as_ref(), retention).await {
+                    log::error!("Could not purge the trash: {}", e);
+                }
+            }
+        });
+    }
+}
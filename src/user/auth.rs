@@ -0,0 +1,184 @@
+use super::error::{Error, Result};
+use super::password::PasswordHasher;
+use super::User;
+use diesel::pg::PgConnection;
+use ldap3::{LdapConn, Scope, SearchEntry};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::env;
+use std::sync::Arc;
+
+/// Role given to users that do not match any configured group
+pub const DEFAULT_ROLE: &str = "user";
+
+/// Role allowed to use the admin API
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Checks a user's credentials during login
+pub trait AuthProvider: Send + Sync {
+    fn authenticate(&self, conn: &PgConnection, email: &str, password: &str) -> Result<User>;
+
+    /// Whether the password checked by `authenticate` is the one stored in the `users` table,
+    /// and so can be changed through arca
+    fn local_passwords(&self) -> bool {
+        true
+    }
+}
+
+/// Selects the provider named by `AUTH_PROVIDER` (`password` by default)
+pub fn from_env(hasher: PasswordHasher) -> Arc<dyn AuthProvider> {
+    match env::var("AUTH_PROVIDER").as_deref() {
+        Ok("ldap") => Arc::new(LdapProvider::from_env(hasher)),
+        Ok("password") | Err(_) => Arc::new(PasswordProvider { hasher }),
+        Ok(other) => panic!("Unknown AUTH_PROVIDER: {}", other),
+    }
+}
+
+/// Verifies the password hash stored in the `users` table
+pub struct PasswordProvider {
+    hasher: PasswordHasher,
+}
+
+impl AuthProvider for PasswordProvider {
+    fn authenticate(&self, conn: &PgConnection, email: &str, password: &str) -> Result<User> {
+        let mut user = User::find(conn, email)?;
+
+        if !self.hasher.verify(password, &user.pass_hash).map_err(|_| Error::NotAuthenticated)? {
+            return Err(Error::NotAuthenticated);
+        }
+
+        // upgrade outdated hashes while the plaintext is at hand, a failure here must not
+        // prevent the login
+        if self.hasher.needs_rehash(&user.pass_hash) {
+            if let Err(e) = user.set_password(conn, &self.hasher, password) {
+                log::warn!("Could not rehash password for {}: {}", user.id, e);
+            }
+        }
+        Ok(user)
+    }
+}
+
+/// Searches the directory for the user's entry and binds as it with the given password
+pub struct LdapProvider {
+    url: String,
+    bind_dn: Option<String>,
+    bind_password: String,
+    base_dn: String,
+    user_filter: String,
+    group_attr: String,
+    group_roles: Vec<(String, String)>,
+    hasher: PasswordHasher,
+}
+
+impl LdapProvider {
+    /// `LDAP_GROUP_ROLES` has the form `admin=cn=admins,dc=example,dc=com;user=cn=staff,...`,
+    /// the first matching group wins
+    pub fn from_env(hasher: PasswordHasher) -> Self {
+        let group_roles = env::var("LDAP_GROUP_ROLES")
+            .unwrap_or_default()
+            .split(';')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let mut parts = entry.splitn(2, '=');
+                let role = parts.next().unwrap_or_default().trim().to_owned();
+                let group = parts
+                    .next()
+                    .expect("LDAP_GROUP_ROLES entries must be of the form role=group_dn")
+                    .trim()
+                    .to_lowercase();
+                (group, role)
+            })
+            .collect();
+
+        Self {
+            url: env::var("LDAP_URL").expect("Could not find LDAP_URL in .env"),
+            bind_dn: env::var("LDAP_BIND_DN").ok(),
+            bind_password: env::var("LDAP_BIND_PASSWORD").unwrap_or_default(),
+            base_dn: env::var("LDAP_BASE_DN").expect("Could not find LDAP_BASE_DN in .env"),
+            user_filter: env::var("LDAP_USER_FILTER").unwrap_or_else(|_| "(mail={email})".to_owned()),
+            group_attr: env::var("LDAP_GROUP_ATTR").unwrap_or_else(|_| "memberOf".to_owned()),
+            group_roles,
+            hasher,
+        }
+    }
+
+    /// The role of the first configured group the entry is a member of
+    fn role(&self, groups: &[String]) -> Option<String> {
+        self.group_roles
+            .iter()
+            .find(|(group, _)| groups.iter().any(|g| g.to_lowercase() == *group))
+            .map(|(_, role)| role.clone())
+    }
+
+    /// Returns the entry's distinguished name and group memberships
+    fn bind(&self, email: &str, password: &str) -> Result<(String, Vec<String>)> {
+        // an empty password would be an anonymous bind
+        if password.is_empty() {
+            return Err(Error::NotAuthenticated);
+        }
+
+        let mut ldap = LdapConn::new(&self.url).map_err(|_| Error::ProviderError)?;
+        if let Some(bind_dn) = &self.bind_dn {
+            ldap.simple_bind(bind_dn, &self.bind_password)
+                .and_then(|res| res.success())
+                .map_err(|_| Error::ProviderError)?;
+        }
+
+        let filter = self.user_filter.replace("{email}", &ldap3::ldap_escape(email));
+        let (entries, _) = ldap
+            .search(&self.base_dn, Scope::Subtree, &filter, vec![self.group_attr.as_str()])
+            .and_then(|res| res.success())
+            .map_err(|_| Error::ProviderError)?;
+
+        let mut entries = entries.into_iter().map(SearchEntry::construct);
+        let entry = match (entries.next(), entries.next()) {
+            (Some(entry), None) => entry,
+            _ => return Err(Error::NotAuthenticated),
+        };
+
+        ldap.simple_bind(&entry.dn, password)
+            .and_then(|res| res.success())
+            .map_err(|_| Error::NotAuthenticated)?;
+        let _ = ldap.unbind();
+
+        let groups = entry.attrs.get(&self.group_attr).cloned().unwrap_or_default();
+        Ok((entry.dn, groups))
+    }
+}
+
+impl AuthProvider for LdapProvider {
+    fn authenticate(&self, conn: &PgConnection, email: &str, password: &str) -> Result<User> {
+        let (_, groups) = self.bind(email, password)?;
+        let role = self.role(&groups);
+
+        // provision the user on first login, the local password is never used
+        let (mut user, provisioned) = match User::find(conn, email) {
+            Ok(user) => (user, false),
+            Err(Error::NotFound) => {
+                let password = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(64)
+                    .collect::<String>();
+                (User::create(conn, &self.hasher, email, &password, None)?, true)
+            }
+            Err(e) => return Err(e),
+        };
+
+        // the directory decides the role of mapped users, so leaving the admin group revokes
+        // admin, only users no group maps keep the role set locally
+        let role = match role {
+            Some(role) => role,
+            None if provisioned => DEFAULT_ROLE.to_owned(),
+            None => return Ok(user),
+        };
+        if user.role != role {
+            user.role = role;
+            user = user.update(conn)?;
+        }
+        Ok(user)
+    }
+
+    fn local_passwords(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,130 @@
+use super::error::Error as UserError;
+use super::User;
+use crate::audit::AuditEvent;
+use crate::env::DbPool;
+use crate::error::Result;
+use crate::volume::Volume;
+use chrono::{Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::env;
+use std::path::{Path, PathBuf};
+
+const PURGE_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Reads `ACCOUNT_DELETION_GRACE_DAYS`, deleted accounts are purged immediately without it
+pub fn grace_from_env() -> Option<Duration> {
+    env::var("ACCOUNT_DELETION_GRACE_DAYS")
+        .ok()
+        .map(|days| {
+            days.parse::<i64>()
+                .expect("ACCOUNT_DELETION_GRACE_DAYS must be a number")
+        })
+        .filter(|days| *days > 0)
+        .map(Duration::days)
+}
+
+/// Archives the user's volume, marks the account deleted, revokes its sessions and
+/// tokens and records an audit event. The email address is replaced so it can be used to sign
+/// up again, the audit event keeps it until the purge. Without a grace period the account is
+/// purged right away.
+pub async fn delete_account(
+    pool: &DbPool,
+    finder_root: &Path,
+    grace: Option<Duration>,
+    user: User,
+    actor_id: Option<uuid::Uuid>,
+) -> Result<()> {
+    use crate::schema::api_tokens::dsl as tokens;
+    use crate::schema::users::dsl;
+
+    let archived = Volume::archive(finder_root, user.id).await?;
+
+    let conn = pool.get().map_err(|_| UserError::DbError)?;
+    let deleted = conn.transaction::<_, UserError, _>(|| {
+        diesel::update(dsl::users.find(user.id))
+            .set((
+                dsl::deleted_at.eq(Utc::now()),
+                dsl::session_version.eq(dsl::session_version + 1),
+                dsl::email.eq(tombstone(user.id)),
+            ))
+            .execute(&conn)?;
+        diesel::delete(tokens::api_tokens.filter(tokens::user_id.eq(user.id))).execute(&conn)?;
+        AuditEvent::record(
+            &conn,
+            Some(user.id),
+            actor_id,
+            "account.deleted",
+            serde_json::json!({
+                "email": user.email,
+                "volume_archived": archived,
+                "grace_days": grace.map(|grace| grace.num_days()),
+            }),
+        )?;
+        Ok(())
+    });
+
+    if let Err(e) = deleted {
+        // put the volume back so the account stays usable
+        if archived {
+            Volume::unarchive(finder_root, user.id).await?;
+        }
+        return Err(e.into());
+    }
+
+    if grace.is_none() {
+        purge(&conn, finder_root, user.id).await?;
+    }
+    Ok(())
+}
+
+/// Address a deleted account is kept under until the purge, `.invalid` never resolves
+fn tombstone(user_id: uuid::Uuid) -> String {
+    format!("deleted-{}@arca.invalid", user_id.to_simple())
+}
+
+/// Removes the archived volume and the database row of a deleted account, and the personal
+/// data from its audit events
+pub async fn purge(conn: &PgConnection, finder_root: &Path, user_id: uuid::Uuid) -> Result<()> {
+    use crate::schema::users::dsl;
+
+    Volume::remove_archive(finder_root, user_id).await?;
+
+    conn.transaction::<_, UserError, _>(|| {
+        diesel::delete(dsl::users.find(user_id).filter(dsl::deleted_at.is_not_null()))
+            .execute(conn)?;
+        AuditEvent::scrub(conn, user_id)?;
+        AuditEvent::record(conn, Some(user_id), None, "account.purged", serde_json::json!({}))?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+async fn purge_expired(pool: &DbPool, finder_root: &Path, grace: Duration) -> Result<()> {
+    use crate::schema::users::dsl;
+
+    let conn = pool.get().map_err(|_| UserError::DbError)?;
+    let expired = dsl::users
+        .filter(dsl::deleted_at.lt(Utc::now() - grace))
+        .select(dsl::id)
+        .load::<uuid::Uuid>(&conn)
+        .map_err(UserError::from)?;
+
+    for user_id in expired {
+        purge(&conn, finder_root, user_id).await?;
+    }
+    Ok(())
+}
+
+/// Periodically purges accounts whose grace period has ended
+pub fn spawn_purge(pool: DbPool, finder_root: PathBuf, grace: Duration) {
+    actix_rt::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(PURGE_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_expired(&pool, &finder_root, grace).await {
+                log::error!("Could not purge deleted accounts: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,74 @@
+use super::error::{Error, Result};
+use super::{normalize_email, User};
+use crate::schema::email_changes;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+const TOKEN_LEN: usize = 40;
+const EXPIRY_HOURS: i64 = 24;
+
+/// A requested email change waiting for the new address to be verified
+#[derive(Queryable, Insertable)]
+#[table_name = "email_changes"]
+pub struct EmailChange {
+    token_hash: String,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) new_email: String,
+    pub(crate) expires_at: DateTime<Utc>,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl EmailChange {
+    /// Records the request, replacing earlier ones of the user, and returns the
+    /// verification token to send to the new address
+    pub fn create(conn: &PgConnection, user_id: uuid::Uuid, new_email: &str) -> Result<String> {
+        use crate::schema::email_changes::dsl;
+
+        let token = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .collect::<String>();
+
+        let change = Self {
+            token_hash: hash_token(&token),
+            user_id,
+            new_email: normalize_email(new_email),
+            expires_at: Utc::now() + Duration::hours(EXPIRY_HOURS),
+        };
+
+        conn.transaction(|| {
+            diesel::delete(dsl::email_changes.filter(dsl::user_id.eq(user_id))).execute(conn)?;
+            diesel::insert_into(dsl::email_changes)
+                .values(&change)
+                .execute(conn)?;
+            Ok(token)
+        })
+    }
+
+    /// Applies the change belonging to `token`, the unique index rejects addresses taken in the meantime
+    pub fn confirm(conn: &PgConnection, token: &str) -> Result<User> {
+        use crate::schema::email_changes::dsl;
+        use crate::schema::users::dsl::{email, users};
+
+        conn.transaction(|| {
+            let change = diesel::delete(dsl::email_changes.find(hash_token(token)))
+                .get_result::<Self>(conn)
+                .map_err(|_| Error::InvalidCode)?;
+            if change.expires_at < Utc::now() {
+                return Err(Error::InvalidCode);
+            }
+
+            diesel::update(users.find(change.user_id))
+                .set(email.eq(&change.new_email))
+                .get_result::<User>(conn)
+                .map_err(Into::into)
+        })
+    }
+}
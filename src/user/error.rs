@@ -4,6 +4,9 @@ use actix_web::{error, http, HttpResponse};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Unique index on the users' emails, ignoring case
+const EMAIL_INDEX: &str = "users_email_lower_idx";
+
 #[derive(Debug)]
 pub enum Error {
   InvalidEmail,
@@ -14,6 +17,17 @@ pub enum Error {
   SessionError,
   NotAuthenticated,
   NotAuthorized,
+  InvalidCode,
+  TotpAlreadyEnabled,
+  TotpNotEnabled,
+  CryptoError,
+  InvalidParams,
+  ProviderError,
+  WeakPassword(&'static str),
+  MailError,
+  AccountDisabled,
+  TooManyAttempts,
+  Conflict,
 }
 
 impl fmt::Display for Error {
@@ -25,6 +39,15 @@ impl fmt::Display for Error {
       NotFound => write!(f, "User Not Found"),
       NotAuthenticated => write!(f, "User Not Authenticated"),
       NotAuthorized => write!(f, "User Not Authorized"),
+      InvalidCode => write!(f, "Invalid Verification Code"),
+      TotpAlreadyEnabled => write!(f, "Two-Factor Authentication Already Enabled"),
+      TotpNotEnabled => write!(f, "Two-Factor Authentication Not Enabled"),
+      InvalidParams => write!(f, "Invalid Params"),
+      ProviderError => write!(f, "Identity Provider Error"),
+      WeakPassword(reason) => write!(f, "{}", reason),
+      AccountDisabled => write!(f, "User Account Disabled"),
+      TooManyAttempts => write!(f, "Too Many Attempts, Try Again Later"),
+      Conflict => write!(f, "Already Exists"),
       _ => write!(f, "Internal Server Error"),
     }
   }
@@ -34,9 +57,14 @@ impl error::ResponseError for Error {
   fn status_code(&self) -> http::StatusCode {
     use Error::*;
     match *self {
-      InvalidEmail | AlreadyExists => http::StatusCode::BAD_REQUEST,
+      InvalidEmail | AlreadyExists | TotpAlreadyEnabled | TotpNotEnabled | InvalidParams
+      | WeakPassword(_) => http::StatusCode::BAD_REQUEST,
       NotFound => http::StatusCode::NOT_FOUND,
-      NotAuthorized | NotAuthenticated => http::StatusCode::UNAUTHORIZED,
+      Conflict => http::StatusCode::CONFLICT,
+      AccountDisabled => http::StatusCode::FORBIDDEN,
+      ProviderError => http::StatusCode::BAD_GATEWAY,
+      NotAuthorized | NotAuthenticated | InvalidCode => http::StatusCode::UNAUTHORIZED,
+      TooManyAttempts => http::StatusCode::TOO_MANY_REQUESTS,
       _ => http::StatusCode::INTERNAL_SERVER_ERROR,
     }
   }
@@ -48,8 +76,19 @@ impl error::ResponseError for Error {
 }
 
 impl From<diesel::result::Error> for Error {
-    fn from(_err: diesel::result::Error) -> Self {
-        Self::DbError
+    fn from(err: diesel::result::Error) -> Self {
+        use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+        match err {
+            diesel::result::Error::NotFound => Self::NotFound,
+            DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                if info.constraint_name() == Some(EMAIL_INDEX) {
+                    Self::AlreadyExists
+                } else {
+                    Self::Conflict
+                }
+            }
+            _ => Self::DbError,
+        }
     }
 }
 
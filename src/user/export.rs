@@ -0,0 +1,329 @@
+use super::error::Error as UserError;
+use super::oidc::Identity;
+use super::token::ApiToken;
+use super::totp::Totp;
+use super::User;
+use crate::audit::AuditEvent;
+use crate::env::{DbPool, Environment};
+use crate::error::{Error, Result};
+use crate::grant::Grant;
+use crate::netmount::Netmount;
+use crate::share::Share;
+use crate::storage::temp_path;
+use crate::volume::Volume;
+use actix_web::web;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::StreamExt;
+use serde_derive::Serialize;
+use std::env;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Directory under `FINDER_ROOT` holding finished exports
+pub const EXPORT_DIR: &str = ".exports";
+
+const DEFAULT_EXPIRY_HOURS: i64 = 48;
+const CLEANUP_INTERVAL_SECS: u64 = 60 * 60;
+
+pub const PENDING: &str = "pending";
+pub const RUNNING: &str = "running";
+pub const READY: &str = "ready";
+pub const FAILED: &str = "failed";
+
+/// Background job building an archive of everything stored about a user
+#[derive(Queryable, Serialize)]
+pub struct ExportJob {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) status: String,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+}
+
+type Archive = tar::Builder<GzEncoder<std::fs::File>>;
+
+/// Account metadata written to `account.json` in the archive
+#[derive(Serialize)]
+struct AccountExport {
+    user: User,
+    /// The volumes below `volumes/` in the archive, by id
+    volumes: Vec<serde_json::Value>,
+    identities: Vec<Identity>,
+    api_tokens: Vec<ApiToken>,
+    two_factor_enabled: bool,
+    recovery_codes_remaining: i64,
+    audit_events: Vec<AuditEvent>,
+    shares: Vec<Share>,
+    grants_given: Vec<Grant>,
+    grants_received: Vec<Grant>,
+    /// Without their credentials
+    netmounts: Vec<Netmount>,
+}
+
+fn header(entry_type: tar::EntryType, size: u64, mode: u32, mtime: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    header
+}
+
+/// Runs `op` on the archive on the thread pool, so compressing never blocks a worker
+async fn with_archive<F>(mut archive: Archive, op: F) -> Result<Archive>
+where
+    F: FnOnce(&mut Archive) -> std::io::Result<()> + Send + 'static,
+{
+    web::block(move || op(&mut archive).map(|_| archive))
+        .await
+        .map_err(|e| Error::Other(e.to_string()))
+}
+
+fn expiry_from_env() -> Duration {
+    Duration::hours(
+        env::var("EXPORT_EXPIRY_HOURS")
+            .map(|hours| hours.parse().expect("EXPORT_EXPIRY_HOURS must be a number"))
+            .unwrap_or(DEFAULT_EXPIRY_HOURS),
+    )
+}
+
+impl ExportJob {
+    /// Queues an export, a user can only have one pending or running at a time, which the
+    /// `export_jobs_active` index enforces with a unique violation
+    pub fn create(conn: &PgConnection, user_id: uuid::Uuid) -> QueryResult<Self> {
+        use crate::schema::export_jobs::dsl;
+
+        diesel::insert_into(dsl::export_jobs)
+            .values(dsl::user_id.eq(user_id))
+            .get_result::<Self>(conn)
+    }
+
+    pub fn find(conn: &PgConnection, user_id: uuid::Uuid, id: uuid::Uuid) -> QueryResult<Self> {
+        use crate::schema::export_jobs::dsl;
+
+        dsl::export_jobs
+            .find(id)
+            .filter(dsl::user_id.eq(user_id))
+            .first::<Self>(conn)
+    }
+
+    fn set_status(conn: &PgConnection, id: uuid::Uuid, status: &str, expires_at: Option<DateTime<Utc>>) -> QueryResult<()> {
+        use crate::schema::export_jobs::dsl;
+
+        diesel::update(dsl::export_jobs.find(id))
+            .set((dsl::status.eq(status), dsl::expires_at.eq(expires_at)))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    pub fn archive_path(finder_root: &Path, id: uuid::Uuid) -> PathBuf {
+        finder_root
+            .join(EXPORT_DIR)
+            .join(format!("{}.tar.gz", id.to_simple()))
+    }
+
+    /// Whether the archive can be downloaded
+    pub fn is_available(&self) -> bool {
+        self.status == READY && self.expires_at.map_or(false, |expires_at| expires_at > Utc::now())
+    }
+
+    /// Builds the archive in the background
+    pub fn spawn(env: web::Data<Environment>, id: uuid::Uuid, user_id: uuid::Uuid) {
+        actix_rt::spawn(async move {
+            if let Err(e) = Self::run(&env, id, user_id).await {
+                log::error!("Export {} failed: {}", id, e);
+                if let Ok(conn) = env.db_pool.get() {
+                    let _ = Self::set_status(&conn, id, FAILED, None);
+                }
+            }
+        });
+    }
+
+    async fn run(env: &Environment, id: uuid::Uuid, user_id: uuid::Uuid) -> Result<()> {
+        let conn = env.db_pool.get().map_err(|_| UserError::DbError)?;
+        Self::set_status(&conn, id, RUNNING, None).map_err(UserError::from)?;
+
+        let user = User::find_by_id(&conn, user_id)?;
+        let volumes = Volume::for_user(env, &user).await?;
+        let metadata = serde_json::to_vec_pretty(&Self::collect(&conn, user, &volumes)?)
+            .map_err(|e| Error::Other(e.to_string()))?;
+        let dir = env.finder_root.join(EXPORT_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let spool = dir.join(format!("{}.part", id.to_simple()));
+
+        // built next to the archive and only renamed once complete, so a failed export
+        // leaves nothing behind
+        let path = Self::archive_path(&env.finder_root, id);
+        let tmp = temp_path(&path);
+        let built = Self::build(&tmp, &spool, metadata, &volumes).await;
+        let _ = tokio::fs::remove_file(&spool).await;
+        if let Err(e) = match built {
+            Ok(()) => tokio::fs::rename(&tmp, &path).await.map_err(Into::into),
+            Err(e) => Err(e),
+        } {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+
+        Self::set_status(&conn, id, READY, Some(Utc::now() + expiry_from_env()))
+            .map_err(UserError::from)?;
+        Ok(())
+    }
+
+    /// Writes the archive of `metadata` and the volumes to `path`
+    async fn build(path: &Path, spool: &Path, metadata: Vec<u8>, volumes: &[Volume]) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        let mtime = Utc::now().timestamp() as u64;
+        archive = with_archive(archive, move |archive| {
+            let mut header = header(tar::EntryType::Regular, metadata.len() as u64, 0o644, mtime);
+            archive.append_data(&mut header, "account.json", metadata.as_slice())
+        })
+        .await?;
+
+        for vol in volumes {
+            archive = Self::append_volume(archive, vol, spool).await?;
+        }
+        with_archive(archive, |archive| {
+            archive.finish()?;
+            archive.get_mut().try_finish()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Adds everything in the volume below `volumes/<id>`, reading it through the volume's
+    /// storage so mapped accounts and remote drivers are exported like local volumes. Each
+    /// file is spooled to `spool` first, the archive needs to know its size up front.
+    async fn append_volume(mut archive: Archive, vol: &Volume, spool: &Path) -> Result<Archive> {
+        let storage = vol.storage();
+        let root = Path::new("volumes").join(vol.id.trim_end_matches('_'));
+        let mut pending = vec![(PathBuf::new(), storage.stat(&vol.path).await?)];
+
+        while let Some((relative, stat)) = pending.pop() {
+            let full = vol.path.join(&relative);
+            let name = root.join(&relative);
+            if stat.is_dir {
+                archive = with_archive(archive, move |archive| {
+                    let mut header = header(tar::EntryType::Directory, 0, 0o755, stat.modified);
+                    archive.append_data(&mut header, name, std::io::empty())
+                })
+                .await?;
+                for (entry, stat) in storage.list(&full).await? {
+                    pending.push((relative.join(entry), stat));
+                }
+                continue;
+            }
+
+            // files removed in the meantime are left out
+            let mut data = match storage.read(&full).await {
+                Ok(data) => data,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mut file = tokio::fs::File::create(spool).await?;
+            while let Some(chunk) = data.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            drop(file);
+
+            let spool = spool.to_owned();
+            archive = with_archive(archive, move |archive| {
+                let mut file = std::fs::File::open(&spool)?;
+                let len = file.metadata()?.len();
+                let mut header = header(tar::EntryType::Regular, len, 0o644, stat.modified);
+                archive.append_data(&mut header, name, &mut file)
+            })
+            .await?;
+        }
+        Ok(archive)
+    }
+
+    fn collect(conn: &PgConnection, user: User, volumes: &[Volume]) -> Result<AccountExport> {
+        use crate::schema::recovery_codes::dsl as codes;
+
+        let recovery_codes_remaining = codes::recovery_codes
+            .filter(codes::user_id.eq(user.id))
+            .filter(codes::used.eq(false))
+            .count()
+            .get_result::<i64>(conn)
+            .map_err(UserError::from)?;
+
+        let volumes = volumes
+            .iter()
+            .map(|vol| {
+                serde_json::json!({
+                    "id": vol.id.trim_end_matches('_'),
+                    "name": vol.name,
+                    "driver": vol.driver,
+                })
+            })
+            .collect();
+
+        Ok(AccountExport {
+            volumes,
+            identities: Identity::for_user(conn, user.id)?,
+            api_tokens: ApiToken::list(conn, user.id)?,
+            two_factor_enabled: Totp::find_enabled(conn, user.id)?.is_some(),
+            recovery_codes_remaining,
+            audit_events: AuditEvent::for_user(conn, user.id)?,
+            shares: Share::list(conn, user.id)?,
+            grants_given: Grant::given(conn, user.id)?,
+            grants_received: Grant::received(conn, user.id)?,
+            netmounts: Netmount::list(conn, user.id)?,
+            user,
+        })
+    }
+
+    async fn remove_expired(pool: &DbPool, finder_root: &Path) -> Result<()> {
+        use crate::schema::export_jobs::dsl;
+
+        let conn = pool.get().map_err(|_| UserError::DbError)?;
+        let expired = diesel::delete(dsl::export_jobs.filter(dsl::expires_at.lt(Utc::now())))
+            .returning(dsl::id)
+            .get_results::<uuid::Uuid>(&conn)
+            .map_err(UserError::from)?;
+
+        for id in expired {
+            match tokio::fs::remove_file(Self::archive_path(finder_root, id)).await {
+                Err(e) if e.kind() != tokio::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks jobs as failed that were pending or running when the server stopped, they
+    /// would never finish and keep their user from exporting again
+    fn fail_interrupted(pool: &DbPool) -> Result<()> {
+        use crate::schema::export_jobs::dsl;
+
+        let conn = pool.get().map_err(|_| UserError::DbError)?;
+        diesel::update(dsl::export_jobs.filter(dsl::status.eq_any(&[PENDING, RUNNING])))
+            .set(dsl::status.eq(FAILED))
+            .execute(&conn)
+            .map_err(UserError::from)?;
+        Ok(())
+    }
+
+    /// Periodically deletes expired archives
+    pub fn spawn_cleanup(pool: DbPool, finder_root: PathBuf) {
+        if let Err(e) = Self::fail_interrupted(&pool) {
+            log::error!("Could not fail interrupted exports: {}", e);
+        }
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(CLEANUP_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                if let Err(e) = Self::remove_expired(&pool, &finder_root).await {
+                    log::error!("Could not remove expired exports: {}", e);
+                }
+            }
+        });
+    }
+}
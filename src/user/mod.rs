@@ -1,10 +1,20 @@
+pub mod auth;
+pub mod deletion;
+pub mod email;
 pub mod error;
+pub mod export;
+pub mod oidc;
+pub mod password;
+pub mod reset;
+pub mod token;
+pub mod totp;
 
 use super::schema::users;
 use super::volume::*;
 use diesel::prelude::*;
 use diesel::{pg::PgConnection, Insertable, Queryable};
 use error::Result;
+use password::PasswordHasher;
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Queryable, AsChangeset)]
@@ -15,6 +25,12 @@ pub struct User {
     #[serde(skip)]
     pub(crate) pass_hash: String,
     pub(crate) volumes: Option<Vec<Volume>>,
+    pub(crate) role: String,
+    pub(crate) session_version: i32,
+    pub(crate) deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub(crate) disabled: bool,
+    pub(crate) quota_bytes: Option<i64>,
+    pub(crate) os_user: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -26,17 +42,23 @@ struct NewUser {
 }
 
 
+/// Emails are stored trimmed and lower case, matching the unique index on `lower(email)`
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
 impl User {
     pub fn create(
         conn: &PgConnection,
+        hasher: &PasswordHasher,
         email: &str,
         password: &str,
         volumes: Option<Vec<Volume>>,
     ) -> Result<Self> {
-        let pass_hash = bcrypt::hash_with_result(password, 10)?.to_string();
+        let pass_hash = hasher.hash(password)?;
 
         let new_user = NewUser {
-            email: email.to_owned(),
+            email: normalize_email(email),
             pass_hash,
             volumes,
         };
@@ -49,23 +71,101 @@ impl User {
 
     pub fn find(conn: &PgConnection, email: &str) -> Result<Self> {
         use crate::schema::users::dsl::email as dsl_email;
-        use crate::schema::users::dsl::users;
+        use crate::schema::users::dsl::{deleted_at, users};
 
         users
-            .filter(dsl_email.eq(&email))
+            .filter(dsl_email.eq(normalize_email(email)))
+            .filter(deleted_at.is_null())
             .first::<Self>(conn)
             .map_err(Into::into)
     }
 
     pub fn find_by_id(conn: &PgConnection, id: uuid::Uuid) -> Result<Self> {
-        use crate::schema::users::dsl::users;
+        use crate::schema::users::dsl::{deleted_at, users};
 
         users
             .find(id)
+            .filter(deleted_at.is_null())
             .first::<Self>(conn)
             .map_err(Into::into)
     }
 
+    pub fn is_admin(&self) -> bool {
+        self.role == auth::ADMIN_ROLE
+    }
+
+    /// Rejects accounts disabled by an administrator
+    pub fn ensure_active(&self) -> Result<()> {
+        if self.disabled {
+            return Err(error::Error::AccountDisabled);
+        }
+        Ok(())
+    }
+
+    /// Paginated listing for administrators, optionally filtered by a substring of the email
+    pub fn list(conn: &PgConnection, search: Option<&str>, page: i64, per_page: i64) -> Result<(Vec<Self>, i64)> {
+        use crate::schema::users::dsl::{deleted_at, email, users};
+
+        let pattern = format!(
+            "%{}%",
+            search
+                .unwrap_or_default()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        let total = users
+            .filter(deleted_at.is_null())
+            .filter(email.ilike(&pattern))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        let page = users
+            .filter(deleted_at.is_null())
+            .filter(email.ilike(&pattern))
+            .order(email.asc())
+            .offset((page.max(1) - 1) * per_page)
+            .limit(per_page)
+            .load::<Self>(conn)?;
+
+        Ok((page, total))
+    }
+
+    /// Hashes and stores a new password
+    pub fn set_password(&mut self, conn: &PgConnection, hasher: &PasswordHasher, password: &str) -> Result<()> {
+        use crate::schema::users::dsl::{pass_hash, users};
+
+        let hash = hasher.hash(password)?;
+        diesel::update(users.find(self.id))
+            .set(pass_hash.eq(&hash))
+            .execute(conn)?;
+        self.pass_hash = hash;
+        Ok(())
+    }
+
+    /// Replaces the password with a hash no password matches, until a new one is set
+    pub fn clear_password(&mut self, conn: &PgConnection) -> Result<()> {
+        use crate::schema::users::dsl::{pass_hash, users};
+
+        diesel::update(users.find(self.id))
+            .set(pass_hash.eq(password::UNUSABLE_HASH))
+            .execute(conn)?;
+        self.pass_hash = password::UNUSABLE_HASH.to_owned();
+        Ok(())
+    }
+
+    /// Invalidates every session issued before this call
+    pub fn bump_session_version(&mut self, conn: &PgConnection) -> Result<()> {
+        use crate::schema::users::dsl::{session_version, users};
+
+        self.session_version = diesel::update(users.find(self.id))
+            .set(session_version.eq(session_version + 1))
+            .returning(session_version)
+            .get_result::<i32>(conn)?;
+        Ok(())
+    }
+
     pub fn update(self, conn: &PgConnection) -> Result<Self> {
         use crate::schema::users::dsl::id;
         use crate::schema::users::dsl::users;
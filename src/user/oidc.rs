@@ -0,0 +1,220 @@
+use super::error::{Error, Result};
+use super::password::PasswordHasher;
+use super::User;
+use crate::schema::user_identities;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+
+/// OpenID Connect provider settings, read from the environment
+#[derive(Clone)]
+pub struct OidcConfig {
+    pub(crate) issuer: String,
+    pub(crate) client_id: String,
+    pub(crate) client_secret: Option<String>,
+    pub(crate) redirect_uri: String,
+    pub(crate) auto_provision: bool,
+}
+
+impl OidcConfig {
+    /// Returns `None` unless `OIDC_ISSUER` is set
+    pub fn from_env() -> Option<Self> {
+        let issuer = env::var("OIDC_ISSUER").ok()?;
+        Some(Self {
+            issuer: issuer.trim_end_matches('/').to_owned(),
+            client_id: env::var("OIDC_CLIENT_ID").expect("Could not find OIDC_CLIENT_ID in .env"),
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_uri: env::var("OIDC_REDIRECT_URI")
+                .expect("Could not find OIDC_REDIRECT_URI in .env"),
+            auto_provision: env::var("OIDC_AUTO_PROVISION")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+        })
+    }
+}
+
+/// Subset of the provider's `/.well-known/openid-configuration`
+#[derive(Deserialize)]
+pub struct Discovery {
+    pub(crate) issuer: String,
+    pub(crate) authorization_endpoint: String,
+    pub(crate) token_endpoint: String,
+    pub(crate) jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    pub(crate) id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+pub struct Claims {
+    pub(crate) sub: String,
+    pub(crate) email: Option<String>,
+    pub(crate) email_verified: Option<bool>,
+    pub(crate) nonce: Option<String>,
+}
+
+/// Per login attempt state kept in the session between redirect and callback
+#[derive(Serialize, Deserialize)]
+pub struct AuthState {
+    pub(crate) state: String,
+    pub(crate) nonce: String,
+    pub(crate) verifier: String,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect()
+}
+
+impl AuthState {
+    pub fn generate() -> Self {
+        Self {
+            state: random_string(32),
+            nonce: random_string(32),
+            verifier: random_string(64),
+        }
+    }
+
+    /// PKCE S256 code challenge derived from the verifier
+    pub fn challenge(&self) -> String {
+        base64::encode_config(Sha256::digest(self.verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+impl JwkSet {
+    /// Verifies the signature and standard claims of an ID token
+    pub fn verify(&self, config: &OidcConfig, discovery: &Discovery, id_token: &str) -> Result<Claims> {
+        let header = jsonwebtoken::decode_header(id_token).map_err(|_| Error::ProviderError)?;
+        let jwk = self
+            .keys
+            .iter()
+            .filter(|key| key.kty == "RSA")
+            .find(|key| header.kid.is_none() || key.kid == header.kid)
+            .ok_or(Error::ProviderError)?;
+        let key = DecodingKey::from_rsa_components(
+            jwk.n.as_deref().ok_or(Error::ProviderError)?,
+            jwk.e.as_deref().ok_or(Error::ProviderError)?,
+        );
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&config.client_id]);
+        validation.iss = Some(discovery.issuer.clone());
+
+        jsonwebtoken::decode::<Claims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| Error::NotAuthenticated)
+    }
+}
+
+/// An external identity linked to a local user
+#[derive(Queryable, Serialize)]
+pub struct Identity {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) issuer: String,
+    pub(crate) subject: String,
+    pub(crate) email: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_identities"]
+struct NewIdentity<'a> {
+    user_id: uuid::Uuid,
+    issuer: &'a str,
+    subject: &'a str,
+    email: &'a str,
+}
+
+impl Identity {
+    pub fn find(conn: &PgConnection, issuer: &str, subject: &str) -> Result<Option<Self>> {
+        use crate::schema::user_identities::dsl;
+
+        dsl::user_identities
+            .filter(dsl::issuer.eq(issuer))
+            .filter(dsl::subject.eq(subject))
+            .first::<Self>(conn)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    pub fn for_user(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Vec<Self>> {
+        use crate::schema::user_identities::dsl;
+
+        dsl::user_identities
+            .filter(dsl::user_id.eq(user_id))
+            .load::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    pub fn link(conn: &PgConnection, user_id: uuid::Uuid, issuer: &str, subject: &str, email: &str) -> Result<Self> {
+        use crate::schema::user_identities::dsl;
+
+        diesel::insert_into(dsl::user_identities)
+            .values(&NewIdentity {
+                user_id,
+                issuer,
+                subject,
+                email,
+            })
+            .get_result::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    /// Maps verified claims to a local user, linking to the account with a verified email and
+    /// provisioning a new account if allowed
+    pub fn resolve_user(
+        conn: &PgConnection,
+        hasher: &PasswordHasher,
+        config: &OidcConfig,
+        issuer: &str,
+        claims: &Claims,
+    ) -> Result<User> {
+        if let Some(identity) = Self::find(conn, issuer, &claims.sub)? {
+            return User::find_by_id(conn, identity.user_id);
+        }
+
+        let email = claims.email.as_deref().ok_or(Error::InvalidEmail)?;
+        if claims.email_verified == Some(false) {
+            return Err(Error::InvalidEmail);
+        }
+
+        conn.transaction(|| {
+            let user = match User::find(conn, email) {
+                // only an address the provider vouches for may take over an existing account
+                Ok(_) if claims.email_verified != Some(true) => return Err(Error::NotAuthorized),
+                Ok(user) => user,
+                Err(Error::NotFound) if config.auto_provision => {
+                    // the account can only be used through the provider until a password is set
+                    User::create(conn, hasher, email, &random_string(64), None)?
+                }
+                Err(e) => return Err(e),
+            };
+            Self::link(conn, user.id, issuer, &claims.sub, email)?;
+            Ok(user)
+        })
+    }
+}
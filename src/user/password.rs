@@ -0,0 +1,221 @@
+use super::error::{Error, Result};
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::env;
+
+const DEFAULT_BCRYPT_COST: u32 = 10;
+const DEFAULT_MIN_LENGTH: usize = 8;
+
+/// Stored in place of a hash to disable password logins, `verify` fails on it
+pub const UNUSABLE_HASH: &str = "!";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Bcrypt,
+    Argon2id,
+}
+
+/// Hashes passwords with the configured algorithm and cost
+#[derive(Clone)]
+pub struct PasswordHasher {
+    algorithm: Algorithm,
+    bcrypt_cost: u32,
+    argon2_memory: u32,
+    argon2_iterations: u32,
+}
+
+impl PasswordHasher {
+    /// Reads `PASSWORD_HASH` (`bcrypt` or `argon2id`), `BCRYPT_COST`,
+    /// `ARGON2_MEMORY_KIB` and `ARGON2_ITERATIONS`
+    pub fn from_env() -> Self {
+        let algorithm = match env::var("PASSWORD_HASH").as_deref() {
+            Ok("argon2id") => Algorithm::Argon2id,
+            Ok("bcrypt") | Err(_) => Algorithm::Bcrypt,
+            Ok(other) => panic!("Unknown PASSWORD_HASH: {}", other),
+        };
+        let var = |name: &str, default: u32| {
+            env::var(name)
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("{} must be a number", name)))
+                .unwrap_or(default)
+        };
+        let argon2_defaults = argon2::Config::default();
+
+        Self {
+            algorithm,
+            bcrypt_cost: var("BCRYPT_COST", DEFAULT_BCRYPT_COST),
+            argon2_memory: var("ARGON2_MEMORY_KIB", argon2_defaults.mem_cost),
+            argon2_iterations: var("ARGON2_ITERATIONS", argon2_defaults.time_cost),
+        }
+    }
+
+    fn argon2_config(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            mem_cost: self.argon2_memory,
+            time_cost: self.argon2_iterations,
+            ..argon2::Config::default()
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String> {
+        match self.algorithm {
+            Algorithm::Bcrypt => bcrypt::hash_with_result(password, self.bcrypt_cost)
+                .map(|parts| parts.to_string())
+                .map_err(|_| Error::HashError),
+            Algorithm::Argon2id => {
+                let mut salt = [0; 16];
+                rand::thread_rng().fill_bytes(&mut salt);
+                argon2::hash_encoded(password.as_bytes(), &salt, &self.argon2_config())
+                    .map_err(|_| Error::HashError)
+            }
+        }
+    }
+
+    /// Verifies a password against a hash produced by either algorithm
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool> {
+        if hash.starts_with("$argon2") {
+            argon2::verify_encoded(hash, password.as_bytes()).map_err(|_| Error::HashError)
+        } else {
+            bcrypt::verify(password, hash).map_err(|_| Error::HashError)
+        }
+    }
+
+    /// Whether a hash was made with a different algorithm or weaker parameters than configured
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        match self.algorithm {
+            Algorithm::Bcrypt => {
+                // $2b$10$...
+                let cost = hash.split('$').nth(2).and_then(|cost| cost.parse::<u32>().ok());
+                !hash.starts_with("$2") || cost.map_or(true, |cost| cost < self.bcrypt_cost)
+            }
+            Algorithm::Argon2id => {
+                let params = format!("m={},t={},", self.argon2_memory, self.argon2_iterations);
+                !hash.starts_with("$argon2id$") || !hash.contains(&params)
+            }
+        }
+    }
+}
+
+/// Rules a new password has to satisfy
+pub struct PasswordPolicy {
+    min_length: usize,
+    breached: HashSet<String>,
+}
+
+fn sha1_hex(password: &str) -> String {
+    hex::encode_upper(Sha1::digest(password.as_bytes()))
+}
+
+impl PasswordPolicy {
+    /// Reads `PASSWORD_MIN_LENGTH` and `PASSWORD_BREACHED_LIST`, a local file with one
+    /// password or upper case SHA-1 hash (`HASH:count` lines are accepted) per line
+    pub fn from_env() -> Self {
+        let min_length = env::var("PASSWORD_MIN_LENGTH")
+            .map(|v| v.parse().expect("PASSWORD_MIN_LENGTH must be a number"))
+            .unwrap_or(DEFAULT_MIN_LENGTH);
+
+        let breached = env::var("PASSWORD_BREACHED_LIST")
+            .map(|path| {
+                std::fs::read_to_string(&path)
+                    .unwrap_or_else(|_| panic!("Could not read breached password list: {}", path))
+                    .lines()
+                    .map(|line| {
+                        let entry = line.trim();
+                        let hash = entry.split(':').next().unwrap_or(entry);
+                        if hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                            hash.to_uppercase()
+                        } else {
+                            sha1_hex(entry)
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            min_length,
+            breached,
+        }
+    }
+
+    pub fn check(&self, email: &str, password: &str) -> Result<()> {
+        if password.chars().count() < self.min_length {
+            return Err(Error::WeakPassword("Password Too Short"));
+        }
+        if password.trim().eq_ignore_ascii_case(email.trim()) {
+            return Err(Error::WeakPassword("Password Must Not Equal Email"));
+        }
+        if self.breached.contains(&sha1_hex(password)) {
+            return Err(Error::WeakPassword("Password Found In Breach List"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hasher(algorithm: Algorithm, bcrypt_cost: u32) -> PasswordHasher {
+        PasswordHasher {
+            algorithm,
+            bcrypt_cost,
+            argon2_memory: 64,
+            argon2_iterations: 1,
+        }
+    }
+
+    fn policy(breached: &[&str]) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            breached: breached.iter().map(|password| sha1_hex(password)).collect(),
+        }
+    }
+
+    #[test]
+    fn policy_rejects_weak_passwords() {
+        let policy = policy(&["password123"]);
+        assert!(policy.check("a@example.com", "correct horse").is_ok());
+        assert!(matches!(
+            policy.check("a@example.com", "short"),
+            Err(Error::WeakPassword("Password Too Short"))
+        ));
+        // counted in characters, not bytes
+        assert!(policy.check("a@example.com", "äöüäöüä").is_err());
+        assert!(policy.check("a@example.com", "äöüäöüäö").is_ok());
+        assert!(matches!(
+            policy.check("Someone@Example.com", " someone@example.COM "),
+            Err(Error::WeakPassword("Password Must Not Equal Email"))
+        ));
+        assert!(matches!(
+            policy.check("a@example.com", "password123"),
+            Err(Error::WeakPassword("Password Found In Breach List"))
+        ));
+    }
+
+    #[test]
+    fn rehashes_weaker_or_other_hashes() {
+        let bcrypt = hasher(Algorithm::Bcrypt, 5);
+        let hash = bcrypt.hash("secret").unwrap();
+        assert!(bcrypt.verify("secret", &hash).unwrap());
+        assert!(!bcrypt.needs_rehash(&hash));
+        assert!(!hasher(Algorithm::Bcrypt, 4).needs_rehash(&hash));
+        assert!(hasher(Algorithm::Bcrypt, 6).needs_rehash(&hash));
+
+        let argon2 = hasher(Algorithm::Argon2id, 5);
+        assert!(argon2.needs_rehash(&hash));
+        let hash = argon2.hash("secret").unwrap();
+        assert!(argon2.verify("secret", &hash).unwrap());
+        assert!(!argon2.needs_rehash(&hash));
+        assert!(bcrypt.needs_rehash(&hash));
+        let stronger = PasswordHasher {
+            argon2_iterations: 2,
+            ..argon2.clone()
+        };
+        assert!(stronger.needs_rehash(&hash));
+
+        assert!(!bcrypt.verify("secret", UNUSABLE_HASH).unwrap_or(false));
+        assert!(bcrypt.needs_rehash(UNUSABLE_HASH));
+    }
+}
@@ -0,0 +1,61 @@
+use super::error::{Error, Result};
+use crate::schema::password_resets;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+const TOKEN_LEN: usize = 40;
+const EXPIRY_HOURS: i64 = 24;
+
+/// A one-time link an administrator sent a user to choose a new password
+#[derive(Queryable, Insertable)]
+#[table_name = "password_resets"]
+pub struct PasswordReset {
+    token_hash: String,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) expires_at: DateTime<Utc>,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl PasswordReset {
+    /// Records a reset, replacing earlier ones of the user, and returns the token to send
+    pub fn create(conn: &PgConnection, user_id: uuid::Uuid) -> Result<String> {
+        use crate::schema::password_resets::dsl;
+
+        let token = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .collect::<String>();
+
+        let reset = Self {
+            token_hash: hash_token(&token),
+            user_id,
+            expires_at: Utc::now() + Duration::hours(EXPIRY_HOURS),
+        };
+
+        diesel::delete(dsl::password_resets.filter(dsl::user_id.eq(user_id))).execute(conn)?;
+        diesel::insert_into(dsl::password_resets)
+            .values(&reset)
+            .execute(conn)?;
+        Ok(token)
+    }
+
+    /// Uses up the reset belonging to `token` and returns the user it was sent to
+    pub fn redeem(conn: &PgConnection, token: &str) -> Result<uuid::Uuid> {
+        use crate::schema::password_resets::dsl;
+
+        let reset = diesel::delete(dsl::password_resets.find(hash_token(token)))
+            .get_result::<Self>(conn)
+            .map_err(|_| Error::InvalidCode)?;
+        if reset.expires_at < Utc::now() {
+            return Err(Error::InvalidCode);
+        }
+        Ok(reset.user_id)
+    }
+}
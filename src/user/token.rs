@@ -0,0 +1,188 @@
+use super::error::{Error, Result};
+use super::User;
+use crate::schema::api_tokens;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+const TOKEN_PREFIX: &str = "arca_";
+const TOKEN_LEN: usize = 40;
+
+/// What a personal access token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "read-write")]
+    ReadWrite,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::ReadWrite => "read-write",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "read-write" => Ok(Scope::ReadWrite),
+            _ => Err(Error::InvalidParams),
+        }
+    }
+}
+
+/// Personal access token used for scripted access to the finder API.
+/// Only a hash of the token is stored.
+#[derive(Queryable, Serialize)]
+pub struct ApiToken {
+    pub(crate) id: uuid::Uuid,
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) name: String,
+    #[serde(skip)]
+    token_hash: String,
+    scope: String,
+    pub(crate) volume_path: Option<String>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_tokens"]
+struct NewApiToken<'a> {
+    user_id: uuid::Uuid,
+    name: &'a str,
+    token_hash: String,
+    scope: &'a str,
+    volume_path: Option<&'a str>,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A volume restriction must be a relative path that stays inside the volume
+fn validate_volume_path(path: &str) -> Result<()> {
+    let valid = Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidParams)
+    }
+}
+
+impl ApiToken {
+    /// Creates a token for the user, returning the plaintext token which is only shown once
+    pub fn create(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        name: &str,
+        scope: Scope,
+        volume_path: Option<&str>,
+    ) -> Result<(Self, String)> {
+        use crate::schema::api_tokens::dsl;
+
+        let volume_path = volume_path.map(str::trim).filter(|p| !p.is_empty());
+        if let Some(path) = volume_path {
+            validate_volume_path(path)?;
+        }
+
+        let token = format!(
+            "{}{}",
+            TOKEN_PREFIX,
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(TOKEN_LEN)
+                .collect::<String>()
+        );
+
+        let new_token = NewApiToken {
+            user_id,
+            name,
+            token_hash: hash_token(&token),
+            scope: scope.as_str(),
+            volume_path,
+        };
+
+        let api_token = diesel::insert_into(dsl::api_tokens)
+            .values(&new_token)
+            .get_result::<Self>(conn)?;
+
+        Ok((api_token, token))
+    }
+
+    pub fn list(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Vec<Self>> {
+        use crate::schema::api_tokens::dsl;
+
+        dsl::api_tokens
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::created_at.asc())
+            .load::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    pub fn find(conn: &PgConnection, user_id: uuid::Uuid, id: uuid::Uuid) -> Result<Self> {
+        use crate::schema::api_tokens::dsl;
+
+        dsl::api_tokens
+            .filter(dsl::id.eq(id))
+            .filter(dsl::user_id.eq(user_id))
+            .first::<Self>(conn)
+            .optional()?
+            .ok_or(Error::NotFound)
+    }
+
+    pub fn revoke(conn: &PgConnection, user_id: uuid::Uuid, id: uuid::Uuid) -> Result<Self> {
+        use crate::schema::api_tokens::dsl;
+
+        diesel::delete(
+            dsl::api_tokens
+                .filter(dsl::id.eq(id))
+                .filter(dsl::user_id.eq(user_id)),
+        )
+        .get_result::<Self>(conn)
+        .optional()?
+        .ok_or(Error::NotFound)
+    }
+
+    /// Revokes every token of the user, e.g. once their password changed
+    pub fn revoke_all(conn: &PgConnection, user_id: uuid::Uuid) -> Result<usize> {
+        use crate::schema::api_tokens::dsl;
+
+        diesel::delete(dsl::api_tokens.filter(dsl::user_id.eq(user_id)))
+            .execute(conn)
+            .map_err(Into::into)
+    }
+
+    /// Looks up the owner of a plaintext token and records its use
+    pub fn authenticate(conn: &PgConnection, token: &str) -> Result<(Self, User)> {
+        use crate::schema::api_tokens::dsl;
+
+        let api_token = diesel::update(dsl::api_tokens.filter(dsl::token_hash.eq(hash_token(token))))
+            .set(dsl::last_used_at.eq(Utc::now()))
+            .get_result::<Self>(conn)
+            .optional()?
+            .ok_or(Error::NotAuthenticated)?;
+
+        let user = User::find_by_id(conn, api_token.user_id)?;
+        user.ensure_active()?;
+        Ok((api_token, user))
+    }
+
+    pub fn scope(&self) -> Scope {
+        self.scope.parse().unwrap_or(Scope::Read)
+    }
+}
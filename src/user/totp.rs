@@ -0,0 +1,332 @@
+use super::error::{Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use crate::crypto::SecretKey;
+use crate::schema::{recovery_codes, user_totp};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use rand::distributions::Alphanumeric;
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ISSUER: &str = "arca";
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+const SALT_LEN: usize = 16;
+/// Failed login codes in a row after which the second factor is locked for `LOCKOUT_MINS`
+const MAX_ATTEMPTS: i32 = 5;
+const LOCKOUT_MINS: i64 = 15;
+
+/// Time-based one time password (RFC 6238) enrollment of a user
+#[derive(Queryable, Insertable, AsChangeset)]
+#[table_name = "user_totp"]
+#[primary_key(user_id)]
+pub struct Totp {
+    pub(crate) user_id: uuid::Uuid,
+    secret: Vec<u8>,
+    pub(crate) enabled: bool,
+    last_step: i64,
+    failed_attempts: i32,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "recovery_codes"]
+struct NewRecoveryCode {
+    user_id: uuid::Uuid,
+    code_hash: String,
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+        / STEP
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).expect("HMAC can take a key of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let code = (u32::from(digest[offset]) & 0x7f) << 24
+        | u32::from(digest[offset + 1]) << 16
+        | u32::from(digest[offset + 2]) << 8
+        | u32::from(digest[offset + 3]);
+    code % 10u32.pow(DIGITS)
+}
+
+/// Salted hash of a recovery code, stored as `salt$hash`
+fn hash_recovery_code(salt: &str, code: &str) -> String {
+    let salted = format!("{}{}", salt, code.trim().to_lowercase());
+    format!("{}${}", salt, hex::encode(Sha256::digest(salted.as_bytes())))
+}
+
+/// Whether `code` matches a stored hash, compared in constant time
+fn recovery_code_matches(code_hash: &str, code: &str) -> bool {
+    let split = match code_hash.find('$') {
+        Some(split) => split,
+        None => return false,
+    };
+    let expected = hash_recovery_code(&code_hash[..split], code);
+    expected.len() == code_hash.len()
+        && expected
+            .bytes()
+            .zip(code_hash.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+impl Totp {
+    /// Generates a new (not yet confirmed) secret for the user, replacing any pending one
+    pub fn enroll(conn: &PgConnection, key: &SecretKey, user_id: uuid::Uuid) -> Result<(Self, String)> {
+        use crate::schema::user_totp::dsl;
+
+        if let Some(existing) = Self::find(conn, user_id)? {
+            if existing.enabled {
+                return Err(Error::TotpAlreadyEnabled);
+            }
+        }
+
+        let mut secret = [0; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let totp = Self {
+            user_id,
+            secret: key.encrypt(&secret).ok_or(Error::CryptoError)?,
+            enabled: false,
+            last_step: 0,
+            failed_attempts: 0,
+            locked_until: None,
+        };
+
+        let totp = diesel::insert_into(dsl::user_totp)
+            .values(&totp)
+            .on_conflict(dsl::user_id)
+            .do_update()
+            .set(&totp)
+            .get_result::<Self>(conn)?;
+
+        Ok((totp, base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret)))
+    }
+
+    pub fn find(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Option<Self>> {
+        use crate::schema::user_totp::dsl;
+
+        dsl::user_totp
+            .find(user_id)
+            .first::<Self>(conn)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    pub fn find_enabled(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Option<Self>> {
+        Ok(Self::find(conn, user_id)?.filter(|totp| totp.enabled))
+    }
+
+    /// `otpauth://` URI understood by authenticator apps
+    pub fn uri(email: &str, secret: &str) -> String {
+        format!(
+            "otpauth://totp/{issuer}:{email}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer = ISSUER,
+            email = email,
+            secret = secret,
+            digits = DIGITS,
+            period = STEP
+        )
+    }
+
+    /// Checks a code against the secret, allowing one step of clock drift.
+    /// A code can only be used once.
+    pub fn verify(&mut self, conn: &PgConnection, key: &SecretKey, code: &str) -> Result<()> {
+        use crate::schema::user_totp::dsl;
+
+        let code = code.trim().parse::<u32>().map_err(|_| Error::InvalidCode)?;
+        let secret = key.decrypt(&self.secret).ok_or(Error::CryptoError)?;
+        let now = current_step();
+        let step = (now.saturating_sub(1)..=now + 1)
+            .find(|step| *step as i64 > self.last_step && hotp(&secret, *step) == code)
+            .ok_or(Error::InvalidCode)?;
+
+        self.last_step = step as i64;
+        diesel::update(dsl::user_totp.find(self.user_id))
+            .set(dsl::last_step.eq(self.last_step))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Enables the enrollment and returns a fresh set of recovery codes
+    pub fn confirm(&mut self, conn: &PgConnection, key: &SecretKey, code: &str) -> Result<Vec<String>> {
+        use crate::schema::user_totp::dsl;
+
+        if self.enabled {
+            return Err(Error::TotpAlreadyEnabled);
+        }
+        self.verify(conn, key, code)?;
+
+        conn.transaction(|| {
+            diesel::update(dsl::user_totp.find(self.user_id))
+                .set(dsl::enabled.eq(true))
+                .execute(conn)?;
+            self.enabled = true;
+            Self::regenerate_recovery_codes(conn, self.user_id)
+        })
+    }
+
+    fn regenerate_recovery_codes(conn: &PgConnection, user_id: uuid::Uuid) -> Result<Vec<String>> {
+        use crate::schema::recovery_codes::dsl;
+
+        diesel::delete(dsl::recovery_codes.filter(dsl::user_id.eq(user_id))).execute(conn)?;
+
+        let codes = (0..RECOVERY_CODES)
+            .map(|_| {
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(RECOVERY_CODE_LEN)
+                    .collect::<String>()
+                    .to_lowercase()
+            })
+            .collect::<Vec<_>>();
+
+        let new_codes = codes
+            .iter()
+            .map(|code| {
+                let salt = hex::encode(rand::thread_rng().gen::<[u8; SALT_LEN]>());
+                NewRecoveryCode {
+                    user_id,
+                    code_hash: hash_recovery_code(&salt, code),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        diesel::insert_into(dsl::recovery_codes)
+            .values(&new_codes)
+            .execute(conn)?;
+
+        Ok(codes)
+    }
+
+    /// Consumes a recovery code in place of a one time password
+    pub fn use_recovery_code(conn: &PgConnection, user_id: uuid::Uuid, code: &str) -> Result<()> {
+        use crate::schema::recovery_codes::dsl;
+
+        let unused = dsl::recovery_codes
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::used.eq(false))
+            .select((dsl::id, dsl::code_hash))
+            .load::<(i32, String)>(conn)?;
+        let id = unused
+            .iter()
+            .find(|(_, code_hash)| recovery_code_matches(code_hash, code))
+            .map(|(id, _)| *id)
+            .ok_or(Error::InvalidCode)?;
+
+        // a concurrent login may have used the code in the meantime
+        let updated = diesel::update(dsl::recovery_codes.find(id).filter(dsl::used.eq(false)))
+            .set(dsl::used.eq(true))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(Error::InvalidCode);
+        }
+        Ok(())
+    }
+
+    /// Checks the second factor on login, either a one time password or a recovery code.
+    /// After `MAX_ATTEMPTS` wrong codes in a row no code is accepted for `LOCKOUT_MINS`.
+    pub fn check_login(&mut self, conn: &PgConnection, key: &SecretKey, code: &str) -> Result<()> {
+        use crate::schema::user_totp::dsl;
+
+        let now = Utc::now();
+        if self.locked_until.map_or(false, |until| until > now) {
+            return Err(Error::TooManyAttempts);
+        }
+
+        let checked = self
+            .verify(conn, key, code)
+            .or_else(|_| Self::use_recovery_code(conn, self.user_id, code));
+        match checked {
+            Ok(()) => {
+                diesel::update(dsl::user_totp.find(self.user_id))
+                    .set((dsl::failed_attempts.eq(0), dsl::locked_until.eq(None::<DateTime<Utc>>)))
+                    .execute(conn)?;
+            }
+            Err(Error::InvalidCode) => {
+                // counted in the database so concurrent attempts add up
+                let failed = diesel::update(dsl::user_totp.find(self.user_id))
+                    .set(dsl::failed_attempts.eq(dsl::failed_attempts + 1))
+                    .returning(dsl::failed_attempts)
+                    .get_result::<i32>(conn)?;
+                if failed >= MAX_ATTEMPTS {
+                    diesel::update(dsl::user_totp.find(self.user_id))
+                        .set((
+                            dsl::failed_attempts.eq(0),
+                            dsl::locked_until.eq(now + Duration::minutes(LOCKOUT_MINS)),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+            Err(_) => {}
+        }
+        checked
+    }
+
+    /// Removes the enrollment along with its recovery codes
+    pub fn disable(conn: &PgConnection, user_id: uuid::Uuid) -> Result<()> {
+        conn.transaction(|| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)?;
+            diesel::delete(user_totp::table.find(user_id)).execute(conn)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc4226_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), *code);
+        }
+    }
+
+    /// The SHA-1 vectors of RFC 6238, which are 8 digits long, cut to the 6 digits used here
+    #[test]
+    fn hotp_matches_rfc6238_vectors() {
+        let expected = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, code) in expected.iter() {
+            assert_eq!(hotp(RFC_SECRET, time / STEP), code % 10u32.pow(DIGITS));
+        }
+    }
+
+    #[test]
+    fn recovery_codes_are_salted() {
+        let first = hash_recovery_code("aa", "code");
+        let second = hash_recovery_code("bb", "code");
+        assert_ne!(first, second);
+        assert!(recovery_code_matches(&first, " CODE "));
+        assert!(!recovery_code_matches(&first, "other"));
+
+        let unsalted = hex::encode(Sha256::digest(b"code"));
+        assert!(!recovery_code_matches(&unsalted, "code"));
+    }
+}
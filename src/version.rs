@@ -0,0 +1,350 @@
+use super::blob::BlobStore;
+use super::env::DbPool;
+use super::error::{Error, Result};
+use super::file::File;
+use super::privsep::{Helper, RunAs};
+use super::storage::{Driver, Storage};
+use super::schema::file_versions;
+use super::trash::escape_like;
+use super::user::error::Error as UserError;
+use super::volume::Volume;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Directory inside every volume holding the contents of overwritten files, hidden from
+/// the finder. Contents are stored once per volume under their SHA-256 hash.
+pub const VERSIONS_DIR: &str = ".versions";
+
+const DEFAULT_KEEP_LAST: i64 = 10;
+const DEFAULT_KEEP_DAYS: i64 = 30;
+const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Which versions of a file are kept: the most recent ones, and the last one of every day
+/// for a while after that
+#[derive(Clone, Copy)]
+pub struct Retention {
+    keep_last: usize,
+    keep_days: Duration,
+}
+
+impl Retention {
+    /// Reads `VERSIONS_KEEP_LAST` (10 by default) and `VERSIONS_KEEP_DAYS` (30 by default)
+    pub fn from_env() -> Self {
+        let keep_last = std::env::var("VERSIONS_KEEP_LAST")
+            .ok()
+            .map(|n| n.parse().expect("VERSIONS_KEEP_LAST must be a number of versions"))
+            .unwrap_or(DEFAULT_KEEP_LAST);
+        let keep_days = std::env::var("VERSIONS_KEEP_DAYS")
+            .ok()
+            .map(|days| days.parse().expect("VERSIONS_KEEP_DAYS must be a number of days"))
+            .unwrap_or(DEFAULT_KEEP_DAYS);
+        Self {
+            keep_last: keep_last.max(0) as usize,
+            keep_days: Duration::days(keep_days),
+        }
+    }
+
+    /// Keeps nothing, for files which are gone
+    fn none() -> Self {
+        Self {
+            keep_last: 0,
+            keep_days: Duration::zero(),
+        }
+    }
+}
+
+/// Earlier content of the file at `path`, kept in the store of the volume at `store_path`.
+/// Paths are only unique along with the `driver` storing them. Versions follow their file
+/// when arca moves it, into the trash as well.
+#[derive(Queryable, Serialize)]
+pub struct Version {
+    pub(crate) id: uuid::Uuid,
+    #[serde(skip)]
+    store_path: String,
+    pub(crate) path: String,
+    pub(crate) size: i64,
+    pub(crate) mtime: DateTime<Utc>,
+    /// Who wrote the content, if it was written through the finder
+    pub(crate) author: Option<uuid::Uuid>,
+    /// Who overwrote the content
+    pub(crate) replaced_by: Option<uuid::Uuid>,
+    pub(crate) hash: String,
+    pub(crate) created_at: DateTime<Utc>,
+    #[serde(skip)]
+    os_user: Option<String>,
+    #[serde(skip)]
+    pub(crate) driver: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "file_versions"]
+struct NewVersion<'a> {
+    store_path: &'a str,
+    path: &'a str,
+    size: i64,
+    mtime: DateTime<Utc>,
+    author: Option<uuid::Uuid>,
+    replaced_by: uuid::Uuid,
+    hash: &'a str,
+    os_user: Option<&'a str>,
+    driver: &'a str,
+}
+
+/// Hash contents are compared and kept by
+pub(crate) fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+impl Version {
+    /// Where the contents of a volume's versions are stored
+    pub fn dir(store_path: &Path) -> PathBuf {
+        store_path.join(VERSIONS_DIR)
+    }
+
+    /// Where the version's content is kept
+    pub fn blob(&self) -> PathBuf {
+        Self::dir(Path::new(&self.store_path)).join(&self.hash)
+    }
+
+    /// Name of the versioned file
+    pub fn name(&self) -> String {
+        Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Keeps the current content of `path`, relative to the volume root, before `user_id`
+    /// replaces it with content hashing to `replacement_hash`. Nothing is kept for new files
+    /// or when the content does not change.
+    pub async fn keep(
+        conn: &PgConnection,
+        vol: &Volume,
+        path: &Path,
+        replacement_hash: &str,
+        user_id: uuid::Uuid,
+        retention: Retention,
+    ) -> Result<Option<Self>> {
+        use crate::schema::file_versions::dsl;
+
+        vol.check_write()?;
+        let storage = vol.storage();
+        let full = File::check_path(vol, path)?;
+        let stat = match storage.stat(&full).await {
+            Ok(stat) => stat,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if stat.is_dir {
+            return Err(Error::InvalidParams);
+        }
+
+        let data = storage.read_all(&full).await?;
+        let hash = content_hash(&data);
+        if hash == replacement_hash {
+            return Ok(None);
+        }
+
+        let dir = Self::dir(&vol.path);
+        let blob = dir.join(&hash);
+        if storage.stat(&blob).await.is_err() {
+            storage.mkdir(&dir, true).await?;
+            storage.write_all(&blob, &data).await?;
+        }
+
+        let full = full.to_string_lossy();
+        // whoever replaced the previous version wrote the current content
+        let author = dsl::file_versions
+            .filter(dsl::driver.eq(vol.driver.as_str()))
+            .filter(dsl::path.eq(full.as_ref()))
+            .order(dsl::created_at.desc())
+            .select(dsl::replaced_by)
+            .first::<Option<uuid::Uuid>>(conn)
+            .optional()?
+            .flatten();
+        let store_path = vol.path.to_string_lossy();
+        let version = diesel::insert_into(dsl::file_versions)
+            .values(&NewVersion {
+                store_path: &store_path,
+                path: &full,
+                size: data.len() as i64,
+                mtime: Utc.timestamp(stat.modified as i64, 0),
+                author,
+                replaced_by: user_id,
+                hash: &hash,
+                os_user: vol.run_as.as_ref().map(|run_as| run_as.user.as_str()),
+                driver: vol.driver.as_str(),
+            })
+            .get_result::<Self>(conn)?;
+
+        Self::prune(conn, &*storage, vol.driver.as_str(), &full, retention).await?;
+        Ok(Some(version))
+    }
+
+    /// Versions of the file at the absolute `path` of the `driver`, newest first
+    pub fn list(conn: &PgConnection, driver: Driver, path: &Path) -> Result<Vec<Self>> {
+        use crate::schema::file_versions::dsl;
+
+        dsl::file_versions
+            .filter(dsl::driver.eq(driver.as_str()))
+            .filter(dsl::path.eq(path.to_string_lossy().as_ref()))
+            .order(dsl::created_at.desc())
+            .load::<Self>(conn)
+            .map_err(Into::into)
+    }
+
+    /// Moves the versions of the file at the absolute `from` of the `driver`, or of every file
+    /// below it for a directory, along with it to `to`
+    pub fn moved(conn: &PgConnection, driver: Driver, from: &Path, to: &Path) -> Result<()> {
+        let from = from.to_string_lossy();
+        diesel::sql_query(
+            "UPDATE file_versions SET path = $1 || substr(path, char_length($2) + 1) \
+             WHERE driver = $4 AND (path = $2 OR path LIKE $3)",
+        )
+        .bind::<Text, _>(to.to_string_lossy().as_ref())
+        .bind::<Text, _>(from.as_ref())
+        .bind::<Text, _>(format!("{}/%", escape_like(&from)))
+        .bind::<Text, _>(driver.as_str())
+        .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn find(conn: &PgConnection, id: uuid::Uuid) -> Result<Self> {
+        use crate::schema::file_versions::dsl;
+
+        dsl::file_versions
+            .find(id)
+            .first::<Self>(conn)
+            .optional()?
+            .ok_or(Error::UserError(UserError::NotFound))
+    }
+
+    /// Content of the version, `storage` being the storage of its volume
+    pub async fn read(&self, storage: &dyn Storage) -> Result<Vec<u8>> {
+        storage.read_all(&self.blob()).await
+    }
+
+    /// Writes the version back to `path`, relative to `vol` which has to contain the file.
+    /// The content it replaces is kept as a version itself.
+    pub async fn restore(
+        &self,
+        conn: &PgConnection,
+        store: Option<&BlobStore>,
+        vol: &Volume,
+        path: &Path,
+        user_id: uuid::Uuid,
+        retention: Retention,
+    ) -> Result<()> {
+        let full = File::check_path(vol, path)?;
+        if vol.driver.as_str() != self.driver || full != Path::new(&self.path) {
+            return Err(Error::PathError);
+        }
+        let data = self.read(&*vol.storage()).await?;
+        Self::keep(conn, vol, path, &content_hash(&data), user_id, retention).await?;
+        BlobStore::write(store, conn, vol, &full, &data).await
+    }
+
+    /// Drops the versions of the file at the absolute `path` of the `driver` which fall out of
+    /// `retention`, along with contents no other version refers to
+    async fn prune(
+        conn: &PgConnection,
+        storage: &dyn Storage,
+        driver: &str,
+        path: &str,
+        retention: Retention,
+    ) -> Result<()> {
+        use crate::schema::file_versions::dsl;
+
+        let versions = dsl::file_versions
+            .filter(dsl::driver.eq(driver))
+            .filter(dsl::path.eq(path))
+            .order(dsl::created_at.desc())
+            .load::<Self>(conn)?;
+
+        let cutoff = Utc::now() - retention.keep_days;
+        let mut days = HashSet::new();
+        for (i, version) in versions.into_iter().enumerate() {
+            // versions are newest first, so the first one seen of a day is its last
+            let newest_of_day = days.insert(version.created_at.date());
+            if i < retention.keep_last || (newest_of_day && version.created_at > cutoff) {
+                continue;
+            }
+
+            diesel::delete(dsl::file_versions.find(version.id)).execute(conn)?;
+            let shared = dsl::file_versions
+                .filter(dsl::driver.eq(&version.driver))
+                .filter(dsl::store_path.eq(&version.store_path))
+                .filter(dsl::hash.eq(&version.hash))
+                .count()
+                .get_result::<i64>(conn)?;
+            if shared == 0 {
+                match storage.remove(&version.blob()).await {
+                    Err(Error::IoError(e)) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(Error::IoError(e))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Applies `retention` to the versions of every file, also for files nobody wrote to in a
+    /// while. Versions of files which are gone, e.g. removed outside of arca or purged from the
+    /// trash, are dropped.
+    pub async fn prune_all(pool: &DbPool, helper: Option<&Arc<Helper>>, retention: Retention) -> Result<()> {
+        use crate::schema::file_versions::dsl;
+
+        let conn = pool.get().map_err(|_| UserError::DbError)?;
+        let files = dsl::file_versions
+            .select((dsl::path, dsl::os_user, dsl::driver))
+            .distinct()
+            .load::<(String, Option<String>, String)>(&conn)?;
+
+        for (path, os_user, driver) in files {
+            let run_as = match (os_user, helper) {
+                (Some(user), Some(helper)) => Some(RunAs {
+                    helper: helper.clone(),
+                    user,
+                }),
+                // the account's files cannot be reached without the helper
+                (Some(_), None) => continue,
+                (None, _) => None,
+            };
+            let storage = match driver.parse::<Driver>().ok().and_then(|d| d.storage(run_as)) {
+                Some(storage) => storage,
+                None => continue,
+            };
+            let retention = match storage.stat(Path::new(&path)).await {
+                Ok(_) => retention,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => Retention::none(),
+                Err(e) => {
+                    log::error!("Could not check the versioned file {}: {}", path, e);
+                    continue;
+                }
+            };
+            if let Err(e) = Self::prune(&conn, &*storage, &driver, &path, retention).await {
+                log::error!("Could not prune the versions of {}: {}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn spawn_prune(pool: DbPool, helper: Option<Arc<Helper>>, retention: Retention) {
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(PRUNE_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                if let Err(e) = Self::prune_all(&pool, helper.as_ref(), retention).await {
+                    log::error!("Could not prune file versions: {}", e);
+                }
+            }
+        });
+    }
+}
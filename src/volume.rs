@@ -1,25 +1,136 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::io::Write;
+use std::sync::Arc;
+use std::str::FromStr;
 use serde_derive::{Deserialize, Serialize};
-use diesel::{Queryable, sql_types::Text, deserialize::{self, FromSql}, serialize::{self, Output, ToSql}, backend::Backend};
-use super::error::Result;
+use diesel::{sql_types::Text, deserialize::{self, FromSql}, serialize::{self, Output, ToSql}, backend::Backend};
+use super::error::{Error, Result};
 use super::env::Environment;
 use super::user::User;
 use super::file::File;
+use super::privsep::{self, RunAs};
+use super::storage::{Driver, Sftp, Storage};
+use super::trash::TRASH_ID;
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Deserialize, Serialize, Queryable)]
-#[repr(transparent)]
+/// What a user may do inside a volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Access {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Admin => "admin",
+        }
+    }
+
+    pub fn can_write(&self) -> bool {
+        *self >= Access::Write
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Read
+    }
+}
+
+impl FromStr for Access {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Access::Read),
+            "write" => Ok(Access::Write),
+            "admin" => Ok(Access::Admin),
+            _ => Err(Error::InvalidParams),
+        }
+    }
+}
+
+/// A directory mounted as an elFinder root. `id` is the volume prefix of every
+/// hash inside the volume, `l1_` for the user's own volume and otherwise derived from what
+/// the volume is, so hashes stay valid when other volumes come and go. Volumes with an
+/// `alias` use it as their id. `name` is the name shown for its root, and `path` is where the
+/// volume lives on the storage its `driver` provides.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "VolumeEntry", into = "VolumeEntry")]
 pub struct Volume {
-    pub(crate) path: PathBuf
+    pub(crate) path: PathBuf,
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) access: Access,
+    pub(crate) alias: Option<String>,
+    /// Set when file operations run as the user's mapped Unix account
+    pub(crate) run_as: Option<RunAs>,
+    pub(crate) quota: Option<Quota>,
+    pub(crate) driver: Driver,
+    /// The netmount an SFTP volume belongs to
+    #[serde(skip)]
+    pub(crate) netmount: Option<uuid::Uuid>,
+}
+
+/// Limit on the bytes stored below `root`, including the volume's trash
+#[derive(Debug, Clone)]
+pub struct Quota {
+    pub(crate) root: PathBuf,
+    pub(crate) bytes: u64,
+}
+
+/// How an extra volume is stored in `users.volumes`. Older rows hold a plain path.
+#[derive(Deserialize, Serialize)]
+struct VolumeEntry {
+    path: PathBuf,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    name: String,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    #[serde(default, skip_serializing_if = "Driver::is_local")]
+    driver: Driver,
+}
+
+impl From<VolumeEntry> for Volume {
+    fn from(entry: VolumeEntry) -> Self {
+        let access = if entry.read_only { Access::Read } else { Access::Write };
+        let id = entry.alias.as_deref().map(Volume::alias_id).unwrap_or_default();
+        Self {
+            alias: entry.alias,
+            driver: entry.driver,
+            ..Self::new(entry.path, &id, &entry.name, access)
+        }
+    }
+}
+
+impl From<Volume> for VolumeEntry {
+    fn from(vol: Volume) -> Self {
+        Self {
+            path: vol.path,
+            name: vol.name,
+            read_only: !vol.access.can_write(),
+            alias: vol.alias,
+            driver: vol.driver,
+        }
+    }
 }
 
 impl<DB> FromSql<Text, DB> for Volume
 where DB: Backend,
       String: FromSql<Text, DB> {
     fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
-        Ok(Self {
-            path: String::from_sql(bytes)?.into()
-        })
+        let text = String::from_sql(bytes)?;
+        if text.starts_with('{') {
+            Ok(serde_json::from_str::<VolumeEntry>(&text)?.into())
+        } else {
+            Ok(Self::new(text, "", "", Access::Write))
+        }
     }
 }
 
@@ -27,31 +138,312 @@ impl<DB> ToSql<Text, DB> for Volume
 where DB: Backend,
       String: ToSql<Text, DB> {
     fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
-        self.path
-            .canonicalize()?
-            .to_string_lossy()
-            .into_owned()
-            .to_sql(out)
+        let path = if self.driver.is_local() {
+            self.path.canonicalize()?
+        } else {
+            self.path.clone()
+        };
+        let entry = VolumeEntry {
+            path,
+            ..self.clone().into()
+        };
+        serde_json::to_string(&entry)?.to_sql(out)
     }
 }
 
 
+/// Volume id of the user's own volume, which is always mounted first
+pub const HOME_ID: &str = "l1_";
+
+/// Directory under `FINDER_ROOT` holding the volumes of deleted accounts
+pub const ARCHIVE_DIR: &str = ".deleted";
+
+/// Directories extra volumes may be assigned from, `VOLUME_BASE_DIRS` separated by `:`.
+/// Without it no extra volumes can be assigned or mounted.
+pub fn bases_from_env() -> Vec<PathBuf> {
+    std::env::var("VOLUME_BASE_DIRS")
+        .unwrap_or_default()
+        .split(':')
+        .filter(|dir| !dir.trim().is_empty())
+        .map(|dir| {
+            Path::new(dir.trim())
+                .canonicalize()
+                .unwrap_or_else(|_| panic!("Could not find volume base directory: {}", dir))
+        })
+        .collect()
+}
+
 impl Volume {
-    
+    pub fn new(path: impl Into<PathBuf>, id: &str, name: &str, access: Access) -> Self {
+        Self {
+            path: path.into(),
+            id: id.to_owned(),
+            name: name.to_owned(),
+            access,
+            alias: None,
+            run_as: None,
+            quota: None,
+            driver: Driver::Local,
+            netmount: None,
+        }
+    }
+
+    /// The storage the volume's files are accessed through
+    pub fn storage(&self) -> Arc<dyn Storage> {
+        match self.driver {
+            Driver::Sftp => Sftp::mounted(self.netmount),
+            driver => driver
+                .storage(self.run_as.clone())
+                .expect("Every driver but SFTP has a storage of its own"),
+        }
+    }
+
+    /// Whether files can be moved between the volumes by renaming them
+    pub fn same_storage(&self, other: &Volume) -> bool {
+        self.driver == other.driver
+            && self.netmount == other.netmount
+            && self.run_as.as_ref().map(|run_as| &run_as.user) == other.run_as.as_ref().map(|run_as| &run_as.user)
+    }
+
+    /// Volume id of an extra volume without an alias, taken from where it is stored
+    fn path_id(driver: Driver, path: &Path) -> String {
+        let digest = Sha256::digest(format!("{:?}:{}", driver, path.display()).as_bytes());
+        format!("v-{}_", &hex::encode(digest)[..8])
+    }
+
+    /// Volume id of a group's volume
+    pub fn group_id(id: uuid::Uuid) -> String {
+        format!("g-{}_", &id.to_simple().to_string()[..8])
+    }
+
+    /// Volume id of a folder granted by another user
+    pub fn grant_id(id: uuid::Uuid) -> String {
+        format!("s-{}_", &id.to_simple().to_string()[..8])
+    }
+
+    /// Volume id of an aliased volume
+    fn alias_id(alias: &str) -> String {
+        format!("{}_", alias)
+    }
+
+    /// Volume id of a netmount, taken from its id so hashes stay valid when other netmounts
+    /// are unmounted
+    pub fn netmount_id(id: uuid::Uuid) -> String {
+        format!("net-{}_", &id.to_simple().to_string()[..8])
+    }
+
+    /// Aliases end up in hashes, so they are limited to lower case letters and digits.
+    /// `l` followed by digits is reserved for the user's own volume, `trash` for the trash.
+    fn valid_alias(alias: &str) -> bool {
+        let numbered = alias.starts_with('l') && alias[1..].chars().all(|c| c.is_ascii_digit());
+        alias.starts_with(|c: char| c.is_ascii_lowercase())
+            && alias.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && !numbered
+            && Self::alias_id(alias) != TRASH_ID
+    }
+
+    /// Whether the volume lies inside one of the allowed base directories. Only local volumes
+    /// are confined to them, other drivers have their own root.
+    pub fn is_within(&self, bases: &[PathBuf]) -> bool {
+        !self.driver.is_local() || bases.iter().any(|base| self.path.starts_with(base))
+    }
+
+    /// Checks an extra volume assigned by an administrator, canonicalizing the path of local
+    /// volumes. Volumes on other storage are created if they do not exist yet, except in
+    /// memory, which is refused outside of tests.
+    pub async fn validate(self, bases: &[PathBuf]) -> Result<Self> {
+        // whatever is kept in memory is lost on restart, the driver is only meant for tests
+        if self.driver == Driver::Memory && !cfg!(test) {
+            return Err(Error::InvalidParams);
+        }
+        let path = if self.driver.is_local() {
+            let path = self.path.canonicalize().map_err(|_| Error::InvalidParams)?;
+            if !path.is_dir() {
+                return Err(Error::InvalidParams);
+            }
+            path
+        } else {
+            let normal = self.path.is_absolute()
+                && self.path.components().all(|c| matches!(c, Component::RootDir | Component::Normal(_)));
+            if !normal {
+                return Err(Error::InvalidParams);
+            }
+            self.storage().mkdir(&self.path, true).await?;
+            self.path.clone()
+        };
+        if self.alias.as_deref().map_or(false, |alias| !Self::valid_alias(alias)) {
+            return Err(Error::InvalidParams);
+        }
+        let vol = Self { path, ..self };
+        if !vol.is_within(bases) {
+            return Err(Error::Forbidden);
+        }
+        Ok(vol)
+    }
+
+    /// Directory of a user's volume under `FINDER_ROOT`
+    pub fn user_path(finder_root: &Path, user_id: uuid::Uuid) -> PathBuf {
+        finder_root.join(user_id.to_simple().to_string())
+    }
+
+    /// Where the volume of a deleted account is kept until its grace period ends
+    pub fn archive_path(finder_root: &Path, user_id: uuid::Uuid) -> PathBuf {
+        finder_root
+            .join(ARCHIVE_DIR)
+            .join(user_id.to_simple().to_string())
+    }
+
+    /// The user's own volume, created on first use. Users mapped to a Unix account get their
+    /// home directory instead, accessed as that account.
     pub async fn create_or_find(env: &Environment, user: &User) -> Result<Self> {
-        let path = [AsRef::<Path>::as_ref(&env.finder_root), user.id.to_simple().to_string().as_ref()]
-           .iter()
-           .collect::<PathBuf>();
+        if let (Some(helper), Some(os_user)) = (&env.fs_helper, &user.os_user) {
+            let account = privsep::os_user(os_user)?;
+            return Ok(Self {
+                run_as: Some(RunAs {
+                    helper: helper.clone(),
+                    user: os_user.clone(),
+                }),
+                ..Self::new(account.dir, HOME_ID, "Home", Access::Admin)
+            }
+            .with_quota(user));
+        }
+
+        let path = Self::user_path(&env.finder_root, user.id);
 
         if !path.exists() {
             tokio::fs::create_dir(&path).await?;
         }
 
+        Ok(Self::new(path.canonicalize()?, HOME_ID, "Home", Access::Admin).with_quota(user))
+    }
+
+    /// The user's own volume followed by the extra volumes assigned to them. Volumes which are
+    /// missing or no longer inside an allowed base directory are left out.
+    pub async fn for_user(env: &Environment, user: &User) -> Result<Vec<Self>> {
+        let own = Self::create_or_find(env, user).await?;
+        let run_as = own.run_as.clone();
+        let mut mounts = vec![own];
+
+        for vol in user.volumes.iter().flatten() {
+            let path = match vol.driver {
+                Driver::Local => tokio::fs::canonicalize(&vol.path).await.ok().filter(|path| path.is_dir()),
+                _ => match vol.storage().stat(&vol.path).await {
+                    Ok(stat) if stat.is_dir => Some(vol.path.clone()),
+                    _ => None,
+                },
+            };
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    log::warn!("Skipping missing volume {} of {}", vol.path.display(), user.id);
+                    continue;
+                }
+            };
+            let id = match &vol.alias {
+                Some(alias) => Self::alias_id(alias),
+                None => Self::path_id(vol.driver, &path),
+            };
+            let name = if vol.name.is_empty() {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            } else {
+                vol.name.clone()
+            };
+            let vol = Self {
+                path,
+                id,
+                name,
+                run_as: run_as.clone(),
+                ..vol.clone()
+            };
+            if vol.is_within(&env.volume_bases) {
+                mounts.push(vol);
+            }
+        }
+        Ok(mounts)
+    }
+
+    fn with_quota(self, user: &User) -> Self {
+        self.with_quota_bytes(user.quota_bytes)
+    }
+
+    /// Limits the volume to `bytes` below its path
+    pub fn with_quota_bytes(self, bytes: Option<i64>) -> Self {
+        let quota = bytes.map(|bytes| Quota {
+            root: self.path.clone(),
+            bytes: bytes.max(0) as u64,
+        });
+        Self { quota, ..self }
+    }
+
+    /// Bytes that may still be stored in the volume, `None` if it has no quota
+    pub async fn quota_remaining(&self) -> Result<Option<u64>> {
+        let quota = match &self.quota {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+        let used = self.storage().size(&quota.root).await?;
+        if used >= quota.bytes {
+            return Err(Error::QuotaExceeded);
+        }
+        Ok(Some(quota.bytes - used))
+    }
+
+    /// Narrows the volume down to a sub directory, e.g. for path restricted API tokens
+    pub async fn restrict(self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = File::check_path(&self, path)?;
+        if !self.storage().stat(&path).await?.is_dir {
+            return Err(Error::PathError);
+        }
         Ok(Self {
-            path
+            path,
+            ..self
         })
     }
 
+    /// Whether the volume is a remote directory mounted by the user. Trash and versions are
+    /// kept by path, so they are not available on netmounts.
+    pub fn is_netmount(&self) -> bool {
+        self.netmount.is_some()
+    }
+
+    /// Fails unless the volume may be modified
+    pub fn check_write(&self) -> Result<()> {
+        if self.access.can_write() {
+            Ok(())
+        } else {
+            Err(Error::Forbidden)
+        }
+    }
+
+    /// Moves a user's volume out of reach, returns false if there was none
+    pub async fn archive(finder_root: &Path, user_id: uuid::Uuid) -> Result<bool> {
+        let path = Self::user_path(finder_root, user_id);
+        if !path.exists() {
+            return Ok(false);
+        }
+        let archive = Self::archive_path(finder_root, user_id);
+        tokio::fs::create_dir_all(finder_root.join(ARCHIVE_DIR)).await?;
+        tokio::fs::rename(&path, &archive).await?;
+        Ok(true)
+    }
+
+    /// Undoes `archive`
+    pub async fn unarchive(finder_root: &Path, user_id: uuid::Uuid) -> Result<()> {
+        let archive = Self::archive_path(finder_root, user_id);
+        tokio::fs::rename(&archive, Self::user_path(finder_root, user_id)).await?;
+        Ok(())
+    }
+
+    /// Permanently removes the archived volume of a user
+    pub async fn remove_archive(finder_root: &Path, user_id: uuid::Uuid) -> Result<()> {
+        match tokio::fs::remove_dir_all(Self::archive_path(finder_root, user_id)).await {
+            Err(e) if e.kind() != tokio::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn root(&self) -> Result<File> {
         File::info(self, "/").await
     }